@@ -46,7 +46,7 @@ use thrift_types::edenfs::GetCurrentSnapshotInfoRequest;
 use thrift_types::edenfs::GetScmStatusParams;
 use thrift_types::edenfs::GlobParams;
 use thrift_types::edenfs::MountId;
-#[cfg(target_os = "macos")]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
 use thrift_types::edenfs::StartFileAccessMonitorParams;
 use thrift_types::edenfs::UnmountArgument;
 use thrift_types::edenfs_clients::errors::UnmountV2Error;
@@ -512,6 +512,12 @@ impl EdenFsInstance {
         self.config_dir.join("storage")
     }
 
+    /// State file recording the running File Access Monitor session, written
+    /// by `eden fam start --background` and read back by `eden fam stop`.
+    pub fn fam_state_file(&self) -> PathBuf {
+        self.config_dir.join("fam-session.json")
+    }
+
     pub fn client_name(&self, path: &Path) -> Result<String> {
         // Resolve symlinks and get absolute path
         let path = path.canonicalize().from_err()?;
@@ -841,7 +847,7 @@ impl EdenFsInstance {
             .map_err(|_| EdenFsError::Other(anyhow!("failed to get regex counters")))
     }
 
-    #[cfg(target_os = "macos")]
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
     pub async fn start_file_access_monitor(
         &self,
         path_prefix: &Vec<PathBuf>,
@@ -868,7 +874,7 @@ impl EdenFsInstance {
             .map_err(|e| EdenFsError::Other(anyhow!("failed to start file access monitor: {}", e)))
     }
 
-    #[cfg(target_os = "macos")]
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
     pub async fn stop_file_access_monitor(
         &self,
     ) -> Result<thrift_types::edenfs::StopFileAccessMonitorResult> {
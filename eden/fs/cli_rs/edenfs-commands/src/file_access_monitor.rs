@@ -6,13 +6,20 @@
  */
 
 use std::cmp::Reverse;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::fs::File as FsFile;
 use std::io::BufRead;
 use std::io::BufReader;
+use std::io::Write;
+use std::path::Path;
 use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
 
+use anyhow::anyhow;
+use anyhow::Context;
 use anyhow::Result;
 use async_trait::async_trait;
 use clap::Parser;
@@ -25,12 +32,12 @@ use serde::Serialize;
 use crate::ExitCode;
 use crate::Subcommand;
 
-#[cfg(target_os = "macos")]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
 #[derive(Parser, Debug)]
 #[clap(
     name = "file-access-monitor",
     alias = "fam",
-    about = "File Access Monitor(FAM) to audit processes.\nAvailable only on macOS."
+    about = "File Access Monitor(FAM) to audit processes.\nMonitoring requires an EdenFS daemon with FAM support (currently macOS only).\n'read' works on any platform."
 )]
 pub struct FileAccessMonitorCmd {
     #[clap(subcommand)]
@@ -83,7 +90,19 @@ impl crate::Subcommand for StartCmd {
         let mut monitor_paths: Vec<PathBuf> = Vec::new();
 
         for path in &self.paths {
-            monitor_paths.push(expand_path(path));
+            let path = expand_path(path);
+            let path = path.canonicalize().with_context(|| {
+                format!("Monitor path {} does not exist", path.display())
+            })?;
+            monitor_paths.push(path);
+        }
+
+        let (monitor_paths, dropped) = dedup_nested_paths(monitor_paths);
+        for path in &dropped {
+            eprintln!(
+                "Warning: not monitoring {} since it is covered by another monitored path",
+                path.display()
+            );
         }
 
         let output_path = self.output.as_ref().map(expand_path);
@@ -98,33 +117,159 @@ impl crate::Subcommand for StartCmd {
             path_from_bytes(&start_result.tmpOutputPath)?.display()
         );
 
+        // Record the session so a later `eden fam stop` can report what it is
+        // stopping. Failing to write it isn't fatal: the daemon still knows.
+        let session = FamSession {
+            pid: start_result.pid,
+            tmp_output_path: path_from_bytes(&start_result.tmpOutputPath)?,
+        };
+        if let Err(e) = save_fam_session(&session) {
+            eprintln!("Warning: failed to record FAM session: {:#}", e);
+        }
+
         if self.background {
             println!("File Access Monitor is running in the background");
             return Ok(0);
         }
 
-        // TODO[lxw]: handle timeout
+        // A timeout of zero means run until interrupted. Either way Ctrl-C
+        // stops FAM cleanly so the output file is still written.
+        if self.timeout == 0 {
+            println!("File Access Monitor is running. Press Ctrl-C to stop.");
+            tokio::signal::ctrl_c().await?;
+            println!();
+        } else {
+            let mut remaining = self.timeout;
+            while remaining > 0 {
+                print!(
+                    "\rFile Access Monitor stops in {} second(s). Press Ctrl-C to stop now. ",
+                    remaining
+                );
+                std::io::stdout().flush()?;
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => break,
+                    _ = tokio::time::sleep(Duration::from_secs(1)) => remaining -= 1,
+                }
+            }
+            println!();
+        }
 
         stop_fam().await
     }
 }
 
+/// Drop monitor paths nested under another requested path, so FAM doesn't
+/// double-report events under them. Returns the kept paths and the dropped
+/// ones (for warning). Expects canonicalized paths.
+fn dedup_nested_paths(mut paths: Vec<PathBuf>) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    // Sorting component-wise puts every ancestor right before its descendants
+    paths.sort();
+    paths.dedup();
+    let mut kept: Vec<PathBuf> = Vec::new();
+    let mut dropped: Vec<PathBuf> = Vec::new();
+    for path in paths {
+        if kept.iter().any(|ancestor| path.starts_with(ancestor)) {
+            dropped.push(path);
+        } else {
+            kept.push(path);
+        }
+    }
+    (kept, dropped)
+}
+
+/// Session info written by `start --background` under the EdenFS state dir,
+/// so a later `eden fam stop` can report which session it is stopping. The
+/// daemon remains the source of truth; this file is informational only.
+#[derive(Serialize, Deserialize, Debug)]
+struct FamSession {
+    pid: i32,
+    tmp_output_path: PathBuf,
+}
+
+fn save_fam_session(session: &FamSession) -> Result<()> {
+    let path = EdenFsInstance::global().fam_state_file();
+    std::fs::write(&path, serde_json::to_string(session)?)
+        .with_context(|| format!("failed to write FAM state file {}", path.display()))
+}
+
+/// Read back the recorded session. Returns `None` when the file is missing or
+/// unparsable (e.g. stale, or written by a different version) - stopping must
+/// keep working without it.
+fn load_fam_session() -> Option<FamSession> {
+    let path = EdenFsInstance::global().fam_state_file();
+    let content = std::fs::read_to_string(path).ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+fn clear_fam_session() {
+    let _ = std::fs::remove_file(EdenFsInstance::global().fam_state_file());
+}
+
 async fn stop_fam() -> Result<ExitCode> {
+    if let Some(session) = load_fam_session() {
+        println!(
+            "Stopping File Access Monitor session [pid {}, output {}]",
+            session.pid,
+            session.tmp_output_path.display()
+        );
+    }
+
     let stop_result = EdenFsInstance::global().stop_file_access_monitor().await?;
     println!("File Access Monitor stopped");
+    clear_fam_session();
     // TODO: handle the case when the output file is specified
     let output_path = path_from_bytes(&stop_result.specifiedOutputPath)?;
 
     println!("Output file saved to {}", output_path.display());
 
     if stop_result.shouldUpload {
-        // TODO[lxw]: handle uploading outputfile
-        println!("Upload not implemented yet");
-        return Ok(1);
+        match upload_output_file(&output_path).await {
+            Ok(link) => println!("Output file uploaded: {}", link),
+            Err(e) => {
+                eprintln!("Failed to upload output file: {:?}", e);
+                println!(
+                    "The output file is still available locally at {}",
+                    output_path.display()
+                );
+                return Ok(2);
+            }
+        }
     }
     Ok(0)
 }
 
+/// Upload the FAM output file with the configured `rage.reporter` command and
+/// return the link it prints. The file is streamed into the reporter's stdin
+/// so large outputs are never read into memory.
+async fn upload_output_file(output_path: &Path) -> Result<String> {
+    let config = EdenFsInstance::global().get_config()?;
+    let reporter = config
+        .other
+        .get("rage")
+        .and_then(|section| section.get("reporter"))
+        .and_then(|value| value.as_str())
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("no rage.reporter command is configured"))?;
+
+    let mut child = tokio::process::Command::new("/bin/sh")
+        .arg("-c")
+        .arg(&reporter)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    let mut file = tokio::fs::File::open(output_path).await?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    tokio::io::copy(&mut file, &mut stdin).await?;
+    drop(stdin);
+
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(anyhow!("reporter command exited with {}", output.status));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
 #[derive(Parser, Debug)]
 #[clap(about = "Stop File Access Monitor to audit processes.")]
 struct StopCmd {}
@@ -161,6 +306,18 @@ struct ReadCmd {
         default_value = "10"
     )]
     count: usize,
+
+    #[clap(
+        help = "Only aggregate events of the given type, e.g. NOTIFY_OPEN. Can be repeated. When unset, all events are included.",
+        long = "event-type"
+    )]
+    event_types: Vec<String>,
+
+    #[clap(
+        help = "Emit the aggregated summary as JSON instead of the human-readable table. File lists are included when '--verbose' is also set.",
+        long = "json"
+    )]
+    json: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -184,35 +341,86 @@ struct Event {
     event_timestamp: u64,
 }
 
-fn parse_events<R: BufRead>(reader: R) -> Result<Vec<Event>> {
-    let mut objects: Vec<Event> = Vec::new();
-    let mut newObject = String::new();
-    for line in reader.lines().map_while(Result::ok) {
-        newObject.push_str(&line);
-        if line == "}" {
-            objects.push(serde_json::from_str(&newObject)?);
-            newObject.clear();
+/// Parse concatenated JSON events from the reader and hand them to `f` one at
+/// a time, so multi-GB FAM logs are never fully loaded into memory. The
+/// streaming deserializer finds object boundaries itself, so formatting and
+/// `{`/`}` inside path or command values don't confuse it. A trailing
+/// incomplete object is silently dropped.
+fn for_each_event<R: BufRead>(reader: R, mut f: impl FnMut(Event) -> Result<()>) -> Result<()> {
+    for event in serde_json::Deserializer::from_reader(reader).into_iter::<Event>() {
+        match event {
+            Ok(event) => f(event)?,
+            Err(e) if e.is_eof() => break,
+            Err(e) => return Err(e.into()),
         }
     }
+    Ok(())
+}
+
+fn parse_events<R: BufRead>(reader: R) -> Result<Vec<Event>> {
+    let mut objects: Vec<Event> = Vec::new();
+    for_each_event(reader, |event| {
+        objects.push(event);
+        Ok(())
+    })?;
     Ok(objects)
 }
 
-fn sort_pids(events: &[Event]) -> Vec<(u64, u64, u64)> {
-    // Count the number of events with the same PID
-    let mut pid_counts: HashMap<u64, (u64, u64)> = HashMap::new(); // pid -> (counter, ppid)
-    for event in events {
+#[derive(Serialize, Debug)]
+struct PidSummary {
+    pid: u64,
+    ppid: u64,
+    command: String,
+    access_count: u64,
+    #[serde(skip_serializing_if = "BTreeSet::is_empty")]
+    paths: BTreeSet<String>,
+}
+
+/// JSON document emitted by `eden fam read --json`.
+#[derive(Serialize, Debug)]
+struct Summary<'a> {
+    total_events: usize,
+    pids: &'a [PidSummary],
+}
+
+/// Incrementally built per-PID aggregation, so it can be fed one event at a
+/// time from the streaming parser.
+#[derive(Debug, Default)]
+struct PidSummaries {
+    summaries: HashMap<u64, PidSummary>,
+}
+
+impl PidSummaries {
+    fn add(&mut self, event: &Event) {
         let process = &event.process;
-        let count = pid_counts.entry(process.pid).or_insert((0, process.ppid));
-        count.0 += 1;
+        let summary = self
+            .summaries
+            .entry(process.pid)
+            .or_insert_with(|| PidSummary {
+                pid: process.pid,
+                ppid: process.ppid,
+                command: process.command.clone(),
+                access_count: 0,
+                paths: BTreeSet::new(),
+            });
+        summary.access_count += 1;
+        summary.paths.insert(event.file.path.clone());
+    }
+
+    /// Sort the results so we can find the top k
+    fn into_sorted(self) -> Vec<PidSummary> {
+        let mut sorted: Vec<PidSummary> = self.summaries.into_values().collect();
+        sorted.sort_by_key(|summary| Reverse(summary.access_count));
+        sorted
     }
+}
 
-    // Sort the results so we can find the top k
-    let mut sorted_pids: Vec<(u64, u64, u64)> = pid_counts
-        .into_iter()
-        .map(|(pid, (count, ppid))| (pid, count, ppid))
-        .collect();
-    sorted_pids.sort_by_key(|(_, count, _)| Reverse(*count));
-    sorted_pids
+fn summarize_pids(events: &[Event]) -> Vec<PidSummary> {
+    let mut summaries = PidSummaries::default();
+    for event in events {
+        summaries.add(event);
+    }
+    summaries.into_sorted()
 }
 
 #[async_trait]
@@ -223,25 +431,74 @@ impl crate::Subcommand for ReadCmd {
         let file = FsFile::open(path)?;
         let reader = BufReader::new(file);
 
-        let events = parse_events(reader)?;
+        // Track how often each requested event type matched, so we can warn
+        // about types that never showed up (likely typos).
+        let mut requested: HashMap<&str, u64> = self
+            .event_types
+            .iter()
+            .map(|event_type| (event_type.as_str(), 0))
+            .collect();
+
+        let mut summaries = PidSummaries::default();
+        let mut parsed = 0usize;
+        for_each_event(reader, |event| {
+            parsed += 1;
+            if !requested.is_empty() {
+                match requested.get_mut(event.event_type.as_str()) {
+                    Some(matched) => *matched += 1,
+                    None => return Ok(()),
+                }
+            }
+            summaries.add(&event);
+            Ok(())
+        })?;
+
+        for (event_type, matched) in &requested {
+            if *matched == 0 {
+                eprintln!(
+                    "Warning: no events of type '{}' found; is it a valid FAM event type?",
+                    event_type
+                );
+            }
+        }
 
-        if self.verbose {
-            println!("Parsed {} objects", events.len());
-            println!("{:#?}", events);
+        if self.verbose && !self.json {
+            println!("Parsed {} objects", parsed);
         }
 
-        let sorted_pids = sort_pids(&events);
+        let mut summaries = summaries.into_sorted();
+
+        if !self.verbose {
+            for summary in &mut summaries {
+                summary.paths.clear();
+            }
+        }
 
         let slice = if self.count == 0 {
-            &sorted_pids
+            &summaries
         } else {
-            &sorted_pids[..self.count.min(sorted_pids.len())]
+            &summaries[..self.count.min(summaries.len())]
         };
 
+        if self.json {
+            let summary = Summary {
+                total_events: parsed,
+                pids: slice,
+            };
+            println!("{}", serde_json::to_string_pretty(&summary)?);
+            return Ok(0);
+        }
+
         // Print the top results
-        println!("{:<6} | {:<7} | {}", "PID", "PPID", "Counts");
-        for (pid, count, ppid) in slice {
-            println!("{:<6} | {:<7} | {}", pid, ppid, count);
+        println!("{:<6} | {:<7} | {:<7} | {}", "PID", "PPID", "Counts", "Command");
+        for summary in slice {
+            println!(
+                "{:<6} | {:<7} | {:<7} | {}",
+                summary.pid, summary.ppid, summary.access_count, summary.command
+            );
+            for path in &summary.paths {
+                println!("       {}", path);
+            }
         }
         Ok(0)
     }
@@ -371,44 +628,131 @@ mod tests {
         assert_eq!(parsed.unwrap().len(), 1);
     }
 
-    #[test]
-    fn test_sort_pids() {
-        fn make_event(pid: u64, ppid: u64) -> Event {
-            Event {
-                event_type: "NOTIFY_OPEN".to_string(),
-                file: File {
-                    path: "what".to_string(),
-                },
-                process: Process {
-                    ancestors: vec![],
-                    args: vec![],
-                    command: "what".to_string(),
-                    pid,
-                    ppid,
-                    uid: 67890,
-                },
-                event_timestamp: 1740024705,
-            }
+    fn make_event(pid: u64, ppid: u64, path: &str) -> Event {
+        Event {
+            event_type: "NOTIFY_OPEN".to_string(),
+            file: File {
+                path: path.to_string(),
+            },
+            process: Process {
+                ancestors: vec![],
+                args: vec![],
+                command: "what".to_string(),
+                pid,
+                ppid,
+                uid: 67890,
+            },
+            event_timestamp: 1740024705,
         }
+    }
+
+    #[test]
+    fn test_dedup_nested_paths() {
+        let (kept, dropped) = dedup_nested_paths(vec![
+            PathBuf::from("/a/b"),
+            PathBuf::from("/a"),
+            PathBuf::from("/ab"),
+            PathBuf::from("/a"),
+            PathBuf::from("/c/d"),
+            PathBuf::from("/a/b/c"),
+        ]);
+        assert_eq!(
+            kept,
+            vec![
+                PathBuf::from("/a"),
+                PathBuf::from("/ab"),
+                PathBuf::from("/c/d")
+            ]
+        );
+        assert_eq!(
+            dropped,
+            vec![PathBuf::from("/a/b"), PathBuf::from("/a/b/c")]
+        );
+    }
+
+    #[test]
+    fn test_parse_compact_events() {
+        // All on one line: the old line-based `}` heuristic would choke here.
+        let events = r#"{"event_type":"NOTIFY_OPEN","file":{"path":"/tmp/a"},"process":{"ancestors":[],"args":[],"command":"cat","pid":1,"ppid":2,"uid":3},"event_timestamp":1740024705} {"event_type":"NOTIFY_OPEN","file":{"path":"/tmp/b"},"process":{"ancestors":[],"args":[],"command":"cat","pid":1,"ppid":2,"uid":3},"event_timestamp":1740024705}"#;
+        let parsed = parse_events(BufReader::new(Cursor::new(events))).unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[1].file.path, "/tmp/b");
+    }
+
+    #[test]
+    fn test_parse_closing_brace_in_path() {
+        let event = r#"{"event_type":"NOTIFY_OPEN","file":{"path":"/tmp/odd\n}\n"},"process":{"ancestors":[],"args":[],"command":"cat","pid":1,"ppid":2,"uid":3},"event_timestamp":1740024705}"#;
+        let parsed = parse_events(BufReader::new(Cursor::new(event))).unwrap();
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].file.path, "/tmp/odd\n}\n");
+    }
 
+    #[test]
+    fn test_parse_braces_in_strings() {
+        let event = r#"
+{
+  "event_type": "NOTIFY_OPEN",
+  "file": {
+    "path": "/tmp/weird{name}/file"
+  },
+  "process": {
+    "ancestors": [],
+    "args": ["--arg={\"nested\": 1}"],
+    "command": "/usr/local/bin/python3",
+    "pid": 22222,
+    "ppid": 99999,
+    "uid": 67890
+  },
+  "event_timestamp": 1740024705
+}
+        "#;
+        let parsed = parse_events(BufReader::new(Cursor::new(event))).unwrap();
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].file.path, "/tmp/weird{name}/file");
+    }
+
+    #[test]
+    fn test_summarize_pids() {
         let events = vec![
-            make_event(66778, 22309),
-            make_event(980066, 11759),
-            make_event(1, 2),
-            make_event(1, 2),
-            make_event(980066, 11759),
-            make_event(980066, 11759),
-            make_event(66778, 22309),
-            make_event(980066, 11759),
-            make_event(1, 2),
-            make_event(980066, 11759),
-            make_event(1, 2),
+            make_event(66778, 22309, "what"),
+            make_event(980066, 11759, "what"),
+            make_event(1, 2, "what"),
+            make_event(1, 2, "what"),
+            make_event(980066, 11759, "what"),
+            make_event(980066, 11759, "what"),
+            make_event(66778, 22309, "what"),
+            make_event(980066, 11759, "what"),
+            make_event(1, 2, "what"),
+            make_event(980066, 11759, "what"),
+            make_event(1, 2, "what"),
         ];
 
-        let sorted_pids = sort_pids(&events);
-        assert_eq!(sorted_pids.len(), 3);
-        assert_eq!(sorted_pids[0].0, 980066);
-        assert_eq!(sorted_pids[1].0, 1);
-        assert_eq!(sorted_pids[2].0, 66778);
+        let summaries = summarize_pids(&events);
+        assert_eq!(summaries.len(), 3);
+        assert_eq!(summaries[0].pid, 980066);
+        assert_eq!(summaries[0].access_count, 5);
+        assert_eq!(summaries[0].command, "what");
+        assert_eq!(summaries[1].pid, 1);
+        assert_eq!(summaries[2].pid, 66778);
+    }
+
+    #[test]
+    fn test_summarize_pids_distinct_paths() {
+        let events = vec![
+            make_event(1, 2, "/tmp/a"),
+            make_event(1, 2, "/tmp/b"),
+            make_event(1, 2, "/tmp/a"),
+            make_event(3, 2, "/tmp/c"),
+        ];
+
+        let summaries = summarize_pids(&events);
+        assert_eq!(summaries.len(), 2);
+        assert_eq!(summaries[0].pid, 1);
+        assert_eq!(summaries[0].access_count, 3);
+        assert_eq!(
+            summaries[0].paths.iter().collect::<Vec<_>>(),
+            vec!["/tmp/a", "/tmp/b"]
+        );
+        assert_eq!(summaries[1].paths.iter().collect::<Vec<_>>(), vec!["/tmp/c"]);
     }
 }
@@ -24,7 +24,7 @@ use tracing::Level;
 mod config;
 mod debug;
 mod du;
-#[cfg(target_os = "macos")]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
 mod file_access_monitor;
 mod gc;
 mod handles;
@@ -119,7 +119,7 @@ pub enum TopLevelSubcommand {
     Status(crate::status::StatusCmd),
     // Top(crate::top::TopCmd),
     Uptime(crate::uptime::UptimeCmd),
-    #[cfg(target_os = "macos")]
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
     FileAccessMonitor(crate::file_access_monitor::FileAccessMonitorCmd),
 }
 
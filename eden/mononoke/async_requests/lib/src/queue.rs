@@ -157,7 +157,7 @@ impl AsyncMethodRequestQueue {
         &self,
         ctx: &CoreContext,
         claimed_by: &ClaimedBy,
-    ) -> Result<Option<(RequestId, AsynchronousRequestParams)>, Error> {
+    ) -> Result<Option<(RequestId, AsynchronousRequestParams, Timestamp, u8)>, Error> {
         STATS::dequeue_called.add_value(1);
         self.dequeue_inner(ctx, claimed_by)
             .await
@@ -173,7 +173,7 @@ impl AsyncMethodRequestQueue {
         &self,
         ctx: &CoreContext,
         claimed_by: &ClaimedBy,
-    ) -> Result<Option<(RequestId, AsynchronousRequestParams)>, Error> {
+    ) -> Result<Option<(RequestId, AsynchronousRequestParams, Timestamp, u8)>, Error> {
         let entry = self
             .table
             .claim_and_get_new_request(ctx, claimed_by, self.repos.as_deref())
@@ -187,7 +187,8 @@ impl AsyncMethodRequestQueue {
             )
             .await?;
             let req_id = RequestId(entry.id, entry.request_type);
-            Ok(Some((req_id, thrift_params)))
+            let retry_count = entry.num_retries.unwrap_or(0);
+            Ok(Some((req_id, thrift_params, entry.created_at, retry_count)))
         } else {
             // empty queue
             Ok(None)
@@ -546,7 +547,7 @@ mod tests {
                     Some(res) => res,
                     None => panic!("Unexpected None"),
                 };
-                let (req_id, params_from_store) = res;
+                let (req_id, params_from_store, _created_at, _retry_count) = res;
 
                 // Verify that request params from blobstore match what we put there
                 assert_eq!(params_from_store, params.into());
@@ -12,6 +12,7 @@ mod scuba;
 mod stats;
 mod worker;
 
+use std::num::NonZeroU64;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
@@ -73,6 +74,10 @@ struct AsyncRequestsWorkerArgs {
     /// The number of requests / jobs to be processed concurrently
     #[clap(long, short = 'j', default_value = "1")]
     jobs: usize,
+    /// Log 1 in N successfully processed requests to scuba. Errors are always
+    /// logged at full rate.
+    #[clap(long, default_value = "1")]
+    success_sampling_rate: NonZeroU64,
     /// If true, the worker will process requests for the global queue.
     #[clap(long)]
     process_global_queue: bool,
@@ -5,6 +5,8 @@
  * GNU General Public License version 2.
  */
 
+use std::num::NonZeroU64;
+
 use anyhow::Error;
 use anyhow::Result;
 use async_requests::types::AsynchronousRequestResult;
@@ -12,6 +14,7 @@ use async_requests::RequestId;
 use async_requests_types_thrift::AsynchronousRequestResult as ThriftAsynchronousRequestResult;
 use context::CoreContext;
 use futures_stats::FutureStats;
+use mononoke_types::Timestamp;
 use slog::info;
 use source_control::AsyncRequestError;
 use stats::define_stats;
@@ -23,6 +26,7 @@ define_stats! {
     prefix = "async_requests.worker.process";
 
     process_complete_failed: timeseries("complete.failed"; Count),
+    process_permanent_error: timeseries("permanent.error"; Count),
     process_retriable_error: timeseries("retriable.error"; Count),
     process_succeeded: timeseries("succeeded"; Count),
     process_error: timeseries("error"; Count),
@@ -34,6 +38,7 @@ impl AsyncMethodRequestWorker {
         ctx: &CoreContext,
         req_id: &RequestId,
         target: &str,
+        enqueued_at: Timestamp,
     ) -> CoreContext {
         let ctx = ctx.with_mutated_scuba(|mut scuba| {
             // Legacy columns
@@ -43,6 +48,10 @@ impl AsyncMethodRequestWorker {
             // New column names to match the mononoke_scs_server table
             scuba.add("token", format!("{}", req_id.0.0));
             scuba.add("method", req_id.1.0.clone());
+
+            // How long the request sat in the queue before this worker picked
+            // it up, as opposed to how long processing took.
+            scuba.add("queue_wait_ms", enqueued_at.since_millis());
             scuba
         });
 
@@ -61,12 +70,15 @@ pub(crate) fn log_start(ctx: &CoreContext) {
 }
 
 /// Log the result of a request: either a success or a final error. Retriable errors (i.e. where the worker
-/// internally failed and will retry) are logged separately.
+/// internally failed and will retry) are logged separately. Successes are
+/// sampled at `success_sampling_rate` (1 in N); errors are always logged.
 pub(crate) fn log_result(
     ctx: CoreContext,
     stats: &FutureStats,
     result: &AsynchronousRequestResult,
     complete_result: &Result<bool>,
+    retry_count: u8,
+    success_sampling_rate: NonZeroU64,
 ) {
     let mut scuba = ctx.scuba().clone();
 
@@ -98,22 +110,40 @@ pub(crate) fn log_result(
 
     scuba.add_future_stats(stats);
     scuba.add("status", status);
+    scuba.add("retry_count", retry_count);
 
     if let Some(error) = error {
         scuba.unsampled();
         scuba.add("error", error.as_str());
+    } else {
+        scuba.sampled(success_sampling_rate);
     }
     scuba.log_with_msg("Request complete", None);
 }
 
-/// Log a retriable error, i.e. one that failed because of internal worker issues and will be retried.
-pub(crate) fn log_retriable_error(ctx: CoreContext, stats: &FutureStats, error: Error) {
+/// Log an error where the worker itself failed to process the request. If the
+/// worker will retry the request, the error is transient; otherwise retries are
+/// exhausted and the request will be failed permanently.
+pub(crate) fn log_worker_error(
+    ctx: CoreContext,
+    stats: &FutureStats,
+    error: Error,
+    will_retry: bool,
+    retry_count: u8,
+) {
     let mut scuba = ctx.scuba().clone();
 
-    STATS::process_retriable_error.add_value(1);
+    let status = if will_retry {
+        STATS::process_retriable_error.add_value(1);
+        "RETRIABLE_ERROR"
+    } else {
+        STATS::process_permanent_error.add_value(1);
+        "PERMANENT_ERROR"
+    };
 
     scuba.add_future_stats(stats);
-    scuba.add("status", "RETRIABLE_ERROR");
+    scuba.add("status", status);
+    scuba.add("retry_count", retry_count);
     scuba.unsampled();
     scuba.add("error", format!("{:?}", error));
 
@@ -13,6 +13,7 @@
 //! One important consideration to keep in mind - worker executes request "at least once"
 //! but not exactly once i.e. the same request might be executed a few times.
 
+use std::num::NonZeroU64;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
@@ -53,7 +54,7 @@ use stats::prelude::*;
 
 use crate::methods::megarepo_async_request_compute;
 use crate::scuba::log_result;
-use crate::scuba::log_retriable_error;
+use crate::scuba::log_worker_error;
 use crate::scuba::log_start;
 use crate::stats::stats_loop;
 use crate::AsyncRequestsWorkerArgs;
@@ -84,6 +85,7 @@ pub struct AsyncMethodRequestWorker {
     will_exit: Arc<AtomicBool>,
     limit: Option<usize>,
     concurrency_limit: usize,
+    success_sampling_rate: NonZeroU64,
 }
 
 impl AsyncMethodRequestWorker {
@@ -119,6 +121,7 @@ impl AsyncMethodRequestWorker {
             will_exit,
             limit: args.request_limit,
             concurrency_limit: args.jobs,
+            success_sampling_rate: args.success_sampling_rate,
         })
     }
 }
@@ -158,12 +161,13 @@ impl RepoShardedProcessExecutor for AsyncMethodRequestWorker {
         request_stream
             .for_each_concurrent(
                 Some(self.concurrency_limit),
-                |(req_id, params)| async move {
+                |(req_id, params, created_at, retry_count)| async move {
                     let worker = self.clone();
                     let ctx = CoreContext::clone(&self.ctx);
-                    if let Err(e) =
-                        mononoke::spawn_task(worker.compute_and_mark_completed(ctx, req_id, params))
-                            .await
+                    if let Err(e) = mononoke::spawn_task(worker.compute_and_mark_completed(
+                        ctx, req_id, params, created_at, retry_count,
+                    ))
+                    .await
                     {
                         warn!(self.ctx.logger(), "Error spawning request: {:?}", e);
                     }
@@ -191,7 +195,7 @@ impl AsyncMethodRequestWorker {
         ctx: &CoreContext,
         queue: Arc<AsyncMethodRequestQueue>,
         will_exit: Arc<AtomicBool>,
-    ) -> impl Stream<Item = (RequestId, AsynchronousRequestParams)> {
+    ) -> impl Stream<Item = (RequestId, AsynchronousRequestParams, Timestamp, u8)> {
         let claimed_by = ClaimedBy(self.name.clone());
         let sleep_time = Duration::from_millis(DEQUEUE_STREAM_SLEEP_TIME);
         Self::request_stream_inner(
@@ -211,7 +215,7 @@ impl AsyncMethodRequestWorker {
         will_exit: Arc<AtomicBool>,
         sleep_time: Duration,
         abandoned_threshold_secs: i64,
-    ) -> impl Stream<Item = (RequestId, AsynchronousRequestParams)> {
+    ) -> impl Stream<Item = (RequestId, AsynchronousRequestParams, Timestamp, u8)> {
         stream! {
             loop {
                 STATS::dequeue_called.add_value(1);
@@ -236,8 +240,8 @@ impl AsyncMethodRequestWorker {
                         warn!(ctx.logger(), "error while dequeueing, skipping: {:?}", e);
                         tokio::time::sleep(sleep_time).await;
                     }
-                    Ok(Some((request_id, params))) => {
-                        yield (request_id, params);
+                    Ok(Some((request_id, params, created_at, retry_count))) => {
+                        yield (request_id, params, created_at, retry_count);
                     }
                     Ok(None) => {
                         // No requests in the queues, sleep before trying again.
@@ -289,6 +293,8 @@ impl AsyncMethodRequestWorker {
         ctx: CoreContext,
         req_id: RequestId,
         params: AsynchronousRequestParams,
+        created_at: Timestamp,
+        retry_count: u8,
     ) {
         let target = match params.target() {
             Ok(target) => target,
@@ -298,7 +304,7 @@ impl AsyncMethodRequestWorker {
                 return;
             }
         };
-        let ctx = self.prepare_ctx(&ctx, &req_id, &target);
+        let ctx = self.prepare_ctx(&ctx, &req_id, &target, created_at);
         log_start(&ctx);
 
         // Do the actual work.
@@ -335,7 +341,14 @@ impl AsyncMethodRequestWorker {
                             .queue
                             .complete(&ctx, &req_id, work_result.clone())
                             .await;
-                        log_result(ctx.clone(), &stats, &work_result, &complete_result);
+                        log_result(
+                            ctx.clone(),
+                            &stats,
+                            &work_result,
+                            &complete_result,
+                            retry_count,
+                            self.success_sampling_rate,
+                        );
                         match complete_result {
                             Ok(updated) => {
                                 info!(
@@ -353,7 +366,7 @@ impl AsyncMethodRequestWorker {
                     }
                     Err(err) => {
                         let err_result = self.queue.retry(&ctx, &req_id).await;
-                        match err_result {
+                        let will_retry = match err_result {
                             Ok(will_retry) => {
                                 if will_retry {
                                     info!(
@@ -370,16 +383,18 @@ impl AsyncMethodRequestWorker {
                                         err
                                     );
                                 }
+                                will_retry
                             }
                             Err(err) => {
                                 error!(
                                     ctx.logger(),
                                     "[{}] failed to process retry attempt: {:?}", &req_id.0, err
                                 );
+                                false
                             }
-                        }
+                        };
 
-                        log_retriable_error(ctx.clone(), &stats, err);
+                        log_worker_error(ctx.clone(), &stats, err, will_retry, retry_count);
                     }
                 }
             }
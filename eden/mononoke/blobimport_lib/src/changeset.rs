@@ -493,6 +493,10 @@ impl<R: BlobimportRepoLike + Clone + 'static> UploadChangesets<R> {
                     cs_metadata,
                     verify_origin_repo: origin_repo.clone(),
                     upload_to_blobstore_only: false,
+                    batch_graph_insert: None,
+                    max_concurrent_uploads: None,
+                    validator: None,
+                    changed_files_base: None,
                 };
                 let cshandle =
                     create_changeset.create(ctx.clone(), &repo, None, scuba_logger.clone());
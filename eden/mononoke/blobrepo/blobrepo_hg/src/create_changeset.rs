@@ -17,10 +17,14 @@ use anyhow::Error;
 use anyhow::Result;
 use blobstore::Blobstore;
 use blobstore::Loadable;
+use bonsai_hg_mapping::BonsaiHgMapping;
 use bonsai_hg_mapping::BonsaiHgMappingArc;
 use bonsai_hg_mapping::BonsaiHgMappingEntry;
 use bonsai_hg_mapping::BonsaiHgMappingRef;
 use cloned::cloned;
+use commit_graph::ChangesetParents;
+use commit_graph::ChangesetSubtreeSources;
+use commit_graph::CommitGraphWriter;
 use commit_graph::CommitGraphWriterArc;
 use context::CoreContext;
 use futures::channel::oneshot;
@@ -44,6 +48,7 @@ use mononoke_macros::mononoke;
 use mononoke_types::subtree_change::SubtreeChange;
 use mononoke_types::BlobstoreValue;
 use mononoke_types::BonsaiChangeset;
+use mononoke_types::ChangesetId;
 use mononoke_types::FileType;
 use mononoke_types::MPath;
 use mononoke_types::NonRootMPath;
@@ -53,6 +58,7 @@ use scuba_ext::MononokeScubaSampleBuilder;
 use sorted_vector_map::SortedVectorMap;
 use stats::prelude::*;
 use uuid::Uuid;
+use vec1::Vec1;
 use wireproto_handler::BackupSourceRepo;
 
 use crate::bonsai_generation::create_bonsai_changeset_object;
@@ -68,12 +74,14 @@ define_stats! {
     create_changeset_cf_count: timeseries("create_changeset.changed_files_count"; Average, Sum),
 }
 
+/// Returns the bonsai changeset to use, and whether the locally generated one
+/// was substituted with the origin repo's version.
 async fn verify_bonsai_changeset_with_origin(
     ctx: &CoreContext,
     bcs: BonsaiChangeset,
     cs: &HgBlobChangeset,
     origin_repo: &BackupSourceRepo,
-) -> Result<BonsaiChangeset, Error> {
+) -> Result<(BonsaiChangeset, bool), Error> {
     // There are some non-canonical bonsai changesets in the prod repos.
     // To make the blobimported backup repos exactly the same, we will
     // fetch bonsai from the prod in case of mismatch
@@ -83,14 +91,104 @@ async fn verify_bonsai_changeset_with_origin(
         .await?;
     match origin_bonsai_id {
         Some(id) if id != bcs.get_changeset_id() => {
-            id.load(ctx, origin_repo.repo_blobstore())
+            let bcs = id
+                .load(ctx, origin_repo.repo_blobstore())
                 .map_err(|e| anyhow!(e))
+                .await?;
+            Ok((bcs, true))
+        }
+        _ => Ok((bcs, false)),
+    }
+}
+
+/// An accumulator for commit graph and bonsai-hg mapping inserts that were
+/// deferred by `CreateChangeset` via `batch_graph_insert`.
+///
+/// Entries are recorded in changeset completion order, so for changesets
+/// linked through `ChangesetHandle` parents the recorded order is a valid
+/// topological order, which is what `CommitGraphWriter::add_many` requires.
+///
+/// `flush` is not atomic: the commit graph insert happens before the mapping
+/// inserts, and a failure part way through leaves the already inserted
+/// changesets visible, just like a failure between two per-changeset inserts
+/// would. Until `flush` returns, the recorded changesets are in the blobstore
+/// but unreachable.
+#[derive(Default)]
+pub struct GraphInsertBatch {
+    entries: Mutex<
+        Vec<(
+            ChangesetId,
+            ChangesetParents,
+            ChangesetSubtreeSources,
+            BonsaiHgMappingEntry,
+        )>,
+    >,
+}
+
+impl GraphInsertBatch {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    fn record(
+        &self,
+        bcs_id: ChangesetId,
+        parents: ChangesetParents,
+        subtree_sources: ChangesetSubtreeSources,
+        mapping_entry: BonsaiHgMappingEntry,
+    ) {
+        self.entries
+            .lock()
+            .expect("poisoned lock")
+            .push((bcs_id, parents, subtree_sources, mapping_entry));
+    }
+
+    /// Insert all recorded changesets into the commit graph with a single
+    /// batched call, followed by their bonsai-hg mapping entries. Returns
+    /// the number of changesets newly added to the commit graph.
+    pub async fn flush(
+        &self,
+        ctx: &CoreContext,
+        commit_graph_writer: &dyn CommitGraphWriter,
+        bonsai_hg_mapping: &dyn BonsaiHgMapping,
+    ) -> Result<usize> {
+        let entries = std::mem::take(&mut *self.entries.lock().expect("poisoned lock"));
+        let mut graph_entries = Vec::with_capacity(entries.len());
+        let mut mapping_entries = Vec::with_capacity(entries.len());
+        for (bcs_id, parents, subtree_sources, mapping_entry) in entries {
+            graph_entries.push((bcs_id, parents, subtree_sources));
+            mapping_entries.push(mapping_entry);
+        }
+        let graph_entries = match Vec1::try_from_vec(graph_entries) {
+            Ok(graph_entries) => graph_entries,
+            Err(_) => return Ok(0),
+        };
+        let added = commit_graph_writer
+            .add_many(ctx, graph_entries)
+            .await
+            .context("While batch inserting into changeset table")?;
+        for mapping_entry in mapping_entries {
+            bonsai_hg_mapping
+                .add(ctx, mapping_entry)
                 .await
+                .context("While inserting mapping")?;
         }
-        _ => Ok(bcs),
+        Ok(added)
     }
 }
 
+/// Which parent manifests `compute_changed_files` diffs against when
+/// `CreateChangeset::expected_files` is not provided.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChangedFilesBase {
+    /// Diff against the first parent only.
+    P1Only,
+    /// Diff against the second parent only.
+    P2Only,
+    /// Diff against both parents (the default).
+    Both,
+}
+
 pub struct CreateChangeset {
     /// This should always be provided, keeping it an Option for tests
     pub expected_nodeid: Option<HgNodeHash>,
@@ -107,6 +205,24 @@ pub struct CreateChangeset {
     /// manually after this call. Effectively, the commit will be in the blobstore, but
     /// unreachable.
     pub upload_to_blobstore_only: bool,
+    /// If set (and `upload_to_blobstore_only` is false), the commit graph and
+    /// bonsai-hg mapping inserts are recorded into this accumulator instead of
+    /// being performed per changeset. The caller must call
+    /// `GraphInsertBatch::flush` once all the returned handles have completed.
+    pub batch_graph_insert: Option<Arc<GraphInsertBatch>>,
+    /// Cap on how many `sub_entries` uploads run concurrently. `None` keeps
+    /// the default fan-out.
+    pub max_concurrent_uploads: Option<usize>,
+    /// Optional pre-save hook, invoked with the assembled hg and bonsai
+    /// changesets before either is saved to the blobstore. An error fails the
+    /// whole create and is propagated to dependent changesets through
+    /// `signal_parent_ready`, like the hash-mismatch check.
+    pub validator: Option<Arc<dyn Fn(&HgBlobChangeset, &BonsaiChangeset) -> Result<()> + Send + Sync>>,
+    /// Which parents the changed-files computation diffs against. `None`
+    /// keeps the default of diffing against both. Overriding this matters
+    /// for octopus-like reconstruction, where the default parent ordering
+    /// produces a misleading file list.
+    pub changed_files_base: Option<ChangedFilesBase>,
 }
 
 impl CreateChangeset {
@@ -133,10 +249,17 @@ impl CreateChangeset {
             cloned!(ctx, entry_processor);
             let root_manifest = self.root_manifest;
             let sub_entries = self.sub_entries;
+            let max_concurrent_uploads = self.max_concurrent_uploads;
             async move {
-                process_entries(&ctx, &entry_processor, root_manifest, sub_entries)
-                    .await
-                    .context("While processing entries")
+                process_entries(
+                    &ctx,
+                    &entry_processor,
+                    root_manifest,
+                    sub_entries,
+                    max_concurrent_uploads,
+                )
+                .await
+                .context("While processing entries")
             }
         };
 
@@ -148,6 +271,7 @@ impl CreateChangeset {
                     Err(err) => Err(err.context("While waiting for parents to complete")),
                     Ok((stats, result)) => {
                         scuba_logger
+                            .add("parents_wait_ms", stats.completion_time.as_millis() as u64)
                             .add_future_stats(&stats)
                             .log_with_msg("Parents completed", None);
                         Ok(result)
@@ -160,13 +284,21 @@ impl CreateChangeset {
         let changeset = {
             cloned!(ctx, signal_parent_ready, mut scuba_logger);
             let expected_files = self.expected_files;
+            let changed_files_base = self.changed_files_base;
             let subtree_changes = self.subtree_changes;
             let cs_metadata = self.cs_metadata;
+            let validator = self.validator;
             let blobstore = repo.repo_blobstore_arc();
 
             async move {
-                let (root_mf_id, (parents, parent_manifest_hashes, bonsai_parents)) =
-                    future::try_join(upload_entries, parents_data).await?;
+                let (
+                    (upload_entries_stats, root_mf_id),
+                    (parents, parent_manifest_hashes, bonsai_parents),
+                ) = future::try_join(upload_entries.try_timed(), parents_data).await?;
+                scuba_logger.add(
+                    "upload_entries_ms",
+                    upload_entries_stats.completion_time.as_millis() as u64,
+                );
                 let files = if let Some(expected_files) = expected_files {
                     STATS::create_changeset_expected_cf.add_value(1);
                     // We are trusting the callee to provide a list of changed files, used
@@ -180,12 +312,24 @@ impl CreateChangeset {
                     Vec::new()
                 } else {
                     STATS::create_changeset_compute_cf.add_value(1);
+                    let (base_p1, base_p2) = match changed_files_base {
+                        Some(ChangedFilesBase::P1Only) => {
+                            (parent_manifest_hashes.first().cloned(), None)
+                        }
+                        Some(ChangedFilesBase::P2Only) => {
+                            (parent_manifest_hashes.get(1).cloned(), None)
+                        }
+                        Some(ChangedFilesBase::Both) | None => (
+                            parent_manifest_hashes.first().cloned(),
+                            parent_manifest_hashes.get(1).cloned(),
+                        ),
+                    };
                     compute_changed_files(
                         ctx.clone(),
                         blobstore.clone(),
                         root_mf_id,
-                        parent_manifest_hashes.first().cloned(),
-                        parent_manifest_hashes.get(1).cloned(),
+                        base_p1,
+                        base_p2,
                     )
                     .await?
                 };
@@ -212,13 +356,19 @@ impl CreateChangeset {
                         .await?;
                         let bonsai_cs = if let Some(origin_repo) = self.verify_origin_repo.as_ref()
                         {
-                            verify_bonsai_changeset_with_origin(
+                            let (bonsai_cs, substituted) = verify_bonsai_changeset_with_origin(
                                 &ctx,
                                 bonsai_cs,
                                 &hg_cs,
                                 origin_repo,
                             )
-                            .await?
+                            .await?;
+                            if substituted {
+                                // Let the blobimport backup job count how often the
+                                // origin repo disagreed with the generated bonsai.
+                                scuba_logger.add("bonsai_substituted", 1);
+                            }
+                            bonsai_cs
                         } else {
                             bonsai_cs
                         };
@@ -245,6 +395,10 @@ impl CreateChangeset {
                     }
                 }
 
+                if let Some(validator) = validator {
+                    validator(&hg_cs, &bonsai_cs).context("While validating changeset")?;
+                }
+
                 scuba_logger
                     .add("changeset_id", format!("{}", cs_id))
                     .log_with_msg("Changeset uuid to hash mapping", None);
@@ -265,13 +419,29 @@ impl CreateChangeset {
                     .expect("signal_parent_ready cannot be taken yet")
                     .send(Ok((bcs_id, cs_id, manifest_id)));
 
-                futures::try_join!(
-                    bcs_fut,
-                    hg_cs.save(&ctx, &blobstore),
-                    entry_processor
-                        .finalize(&ctx, root_mf_id, parent_manifest_hashes)
-                        .map_err(|err| err.context("While finalizing processing")),
-                )?;
+                let ((bonsai_save_stats, _), (hg_save_stats, _), (finalize_stats, _)) =
+                    futures::try_join!(
+                        bcs_fut.try_timed(),
+                        hg_cs.save(&ctx, &blobstore).try_timed(),
+                        entry_processor
+                            .finalize(&ctx, root_mf_id, parent_manifest_hashes)
+                            .map_err(|err| err.context("While finalizing processing"))
+                            .try_timed(),
+                    )?;
+                scuba_logger
+                    .add(
+                        "bonsai_save_ms",
+                        bonsai_save_stats.completion_time.as_millis() as u64,
+                    )
+                    .add(
+                        "hg_save_ms",
+                        hg_save_stats.completion_time.as_millis() as u64,
+                    )
+                    .add(
+                        "finalize_ms",
+                        finalize_stats.completion_time.as_millis() as u64,
+                    )
+                    .log_with_msg("Changeset saved", None);
 
                 Ok::<_, Error>((hg_cs, bonsai_cs))
             }
@@ -308,30 +478,136 @@ impl CreateChangeset {
             let ((hg_cs, bonsai_cs), _) = future::try_join(changeset, parents_complete).await?;
 
             if !self.upload_to_blobstore_only {
-                // update changeset mapping
+                let bcs_id = bonsai_cs.get_changeset_id();
+                let bonsai_hg_entry = BonsaiHgMappingEntry {
+                    hg_cs_id: hg_cs.get_changeset_id(),
+                    bcs_id,
+                };
+                if let Some(batch) = &self.batch_graph_insert {
+                    batch.record(
+                        bcs_id,
+                        bonsai_cs.parents().collect(),
+                        bonsai_cs.subtree_sources().collect(),
+                        bonsai_hg_entry,
+                    );
+                } else {
+                    // update changeset mapping
+                    commit_graph_writer
+                        .add(
+                            &ctx,
+                            bcs_id,
+                            bonsai_cs.parents().collect(),
+                            bonsai_cs.subtree_sources().collect(),
+                        )
+                        .await
+                        .context("While inserting into changeset table")?;
+
+                    // update bonsai mapping
+                    bonsai_hg_mapping
+                        .add(&ctx, bonsai_hg_entry)
+                        .await
+                        .context("While inserting mapping")?;
+                }
+            }
+
+            Ok::<_, Error>((bonsai_cs, hg_cs))
+        }
+        .try_timed()
+        .map({
+            cloned!(mut scuba_logger);
+            move |result| match result {
+                Ok((stats, result)) => {
+                    scuba_logger
+                        .add_future_stats(&stats)
+                        .log_with_msg("CreateChangeset Finished", None);
+                    Ok(result)
+                }
+                Err(err) => Err(err.context(format!(
+                    "While creating Changeset {:?}, uuid: {}",
+                    expected_nodeid, uuid
+                ))),
+            }
+        });
+
+        let can_be_parent = can_be_parent
+            .map(|r| match r {
+                Ok(res) => res,
+                Err(e) => Err(format_err!("can_be_parent: {:?}", e)),
+            })
+            .boxed()
+            .try_shared();
+
+        let completion_future = mononoke::spawn_task(changeset_complete_fut)
+            .map(|result| result?)
+            .boxed()
+            .try_shared();
+
+        ChangesetHandle::new_pending(can_be_parent, completion_future)
+    }
+
+    /// Fast path for re-syncing commits whose blobs were already uploaded:
+    /// verify that both the hg changeset and the bonsai changeset are present
+    /// in the blobstore, and only perform the commit graph and bonsai-hg
+    /// mapping inserts. The hg changeset is not rebuilt from entries.
+    pub fn create_for_existing(
+        ctx: CoreContext,
+        repo: &(impl RepoBlobstoreArc + CommitGraphWriterArc + BonsaiHgMappingArc + Send + Sync),
+        hg_cs_id: HgChangesetId,
+        bonsai: BonsaiChangeset,
+        mut scuba_logger: MononokeScubaSampleBuilder,
+    ) -> ChangesetHandle {
+        STATS::create_changeset.add_value(1);
+        let uuid = Uuid::new_v4();
+        scuba_logger.add("changeset_uuid", format!("{}", uuid));
+
+        let (signal_parent_ready, can_be_parent) = oneshot::channel();
+        let signal_parent_ready = Arc::new(Mutex::new(Some(signal_parent_ready)));
+        let blobstore = repo.repo_blobstore_arc();
+        let commit_graph_writer = repo.commit_graph_writer_arc();
+        let bonsai_hg_mapping = repo.bonsai_hg_mapping_arc();
+
+        let changeset_complete_fut = {
+            cloned!(signal_parent_ready);
+            async move {
+                let bcs_id = bonsai.get_changeset_id();
+                // Loading both changesets by id doubles as the check that they
+                // were already uploaded.
+                let (hg_cs, bonsai_cs) = future::try_join(
+                    hg_cs_id.load(&ctx, &blobstore).map_err(|e| anyhow!(e)),
+                    bcs_id.load(&ctx, &blobstore).map_err(|e| anyhow!(e)),
+                )
+                .await
+                .context("While verifying that the changesets are already in the blobstore")?;
+
+                // We deliberately eat this error - see the comment in `create`.
+                let _ = signal_parent_ready
+                    .lock()
+                    .expect("poisoned lock")
+                    .take()
+                    .expect("signal_parent_ready cannot be taken yet")
+                    .send(Ok((
+                        bcs_id,
+                        hg_cs.get_changeset_id().into_nodehash(),
+                        hg_cs.manifestid(),
+                    )));
+
                 commit_graph_writer
                     .add(
                         &ctx,
-                        bonsai_cs.get_changeset_id(),
+                        bcs_id,
                         bonsai_cs.parents().collect(),
                         bonsai_cs.subtree_sources().collect(),
                     )
                     .await
                     .context("While inserting into changeset table")?;
 
-                // update bonsai mapping
-                let bcs_id = bonsai_cs.get_changeset_id();
-                let bonsai_hg_entry = BonsaiHgMappingEntry {
-                    hg_cs_id: hg_cs.get_changeset_id(),
-                    bcs_id,
-                };
                 bonsai_hg_mapping
-                    .add(&ctx, bonsai_hg_entry)
+                    .add(&ctx, BonsaiHgMappingEntry { hg_cs_id, bcs_id })
                     .await
                     .context("While inserting mapping")?;
-            }
 
-            Ok::<_, Error>((bonsai_cs, hg_cs))
+                Ok::<_, Error>((bonsai_cs, hg_cs))
+            }
         }
         .try_timed()
         .map({
@@ -343,10 +619,19 @@ impl CreateChangeset {
                         .log_with_msg("CreateChangeset Finished", None);
                     Ok(result)
                 }
-                Err(err) => Err(err.context(format!(
-                    "While creating Changeset {:?}, uuid: {}",
-                    expected_nodeid, uuid
-                ))),
+                Err(err) => {
+                    let err = err.context(format!(
+                        "While recording existing Changeset {}, uuid: {}",
+                        hg_cs_id, uuid
+                    ));
+                    let trigger = signal_parent_ready.lock().expect("poisoned lock").take();
+                    if let Some(trigger) = trigger {
+                        // Ignore errors if the receiving end has gone away.
+                        let e = format_err!("signal_parent_ready failed: {:?}", err);
+                        let _ = trigger.send(Err(e));
+                    }
+                    Err(err)
+                }
             }
         });
 
@@ -432,18 +717,112 @@ mod tests {
     use fbinit::FacebookInit;
     use manifest::ManifestOps;
     use maplit::hashmap;
+    use mercurial_types_mocks::nodehash::ONES_CSID as HG_ONES_CSID;
+    use mercurial_types_mocks::nodehash::THREES_CSID as HG_THREES_CSID;
+    use mercurial_types_mocks::nodehash::TWOS_CSID as HG_TWOS_CSID;
     use mercurial_derivation::DeriveHgChangeset;
     use mercurial_types::subtree::HgSubtreeChanges;
     use mercurial_types::subtree::HgSubtreeCopy;
     use mercurial_types::subtree::HgSubtreeDeepCopy;
     use mercurial_types::subtree::HgSubtreeMerge;
     use mononoke_macros::mononoke;
+    use mononoke_types_mocks::changesetid::ONES_CSID;
+    use mononoke_types_mocks::changesetid::THREES_CSID;
+    use mononoke_types_mocks::changesetid::TWOS_CSID;
+    use smallvec::smallvec;
     use sorted_vector_map::sorted_vector_map;
     use tests_utils::drawdag::extend_from_dag_with_actions;
     use tests_utils::BasicTestRepo;
 
     use super::*;
 
+    #[mononoke::fbinit_test]
+    async fn test_batched_graph_insert_matches_per_commit(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        let per_commit: BasicTestRepo = test_repo_factory::build_empty(fb).await?;
+        let batched: BasicTestRepo = test_repo_factory::build_empty(fb).await?;
+
+        // A small chain ending in a merge, in topological order.
+        let changesets: Vec<(ChangesetId, ChangesetParents, BonsaiHgMappingEntry)> = vec![
+            (
+                ONES_CSID,
+                smallvec![],
+                BonsaiHgMappingEntry {
+                    hg_cs_id: HG_ONES_CSID,
+                    bcs_id: ONES_CSID,
+                },
+            ),
+            (
+                TWOS_CSID,
+                smallvec![ONES_CSID],
+                BonsaiHgMappingEntry {
+                    hg_cs_id: HG_TWOS_CSID,
+                    bcs_id: TWOS_CSID,
+                },
+            ),
+            (
+                THREES_CSID,
+                smallvec![ONES_CSID, TWOS_CSID],
+                BonsaiHgMappingEntry {
+                    hg_cs_id: HG_THREES_CSID,
+                    bcs_id: THREES_CSID,
+                },
+            ),
+        ];
+
+        for (bcs_id, parents, mapping_entry) in changesets.clone() {
+            per_commit
+                .commit_graph_writer
+                .add(&ctx, bcs_id, parents, vec![])
+                .await?;
+            per_commit.bonsai_hg_mapping.add(&ctx, mapping_entry).await?;
+        }
+
+        let batch = GraphInsertBatch::new();
+        for (bcs_id, parents, mapping_entry) in changesets.clone() {
+            batch.record(bcs_id, parents, vec![], mapping_entry);
+        }
+        let added = batch
+            .flush(
+                &ctx,
+                batched.commit_graph_writer.as_ref(),
+                batched.bonsai_hg_mapping.as_ref(),
+            )
+            .await?;
+        assert_eq!(added, changesets.len());
+
+        for (bcs_id, _, mapping_entry) in changesets {
+            assert_eq!(
+                batched.commit_graph.changeset_parents(&ctx, bcs_id).await?,
+                per_commit
+                    .commit_graph
+                    .changeset_parents(&ctx, bcs_id)
+                    .await?,
+            );
+            assert_eq!(
+                batched
+                    .bonsai_hg_mapping
+                    .get_hg_from_bonsai(&ctx, bcs_id)
+                    .await?,
+                Some(mapping_entry.hg_cs_id),
+            );
+        }
+
+        // Flushing again is a no-op.
+        assert_eq!(
+            batch
+                .flush(
+                    &ctx,
+                    batched.commit_graph_writer.as_ref(),
+                    batched.bonsai_hg_mapping.as_ref(),
+                )
+                .await?,
+            0
+        );
+
+        Ok(())
+    }
+
     #[mononoke::fbinit_test]
     async fn test_resolve_subtree_changes(fb: FacebookInit) -> Result<()> {
         let ctx = CoreContext::test_mock(fb);
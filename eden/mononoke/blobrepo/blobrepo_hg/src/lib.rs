@@ -18,6 +18,7 @@ pub mod errors {
     pub use blobrepo_errors::*;
 }
 pub use create_changeset::CreateChangeset;
+pub use create_changeset::GraphInsertBatch;
 pub mod file_history {
     pub use blobrepo_common::file_history::*;
 }
@@ -7,6 +7,8 @@
 
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::sync::Mutex;
 
@@ -70,8 +72,13 @@ define_stats! {
     finalize_uploaded_filenodes: timeseries(Rate, Average, Sum),
     finalize_uploaded_manifests: timeseries(Rate, Average, Sum),
     finalize_compute_copy_from_info: timeseries(Rate, Sum),
+    process_entries_peak_in_flight: timeseries(Average, Sum),
 }
 
+/// How many child entry uploads run concurrently when the caller doesn't
+/// specify a limit.
+const DEFAULT_CONCURRENT_UPLOADS: usize = 100;
+
 /// A handle to a possibly incomplete HgBlobChangeset. This is used instead of
 /// Future<Item = HgBlobChangeset> where we don't want to fully serialize waiting for completion.
 /// For example, `create_changeset` takes these as p1/p2 so that it can handle the blobstore side
@@ -417,6 +424,7 @@ pub async fn process_entries<'a>(
     entry_processor: &'a UploadEntries,
     root_manifest: BoxFuture<'a, Result<Option<(HgManifestId, RepoPath)>>>,
     new_child_entries: BoxStream<'a, Result<(Entry<HgManifestId, HgFileNodeId>, RepoPath)>>,
+    max_concurrent_uploads: Option<usize>,
 ) -> Result<HgManifestId> {
     let root_manifest_fut = async move {
         let root_manifest = root_manifest
@@ -435,15 +443,29 @@ pub async fn process_entries<'a>(
         }
     };
 
+    let in_flight = AtomicUsize::new(0);
+    let peak_in_flight = AtomicUsize::new(0);
+
     // Not wrapping this future in "async move" causes mismatched opaque types
     // error ¯\_(ツ)_/¯
-    let child_entries_fut = async move {
-        new_child_entries
-            .map_err(|err| err.context("While uploading child entries"))
-            .try_for_each_concurrent(100, move |(entry, path)| {
-                entry_processor.process_one_entry(ctx, entry, path)
-            })
-            .await
+    let child_entries_fut = {
+        let in_flight = &in_flight;
+        let peak_in_flight = &peak_in_flight;
+        async move {
+            new_child_entries
+                .map_err(|err| err.context("While uploading child entries"))
+                .try_for_each_concurrent(
+                    max_concurrent_uploads.unwrap_or(DEFAULT_CONCURRENT_UPLOADS),
+                    move |(entry, path)| async move {
+                        let current = in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+                        peak_in_flight.fetch_max(current, Ordering::Relaxed);
+                        let result = entry_processor.process_one_entry(ctx, entry, path).await;
+                        in_flight.fetch_sub(1, Ordering::Relaxed);
+                        result
+                    },
+                )
+                .await
+        }
     };
 
     let (root_hash, ()) = future::try_join(root_manifest_fut, child_entries_fut)
@@ -451,6 +473,8 @@ pub async fn process_entries<'a>(
         .await?
         .log_future_stats(entry_processor.scuba_logger.clone(), "Upload entries", None);
 
+    STATS::process_entries_peak_in_flight.add_value(peak_in_flight.load(Ordering::Relaxed) as i64);
+
     match root_hash {
         None => Ok(HgManifestId::new(NULL_HASH)),
         Some(root_hash) => Ok(root_hash),
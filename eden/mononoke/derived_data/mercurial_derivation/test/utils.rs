@@ -182,6 +182,10 @@ pub fn create_changeset_no_parents(
         cs_metadata,
         verify_origin_repo: None,
         upload_to_blobstore_only: false,
+        batch_graph_insert: None,
+        max_concurrent_uploads: None,
+        validator: None,
+        changed_files_base: None,
     };
     create_changeset.create(
         CoreContext::test_mock(fb),
@@ -220,6 +224,10 @@ pub fn create_changeset_one_parent(
         cs_metadata,
         verify_origin_repo: None,
         upload_to_blobstore_only: false,
+        batch_graph_insert: None,
+        max_concurrent_uploads: None,
+        validator: None,
+        changed_files_base: None,
     };
     create_changeset.create(
         CoreContext::test_mock(fb),
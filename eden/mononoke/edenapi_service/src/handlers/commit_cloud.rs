@@ -127,10 +127,16 @@ async fn get_workspaces<R: MononokeRepo>(
         .cloud_workspaces(&request.prefix, &request.reponame)
         .await;
     let res = match cc_res {
-        Ok(res) => Ok(res
-            .into_iter()
-            .map(WorkspaceData::from_cc_type)
-            .collect::<anyhow::Result<Vec<_>>>()?),
+        Ok(res) => {
+            let mut workspaces = res
+                .into_iter()
+                .map(WorkspaceData::from_cc_type)
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            if !request.include_archived {
+                workspaces.retain(|workspace| !workspace.archived);
+            }
+            Ok(workspaces)
+        }
         Err(e) => Err(e),
     };
 
@@ -174,7 +180,11 @@ async fn get_references<R: MononokeRepo>(
         .cloud_references(&request.workspace, &request.reponame, request.version, ci)
         .await;
     let res = match cc_res {
-        Ok(res) => Ok(ReferencesData::from_cc_type(res)?),
+        Ok(res) => {
+            let mut data = ReferencesData::from_cc_type(res)?;
+            data.apply_filters(request.filters.as_deref().unwrap_or_default());
+            Ok(data)
+        }
         Err(e) => {
             match e {
                 MononokeError::InternalError(ref e) => repo.ctx().scuba().clone().log_with_msg(
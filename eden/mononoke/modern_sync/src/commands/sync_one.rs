@@ -23,7 +23,9 @@ use tokio::sync::mpsc;
 use url::Url;
 
 use crate::sender::edenapi::EdenapiSender;
+use crate::sender::edenapi::OperationTimeouts;
 use crate::sender::manager::SendManager;
+use crate::sender::manager::SendManagerConfig;
 use crate::ModernSyncArgs;
 use crate::Repo;
 
@@ -89,12 +91,20 @@ pub async fn run(app: MononokeApp, args: CommandArgs) -> Result<()> {
                 tls_args,
                 ctx.clone(),
                 repo.repo_blobstore().clone(),
+                None,
+                OperationTimeouts::default(),
+                None,
             )
             .await?,
         )
     };
 
-    let send_manager = SendManager::new(sender.clone(), logger.clone(), repo_name.clone());
+    let (send_manager, _error_rx) = SendManager::new(
+        SendManagerConfig::default(),
+        sender.clone(),
+        logger.clone(),
+        repo_name.clone(),
+    )?;
     let (cr_s, mut cr_r) = mpsc::channel::<Result<()>>(1);
 
     crate::sync::process_one_changeset(
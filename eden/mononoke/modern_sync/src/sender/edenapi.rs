@@ -7,6 +7,11 @@
 
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
 use std::time::Duration;
 
 use anyhow::ensure;
@@ -19,6 +24,7 @@ use edenapi::Client;
 use edenapi::HttpClientBuilder;
 use edenapi::HttpClientConfig;
 use edenapi::SaplingRemoteApi;
+use edenapi::SaplingRemoteApiError;
 use edenapi_types::AnyFileContentId;
 use edenapi_types::AnyId;
 use edenapi_types::LookupResponse;
@@ -48,12 +54,160 @@ use url::Url;
 mod util;
 
 const MAX_RETRIES: usize = 3;
+const HEALTH_RETRIES: usize = 3;
+const DEFAULT_UPLOAD_CONCURRENCY: usize = 10;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(300);
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Bounds for a single process_files_upload request.
+const MAX_CONTENT_REQUEST_ITEMS: usize = 100;
+const MAX_CONTENT_REQUEST_BYTES: usize = 50 * 1024 * 1024; // 50 MB
+
+// Contents at least this large are streamed to the uploader chunk by chunk
+// instead of being concatenated in memory first.
+const STREAMING_CONTENT_THRESHOLD: u64 = 10 * 1024 * 1024; // 10 MB
+
+/// Per-operation HTTP timeouts. Any unset operation falls back to the
+/// historical 300s timeout; the initial health check always uses a short one.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OperationTimeouts {
+    pub content_upload: Option<Duration>,
+    pub trees_upload: Option<Duration>,
+    pub changeset_upload: Option<Duration>,
+    pub lookup: Option<Duration>,
+}
+
+/// Controls how `with_retry` backs off between attempts.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub max_retries: usize,
+    pub base_delay: Duration,
+    pub multiplier: u32,
+    pub max_delay: Duration,
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: MAX_RETRIES,
+            base_delay: Duration::from_secs(1),
+            multiplier: 2,
+            max_delay: Duration::from_secs(30),
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential delay before retrying after the given zero-based attempt,
+    /// capped at `max_delay` and optionally scaled down by jitter.
+    fn next_delay(&self, attempt: usize) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(self.multiplier.saturating_pow(attempt as u32))
+            .min(self.max_delay);
+        if self.jitter && !delay.is_zero() {
+            // Scale by a pseudo-random factor in [0.5, 1.0) derived from the
+            // clock, to avoid synchronized retry storms without pulling in a
+            // rand dependency.
+            let nanos = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.subsec_nanos());
+            delay.mul_f64(0.5 + (nanos as f64 / u32::MAX as f64) / 2.0)
+        } else {
+            delay
+        }
+    }
+}
+
+/// Cumulative upload progress of an `EdenapiSender`: items and bytes uploaded
+/// so far. Bytes are only tracked for content uploads; trees, filenodes and
+/// changesets count as items only.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct UploadProgress {
+    pub items: u64,
+    pub bytes: u64,
+}
+
+/// A single bookmark move as applied by `set_bookmarks`. `from`/`to` have the
+/// same semantics as the `set_bookmark` arguments: `None` for `from` creates
+/// the bookmark, `None` for `to` deletes it.
+#[derive(Clone, Debug)]
+pub struct BookmarkMove {
+    pub bookmark: String,
+    pub from: Option<HgChangesetId>,
+    pub to: Option<HgChangesetId>,
+}
+
+/// Outcome of one move from a `set_bookmarks` call.
+#[derive(Debug)]
+pub struct BookmarkMoveResult {
+    pub bookmark: String,
+    pub result: Result<()>,
+}
+
+/// Retry timeouts and server errors; client errors (4xx) won't get better.
+fn is_transient_error(e: &anyhow::Error) -> bool {
+    match e.downcast_ref::<SaplingRemoteApiError>() {
+        Some(e) => e.is_retryable(),
+        // Not a server error (e.g. a local blobstore read failed): keep the
+        // old behavior and retry.
+        None => true,
+    }
+}
 
 pub struct EdenapiSender {
-    client: Client,
+    content_client: Client,
+    trees_client: Client,
+    changeset_client: Client,
+    lookup_client: Client,
     logger: Logger,
     ctx: CoreContext,
     repo_blobstore: RepoBlobstore,
+    concurrency: usize,
+    retry_policy: RetryPolicy,
+    max_content_request_items: usize,
+    max_content_request_bytes: usize,
+    progress_callback: Option<Arc<dyn Fn(UploadProgress) + Send + Sync>>,
+    progress_items: AtomicU64,
+    progress_bytes: AtomicU64,
+    lookup_cache: Option<Mutex<LookupCache>>,
+}
+
+/// Bounded FIFO set of changesets the server confirmed present. Only presence
+/// is ever cached: a commit can show up on the server at any moment, so a
+/// negative lookup can go stale, but a positive one cannot.
+struct LookupCache {
+    capacity: usize,
+    present: HashSet<HgChangesetId>,
+    order: VecDeque<HgChangesetId>,
+}
+
+impl LookupCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            present: HashSet::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    fn contains(&self, id: &HgChangesetId) -> bool {
+        self.present.contains(id)
+    }
+
+    fn insert(&mut self, id: HgChangesetId) {
+        if self.capacity == 0 || !self.present.insert(id) {
+            return;
+        }
+        self.order.push_back(id);
+        while self.present.len() > self.capacity {
+            if let Some(evicted) = self.order.pop_front() {
+                self.present.remove(&evicted);
+            }
+        }
+    }
 }
 
 impl EdenapiSender {
@@ -64,6 +218,9 @@ impl EdenapiSender {
         tls_args: TLSArgs,
         ctx: CoreContext,
         repo_blobstore: RepoBlobstore,
+        concurrency: Option<usize>,
+        timeouts: OperationTimeouts,
+        health_retries: Option<usize>,
     ) -> Result<Self> {
         let ci = ClientInfo::new_with_entry_point(ClientEntryPoint::ModernSync)?.to_json()?;
         let http_config = HttpClientConfig {
@@ -76,23 +233,78 @@ impl EdenapiSender {
 
         info!(logger, "Connecting to {}", url.to_string());
 
-        let client = HttpClientBuilder::new()
-            .repo_name(&reponame)
-            .server_url(url)
-            .http_config(http_config)
-            .timeout(Duration::from_secs(300))
-            .build()?;
+        let build_client = |timeout: Duration| {
+            HttpClientBuilder::new()
+                .repo_name(&reponame)
+                .server_url(url.clone())
+                .http_config(http_config.clone())
+                .timeout(timeout)
+                .build()
+        };
 
-        client.health().await?;
+        // A transient blip at startup shouldn't abort the whole sync process, so
+        // the initial health check gets the same retry treatment as uploads.
+        let health_policy = RetryPolicy {
+            max_retries: health_retries.unwrap_or(HEALTH_RETRIES),
+            ..RetryPolicy::default()
+        };
+        let health_client = build_client(HEALTH_TIMEOUT)?;
+        with_retry(&health_policy, &logger, || {
+            let client = health_client.clone();
+            async move {
+                client.health().await?;
+                Ok(())
+            }
+            .boxed()
+        })
+        .await?;
 
         Ok(Self {
-            client,
+            content_client: build_client(timeouts.content_upload.unwrap_or(DEFAULT_TIMEOUT))?,
+            trees_client: build_client(timeouts.trees_upload.unwrap_or(DEFAULT_TIMEOUT))?,
+            changeset_client: build_client(timeouts.changeset_upload.unwrap_or(DEFAULT_TIMEOUT))?,
+            lookup_client: build_client(timeouts.lookup.unwrap_or(DEFAULT_TIMEOUT))?,
             logger,
             ctx,
             repo_blobstore,
+            concurrency: concurrency.unwrap_or(DEFAULT_UPLOAD_CONCURRENCY),
+            retry_policy: RetryPolicy::default(),
+            max_content_request_items: MAX_CONTENT_REQUEST_ITEMS,
+            max_content_request_bytes: MAX_CONTENT_REQUEST_BYTES,
+            progress_callback: None,
+            progress_items: AtomicU64::new(0),
+            progress_bytes: AtomicU64::new(0),
+            lookup_cache: None,
         })
     }
 
+    /// Remember up to `capacity` changesets confirmed present by lookups, so
+    /// sync loops re-checking the same ancestors don't hit the server again.
+    pub fn with_lookup_cache(mut self, capacity: usize) -> Self {
+        self.lookup_cache = Some(Mutex::new(LookupCache::new(capacity)));
+        self
+    }
+
+    /// Invoke `callback` with the cumulative progress after each uploaded
+    /// batch, so the driver can render a progress bar or emit periodic logs.
+    pub fn with_progress_callback(
+        mut self,
+        callback: Arc<dyn Fn(UploadProgress) + Send + Sync>,
+    ) -> Self {
+        self.progress_callback = Some(callback);
+        self
+    }
+
+    fn record_progress(&self, items: usize, bytes: usize) {
+        if let Some(callback) = &self.progress_callback {
+            let items =
+                self.progress_items.fetch_add(items as u64, Ordering::Relaxed) + items as u64;
+            let bytes =
+                self.progress_bytes.fetch_add(bytes as u64, Ordering::Relaxed) + bytes as u64;
+            callback(UploadProgress { items, bytes });
+        }
+    }
+
     pub async fn upload_contents(
         &self,
         contents: Vec<(AnyFileContentId, FileContents)>,
@@ -109,30 +321,73 @@ impl EdenapiSender {
         let ctx = self.ctx.clone();
 
         let mut full_items = Vec::new();
+        let mut streamed_items = Vec::new();
 
         for (id, blob) in contents {
+            // Large blobs are passed through as streams so they are never
+            // fully materialized on this side; small ones are batched up.
+            if blob.size() >= STREAMING_CONTENT_THRESHOLD {
+                streamed_items.push((id, blob));
+                continue;
+            }
             cloned!(ctx, repo_blobstore);
             let stream = stream_file_bytes(&repo_blobstore, &ctx, blob, Range::all())?;
             let bytes = util::concatenate_bytes(stream.try_collect::<Vec<_>>().await?);
             full_items.push((id, bytes.into()));
         }
 
-        let expected_responses = full_items.len();
-        let response = self
-            .client
-            .process_files_upload(full_items, None, None)
-            .await?;
+        // Don't put everything in one request: a changeset with many large
+        // files would produce an unreasonably big payload.
+        let expected_ids = full_items
+            .iter()
+            .map(|(id, _)| AnyId::AnyFileContentId(*id))
+            .chain(
+                streamed_items
+                    .iter()
+                    .map(|(id, _)| AnyId::AnyFileContentId(*id)),
+            )
+            .collect::<Vec<_>>();
+        let mut responded_ids = Vec::with_capacity(expected_ids.len());
+        for chunk in chunk_contents_by_size(
+            full_items,
+            self.max_content_request_items,
+            self.max_content_request_bytes,
+        ) {
+            let chunk_bytes: usize = chunk.iter().map(|(_, bytes)| bytes.len()).sum();
+            let response = self
+                .content_client
+                .process_files_upload(chunk, None, None)
+                .await?;
+            let entries = response.entries.try_collect::<Vec<_>>().await?;
+            self.record_progress(entries.len(), chunk_bytes);
+            responded_ids.extend(entries.into_iter().map(|token| token.data.id));
+        }
 
-        let actual_responses = response.entries.try_collect::<Vec<_>>().await?.len();
+        for (id, blob) in streamed_items {
+            cloned!(ctx, repo_blobstore);
+            let size = blob.size();
+            // Pass the blobstore and context by value so the stream is
+            // 'static, as required by the streaming upload.
+            let stream = stream_file_bytes(repo_blobstore, ctx, blob, Range::all())?
+                .map_ok(|bytes| bytes.to_vec())
+                .map_err(SaplingRemoteApiError::Other);
+            let token = self
+                .content_client
+                .process_single_file_upload_stream(id, size, stream, None)
+                .await?;
+            self.record_progress(1, size as usize);
+            responded_ids.push(token.data.id);
+        }
 
         ensure!(
-            expected_responses == actual_responses,
-            "Content upload: Expected {} responses, got {}",
-            expected_responses,
-            actual_responses
+            expected_ids.len() == responded_ids.len(),
+            "Content upload: Expected {} responses, got {} ({})",
+            expected_ids.len(),
+            responded_ids.len(),
+            describe_missing_ids(&expected_ids, responded_ids.iter())
         );
 
-        info!(self.logger, "Uploaded {} contents", actual_responses);
+        info!(self.logger, "Uploaded {} contents", responded_ids.len());
 
         Ok(())
     }
@@ -143,25 +398,28 @@ impl EdenapiSender {
     }
 
     async fn upload_trees_attempt(&self, trees: Vec<HgManifestId>) -> Result<()> {
-        let entries = stream::iter(trees)
-            .map(|mf_id| {
-                let ctx = self.ctx.clone();
-                let repo_blobstore = self.repo_blobstore.clone();
-                async move { util::from_tree_to_entry(mf_id, &ctx, &repo_blobstore).await }
-            })
-            .buffer_unordered(10)
-            .try_collect::<Vec<_>>()
-            .await?;
+        let trees = dedup_preserving_order(trees);
+        let entries = collect_concurrently(trees, self.concurrency, |mf_id| {
+            let ctx = self.ctx.clone();
+            let repo_blobstore = self.repo_blobstore.clone();
+            async move { util::from_tree_to_entry(mf_id, &ctx, &repo_blobstore).await }
+        })
+        .await?;
 
-        let expected_responses = entries.len();
-        let res = self.client.upload_trees_batch(entries).await?;
-        let actual_responses = res.entries.try_collect::<Vec<_>>().await?.len();
+        let expected_ids = entries
+            .iter()
+            .map(|entry| AnyId::HgTreeId(entry.node_id))
+            .collect::<Vec<_>>();
+        let res = self.trees_client.upload_trees_batch(entries).await?;
+        let responses = res.entries.try_collect::<Vec<_>>().await?;
         ensure!(
-            expected_responses == actual_responses,
-            "Trees upload: Expected {} responses, got {}",
-            expected_responses,
-            actual_responses,
+            expected_ids.len() == responses.len(),
+            "Trees upload: Expected {} responses, got {} ({})",
+            expected_ids.len(),
+            responses.len(),
+            describe_missing_ids(&expected_ids, responses.iter().map(|r| &r.token.data.id)),
         );
+        self.record_progress(responses.len(), 0);
         Ok(())
     }
     pub async fn upload_filenodes(&self, fn_ids: Vec<HgFileNodeId>) -> Result<()> {
@@ -170,50 +428,88 @@ impl EdenapiSender {
     }
 
     async fn upload_filenodes_attempt(&self, fn_ids: Vec<HgFileNodeId>) -> Result<()> {
-        let filenodes = stream::iter(fn_ids)
-            .map(|file_id| {
-                let ctx = self.ctx.clone();
-                let repo_blobstore = self.repo_blobstore.clone();
-                async move { util::from_id_to_filenode(file_id, &ctx, &repo_blobstore).await }
-            })
-            .buffer_unordered(10)
-            .try_collect::<Vec<_>>()
-            .await?;
+        let fn_ids = dedup_preserving_order(fn_ids);
+        let filenodes = collect_concurrently(fn_ids, self.concurrency, |file_id| {
+            let ctx = self.ctx.clone();
+            let repo_blobstore = self.repo_blobstore.clone();
+            async move { util::from_id_to_filenode(file_id, &ctx, &repo_blobstore).await }
+        })
+        .await?;
 
-        let expected_responses = filenodes.len();
-        let res = self.client.upload_filenodes_batch(filenodes).await?;
-        let actual_responses = res.entries.try_collect::<Vec<_>>().await?.len();
+        let expected_ids = filenodes
+            .iter()
+            .map(|filenode| AnyId::HgFilenodeId(filenode.node_id))
+            .collect::<Vec<_>>();
+        let res = self.trees_client.upload_filenodes_batch(filenodes).await?;
+        let responses = res.entries.try_collect::<Vec<_>>().await?;
         ensure!(
-            expected_responses == actual_responses,
-            "Filenodes upload: Expected {} responses, got {}",
-            expected_responses,
-            actual_responses
+            expected_ids.len() == responses.len(),
+            "Filenodes upload: Expected {} responses, got {} ({})",
+            expected_ids.len(),
+            responses.len(),
+            describe_missing_ids(&expected_ids, responses.iter().map(|r| &r.token.data.id))
         );
+        self.record_progress(responses.len(), 0);
         Ok(())
     }
 
+    /// Pushvars sent with a mirror bookmark move: bypass read-only checks and
+    /// mark the push as a mirror upload.
+    pub fn mirror_pushvars() -> HashMap<String, String> {
+        HashMap::from([
+            ("BYPASS_READONLY".to_owned(), "true".to_owned()),
+            ("MIRROR_UPLOAD".to_owned(), "true".to_owned()),
+        ])
+    }
+
     pub async fn set_bookmark(
         &self,
         bookmark: String,
         from: Option<HgChangesetId>,
         to: Option<HgChangesetId>,
+        pushvars: HashMap<String, String>,
     ) -> Result<()> {
         let res = self
-            .client
+            .lookup_client
             .set_bookmark(
                 bookmark,
                 to.map(|cs| cs.into()),
                 from.map(|cs| cs.into()),
-                HashMap::from([
-                    ("BYPASS_READONLY".to_owned(), "true".to_owned()),
-                    ("MIRROR_UPLOAD".to_owned(), "true".to_owned()),
-                ]),
+                pushvars,
             )
             .await?;
         info!(&self.logger, "Moved bookmark with result {:?}", res);
         Ok(())
     }
 
+    /// Apply many bookmark moves with bounded parallelism, sharing the same
+    /// pushvars. Unlike `set_bookmark`, a failed move doesn't abort the rest:
+    /// every move reports its own outcome so the caller can retry just the
+    /// failures. Results arrive in completion order.
+    pub async fn set_bookmarks(
+        &self,
+        moves: Vec<BookmarkMove>,
+        pushvars: HashMap<String, String>,
+    ) -> Result<Vec<BookmarkMoveResult>> {
+        let results = stream::iter(moves)
+            .map(|mv| {
+                let pushvars = pushvars.clone();
+                async move {
+                    let result = self
+                        .set_bookmark(mv.bookmark.clone(), mv.from, mv.to, pushvars)
+                        .await;
+                    BookmarkMoveResult {
+                        bookmark: mv.bookmark,
+                        result,
+                    }
+                }
+            })
+            .buffer_unordered(self.concurrency)
+            .collect::<Vec<_>>()
+            .await;
+        Ok(results)
+    }
+
     pub async fn upload_identical_changeset(
         &self,
         css: Vec<(HgBlobChangeset, BonsaiChangeset)>,
@@ -231,13 +527,18 @@ impl EdenapiSender {
             .try_collect::<Vec<_>>()
             .await?;
 
-        let expected_responses = entries.len();
-        let res = self.client.upload_identical_changesets(entries).await?;
+        let expected_ids = entries
+            .iter()
+            .map(|entry| AnyId::HgChangesetId(entry.hg_info.node_id))
+            .collect::<Vec<_>>();
+        let res = self.changeset_client.upload_identical_changesets(entries).await?;
         let responses = res.entries.try_collect::<Vec<_>>().await?;
         ensure!(
-            expected_responses == responses.len(),
-            "Not all changesets were uploaded"
+            expected_ids.len() == responses.len(),
+            "Not all changesets were uploaded ({})",
+            describe_missing_ids(&expected_ids, responses.iter().map(|r| &r.token.data.id))
         );
+        self.record_progress(responses.len(), 0);
         let ids = responses
             .iter()
             .map(|r| r.token.data.id)
@@ -247,17 +548,66 @@ impl EdenapiSender {
         Ok(())
     }
 
+    /// Look the given changesets up on the server and return the subset it
+    /// still reports as missing. Useful to double-check an upload landed.
+    pub async fn verify_uploaded(&self, ids: Vec<HgChangesetId>) -> Result<Vec<HgChangesetId>> {
+        let lookup_ids = ids
+            .iter()
+            .map(|id| AnyId::HgChangesetId((*id).into()))
+            .collect::<Vec<_>>();
+        let res = self.lookup_client.lookup_batch(lookup_ids, None, None).await?;
+        let present_ids: HashSet<_> = res
+            .into_iter()
+            .filter_map(|r| match r.result {
+                LookupResult::Present(UploadToken {
+                    data:
+                        UploadTokenData {
+                            id,
+                            bubble_id: _,
+                            metadata: _,
+                        },
+                    signature: _,
+                }) => Some(id),
+                _ => None,
+            })
+            .collect();
+        Ok(ids
+            .into_iter()
+            .filter(|id| !present_ids.contains(&AnyId::HgChangesetId((*id).into())))
+            .collect())
+    }
+
     pub async fn filter_existing_commits(
         &self,
         ids: Vec<(HgChangesetId, ChangesetId)>,
     ) -> Result<Vec<ChangesetId>> {
+        let ids: Vec<_> = match &self.lookup_cache {
+            Some(cache) => {
+                let cache = cache.lock().expect("poisoned lock");
+                ids.into_iter()
+                    .filter(|(hgid, _)| !cache.contains(hgid))
+                    .collect()
+            }
+            None => ids,
+        };
+        if ids.is_empty() {
+            return Ok(vec![]);
+        }
         let hgids = ids
-            .clone()
             .iter()
-            .map(|(hgid, _)| AnyId::HgChangesetId(hgid.clone().into()))
+            .map(|(hgid, _)| AnyId::HgChangesetId((*hgid).into()))
             .collect::<Vec<_>>();
-        let res = self.client.lookup_batch(hgids, None, None).await?;
-        let missing = get_missing_in_order(res, ids);
+        let res = self.lookup_client.lookup_batch(hgids, None, None).await?;
+        let missing = get_missing_in_order(res, ids.clone());
+        if let Some(cache) = &self.lookup_cache {
+            let missing_set: HashSet<_> = missing.iter().collect();
+            let mut cache = cache.lock().expect("poisoned lock");
+            for (hgid, csid) in &ids {
+                if !missing_set.contains(csid) {
+                    cache.insert(*hgid);
+                }
+            }
+        }
         Ok(missing)
     }
 
@@ -265,30 +615,108 @@ impl EdenapiSender {
         &'t self,
         func: impl Fn(&'t Self) -> BoxFuture<'t, Result<T>>,
     ) -> Result<T> {
-        let retry_count = MAX_RETRIES;
-        with_retry(retry_count, &self.logger, || func(self)).await
+        with_retry(&self.retry_policy, &self.logger, || func(self)).await
+    }
+}
+
+/// How many missing ids a count-mismatch error spells out before truncating.
+const MAX_REPORTED_MISSING_IDS: usize = 5;
+
+/// Describe which inputs got no corresponding response entry, listing the
+/// first few missing ids so partial upload failures are debuggable.
+fn describe_missing_ids<'a>(
+    expected: &[AnyId],
+    responded: impl IntoIterator<Item = &'a AnyId>,
+) -> String {
+    let responded: HashSet<&AnyId> = responded.into_iter().collect();
+    let missing = expected
+        .iter()
+        .filter(|id| !responded.contains(id))
+        .collect::<Vec<_>>();
+    format!(
+        "{} ids missing a response, first {}: {:?}",
+        missing.len(),
+        missing.len().min(MAX_REPORTED_MISSING_IDS),
+        &missing[..missing.len().min(MAX_REPORTED_MISSING_IDS)]
+    )
+}
+
+/// Split items into chunks bounded by both a maximum count and a maximum
+/// total byte size. An item bigger than `max_bytes` still gets its own chunk.
+fn chunk_contents_by_size<I, B: AsRef<[u8]>>(
+    items: Vec<(I, B)>,
+    max_items: usize,
+    max_bytes: usize,
+) -> Vec<Vec<(I, B)>> {
+    let mut chunks = Vec::new();
+    let mut current = Vec::new();
+    let mut current_bytes = 0;
+    for (id, bytes) in items {
+        let size = bytes.as_ref().len();
+        if !current.is_empty() && (current.len() >= max_items || current_bytes + size > max_bytes) {
+            chunks.push(std::mem::take(&mut current));
+            current_bytes = 0;
+        }
+        current_bytes += size;
+        current.push((id, bytes));
     }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+/// Drop duplicate items, keeping the first occurrence of each. Uploading the
+/// same object twice is harmless but wastes a request.
+fn dedup_preserving_order<T: Clone + Eq + std::hash::Hash>(items: Vec<T>) -> Vec<T> {
+    let mut seen = HashSet::with_capacity(items.len());
+    items
+        .into_iter()
+        .filter(|item| seen.insert(item.clone()))
+        .collect()
+}
+
+/// Apply `f` to each item with bounded concurrency, collecting the results.
+/// Results arrive in completion order, which callers must not rely on.
+async fn collect_concurrently<I, T, F, Fut>(
+    items: Vec<I>,
+    concurrency: usize,
+    f: F,
+) -> Result<Vec<T>>
+where
+    F: Fn(I) -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    stream::iter(items)
+        .map(f)
+        .buffer_unordered(concurrency)
+        .try_collect()
+        .await
 }
 
 async fn with_retry<'t, T>(
-    max_retry_count: usize,
+    policy: &RetryPolicy,
     logger: &Logger,
     func: impl Fn() -> BoxFuture<'t, Result<T>>,
 ) -> Result<T> {
     let mut attempt = 0usize;
     loop {
         let result = func().await;
-        if attempt >= max_retry_count {
+        if attempt >= policy.max_retries {
             return result;
         }
         match result {
             Ok(result) => return Ok(result),
             Err(e) => {
+                if !is_transient_error(&e) {
+                    return Err(e);
+                }
+                let delay = policy.next_delay(attempt);
                 warn!(
                     logger,
-                    "Found error: {:?}, retrying attempt #{}", e, attempt
+                    "Found error: {:?}, retrying attempt #{} in {:?}", e, attempt, delay
                 );
-                tokio::time::sleep(Duration::from_secs(attempt as u64 + 1)).await;
+                tokio::time::sleep(delay).await;
             }
         }
         attempt += 1;
@@ -361,4 +789,116 @@ mod test {
         let missing = get_missing_in_order(responses, vec![(hg_id1, cs_id1), (hg_id2, cs_id2)]);
         assert_eq!(missing, vec![cs_id1, cs_id2]);
     }
+
+    #[mononoke::test]
+    fn test_describe_missing_ids() {
+        let ids = (0..8u8)
+            .map(|i| {
+                AnyId::HgChangesetId(HgChangesetId::from_bytes(&[i; 20]).unwrap().into())
+            })
+            .collect::<Vec<_>>();
+        let responded = vec![ids[0].clone(), ids[3].clone()];
+        let msg = describe_missing_ids(&ids, responded.iter());
+        assert!(msg.starts_with("6 ids missing a response, first 5:"), "{}", msg);
+        let msg = describe_missing_ids(&ids, ids.iter());
+        assert!(msg.starts_with("0 ids missing a response"), "{}", msg);
+    }
+
+    #[mononoke::test]
+    fn test_chunk_contents_by_size() {
+        let items: Vec<(u32, Vec<u8>)> = vec![
+            (0, vec![0; 4]),
+            (1, vec![0; 4]),
+            (2, vec![0; 10]),
+            (3, vec![0; 1]),
+            (4, vec![0; 1]),
+            (5, vec![0; 1]),
+        ];
+        let chunks = chunk_contents_by_size(items, 2, 8);
+        let ids = chunks
+            .iter()
+            .map(|chunk| chunk.iter().map(|(id, _)| *id).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        // 0+1 fill the byte budget, 2 is oversized but still goes alone, and
+        // 3+4 hit the item limit.
+        assert_eq!(ids, vec![vec![0, 1], vec![2], vec![3, 4], vec![5]]);
+        assert_eq!(chunk_contents_by_size::<u32, Vec<u8>>(vec![], 2, 8).len(), 0);
+    }
+
+    #[mononoke::test]
+    fn test_retry_policy_delay_schedule() {
+        let policy = RetryPolicy {
+            max_retries: 5,
+            base_delay: Duration::from_secs(1),
+            multiplier: 3,
+            max_delay: Duration::from_secs(10),
+            jitter: false,
+        };
+        let delays = (0..5)
+            .map(|attempt| policy.next_delay(attempt))
+            .collect::<Vec<_>>();
+        assert_eq!(delays, [1, 3, 9, 10, 10].map(Duration::from_secs).to_vec());
+    }
+
+    #[mononoke::test]
+    fn test_retry_policy_jitter_bounds() {
+        let policy = RetryPolicy {
+            jitter: true,
+            ..RetryPolicy::default()
+        };
+        // Attempt 2 with the default policy: 1s * 2^2 = 4s before jitter.
+        let delay = policy.next_delay(2);
+        let full = Duration::from_secs(4);
+        assert!(delay >= full / 2);
+        assert!(delay <= full);
+    }
+
+    #[mononoke::test]
+    fn test_lookup_cache_bounded() {
+        let ids = (0..4u8)
+            .map(|i| HgChangesetId::from_bytes(&[i; 20]).unwrap())
+            .collect::<Vec<_>>();
+        let mut cache = LookupCache::new(2);
+        cache.insert(ids[0]);
+        cache.insert(ids[1]);
+        // Re-inserting doesn't grow the cache or disturb the FIFO order.
+        cache.insert(ids[0]);
+        assert!(cache.contains(&ids[0]));
+        assert!(cache.contains(&ids[1]));
+        cache.insert(ids[2]);
+        assert!(!cache.contains(&ids[0]));
+        assert!(cache.contains(&ids[1]));
+        assert!(cache.contains(&ids[2]));
+        // Zero capacity never caches anything.
+        let mut empty = LookupCache::new(0);
+        empty.insert(ids[3]);
+        assert!(!empty.contains(&ids[3]));
+    }
+
+    #[mononoke::test]
+    fn test_dedup_preserving_order() {
+        let ids = (0..3u8)
+            .map(|i| HgFileNodeId::from_bytes(&[i; 20]).unwrap())
+            .collect::<Vec<_>>();
+        let input = vec![ids[1], ids[0], ids[1], ids[2], ids[0]];
+        assert_eq!(
+            dedup_preserving_order(input),
+            vec![ids[1], ids[0], ids[2]]
+        );
+        assert_eq!(dedup_preserving_order(Vec::<u32>::new()), vec![]);
+    }
+
+    #[mononoke::test]
+    fn test_collect_concurrently_single() {
+        let items: Vec<u64> = (0..50).collect();
+        let mut actual = futures::executor::block_on(collect_concurrently(
+            items.clone(),
+            1,
+            |i| async move { Ok(i * 2) },
+        ))
+        .unwrap();
+        actual.sort_unstable();
+        let expected = items.into_iter().map(|i| i * 2).collect::<Vec<_>>();
+        assert_eq!(actual, expected);
+    }
 }
@@ -7,11 +7,15 @@
 
 use std::collections::VecDeque;
 use std::sync::Arc;
+use std::sync::Mutex;
 use std::time::Duration;
 
+use anyhow::bail;
 use anyhow::Result;
 use edenapi_types::AnyFileContentId;
 use futures::channel::oneshot;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
 use mercurial_types::blobs::HgBlobChangeset;
 use mercurial_types::HgFileNodeId;
 use mercurial_types::HgManifestId;
@@ -24,6 +28,7 @@ use stats::define_stats;
 use stats::prelude::*;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
 use tokio::time::interval;
 
 use crate::sender::edenapi::EdenapiSender;
@@ -35,10 +40,16 @@ define_stats! {
     synced_trees:  dynamic_timeseries("{}.synced_trees", (repo: String); Sum),
     synced_filenodes:  dynamic_timeseries("{}.synced_filenodes", (repo: String); Sum),
     sync_lag_seconds:  dynamic_timeseries("{}.sync_lag_seconds", (repo: String); Average),
+    sync_lag_seconds_histogram: dynamic_histogram("{}.sync_lag_seconds_histogram", (repo: String); 10, 0, 3600, Average, Count; P 50; P 90; P 99),
     content_wait_time_s:  dynamic_timeseries("{}.content_wait_time_s", (repo: String); Average),
     trees_files_wait_time_s:  dynamic_timeseries("{}.trees_files_wait_time_s", (repo: String); Average),
     changeset_upload_time_s:  dynamic_timeseries("{}.changeset_upload_time_s", (repo: String); Average),
     content_upload_time_s:  dynamic_timeseries("{}.content_upload_time_ms", (repo: String); Average),
+    content_queue_depth: dynamic_timeseries("{}.content_queue_depth", (repo: String); Average),
+    files_queue_depth: dynamic_timeseries("{}.files_queue_depth", (repo: String); Average),
+    trees_queue_depth: dynamic_timeseries("{}.trees_queue_depth", (repo: String); Average),
+    changesets_queue_depth: dynamic_timeseries("{}.changesets_queue_depth", (repo: String); Average),
+    send_blocked: dynamic_timeseries("{}.send_blocked", (repo: String); Rate, Sum),
 
 }
 
@@ -47,6 +58,83 @@ const FILES_CHANNEL_SIZE: usize = 10000;
 const TREES_CHANNEL_SIZE: usize = 10000;
 const CHANGESET_CHANNEL_SIZE: usize = 5000;
 
+/// Sizes of the channels between the sync driver and the spawned sender
+/// tasks. Defaults match the previously hardcoded values; operators can tune
+/// them to trade memory for throughput.
+#[derive(Clone, Copy, Debug)]
+pub struct SendManagerConfig {
+    pub content_channel_size: usize,
+    pub files_channel_size: usize,
+    pub trees_channel_size: usize,
+    pub changeset_channel_size: usize,
+    /// How many content upload batches may be in flight at once.
+    pub content_upload_concurrency: usize,
+    /// Look up each changeset batch on the server after uploading it, and
+    /// fail the sync if any are missing.
+    pub verify_changeset_uploads: bool,
+    /// Check that the driver sends the Wait message for each changeset before
+    /// its data messages. Violations panic in debug builds and log loudly in
+    /// release builds.
+    pub assert_message_order: bool,
+}
+
+impl Default for SendManagerConfig {
+    fn default() -> Self {
+        Self {
+            content_channel_size: CONTENT_CHANNEL_SIZE,
+            files_channel_size: FILES_CHANNEL_SIZE,
+            trees_channel_size: TREES_CHANNEL_SIZE,
+            changeset_channel_size: CHANGESET_CHANNEL_SIZE,
+            content_upload_concurrency: CONTENT_UPLOAD_CONCURRENCY,
+            verify_changeset_uploads: false,
+            assert_message_order: false,
+        }
+    }
+}
+
+/// Tracks the Wait/data/Done protocol on one channel when
+/// `SendManagerConfig::assert_message_order` is set. The driver must send
+/// `WaitForContents`/`WaitForFilesAndTrees` for a changeset before that
+/// changeset's data messages; a misuse would otherwise silently upload
+/// changesets before their files.
+struct OrderingTracker {
+    enabled: bool,
+    waited: bool,
+}
+
+impl OrderingTracker {
+    fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            waited: false,
+        }
+    }
+
+    fn wait_received(&mut self) {
+        self.waited = true;
+    }
+
+    fn data_received(&self, logger: &Logger, what: &str) {
+        if self.enabled && !self.waited {
+            let msg = format!(
+                "SendManager protocol violation: {} received before its Wait message",
+                what
+            );
+            if cfg!(debug_assertions) {
+                panic!("{}", msg);
+            } else {
+                error!(logger, "{}", msg);
+            }
+        }
+    }
+
+    fn done_received(&mut self) {
+        self.waited = false;
+    }
+}
+
+const QUEUE_DEPTH_SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
 const CHANGESETS_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
 const TREES_FLUSH_INTERVAL: Duration = Duration::from_secs(3);
 const CONTENTS_FLUSH_INTERVAL: Duration = Duration::from_secs(3);
@@ -55,6 +143,10 @@ const MAX_CHANGESET_BATCH_SIZE: usize = 10;
 const MAX_TREES_BATCH_SIZE: usize = 20;
 
 const MAX_CONTENT_BATCH_SIZE: usize = 30;
+const CONTENT_UPLOAD_CONCURRENCY: usize = 10;
+
+// One slot per sender task: each reports at most its first error.
+const ERROR_CHANNEL_SIZE: usize = 4;
 const MAX_BLOB_BYTES: u64 = 10 * 1024 * 1024; // 10 MB
 
 #[derive(Clone)]
@@ -63,6 +155,8 @@ pub struct SendManager {
     files_sender: mpsc::Sender<FileMessage>,
     trees_sender: mpsc::Sender<TreeMessage>,
     changeset_sender: mpsc::Sender<ChangesetMessage>,
+    sender_tasks: Arc<Mutex<Vec<JoinHandle<Result<()>>>>>,
+    reponame: String,
 }
 
 pub enum ContentMessage {
@@ -102,49 +196,166 @@ pub enum ChangesetMessage {
 }
 
 impl SendManager {
-    pub fn new(external_sender: Arc<EdenapiSender>, logger: Logger, reponame: String) -> Self {
+    pub fn new(
+        config: SendManagerConfig,
+        external_sender: Arc<EdenapiSender>,
+        logger: Logger,
+        reponame: String,
+    ) -> Result<(Self, mpsc::Receiver<anyhow::Error>)> {
+        if config.content_channel_size == 0
+            || config.files_channel_size == 0
+            || config.trees_channel_size == 0
+            || config.changeset_channel_size == 0
+        {
+            bail!("SendManager channel sizes must be non-zero: {:?}", config);
+        }
+        if config.content_upload_concurrency == 0 {
+            bail!(
+                "SendManager content upload concurrency must be non-zero: {:?}",
+                config
+            );
+        }
+
+        let (error_tx, error_rx) = mpsc::channel(ERROR_CHANNEL_SIZE);
+        let mut sender_tasks = Vec::new();
+
         // Create channel for receiving content
-        let (content_sender, content_recv) = mpsc::channel(CONTENT_CHANNEL_SIZE);
-        Self::spawn_content_sender(
-            reponame.clone(),
-            content_recv,
-            external_sender.clone(),
-            logger.clone(),
-        );
+        let (content_sender, content_recv) = mpsc::channel(config.content_channel_size);
+        sender_tasks.push(Self::report_errors(
+            error_tx.clone(),
+            Self::spawn_content_sender(
+                reponame.clone(),
+                content_recv,
+                external_sender.clone(),
+                logger.clone(),
+                config.content_upload_concurrency,
+            ),
+        ));
 
         // Create channel for receiving files
-        let (files_sender, files_recv) = mpsc::channel(FILES_CHANNEL_SIZE);
-        Self::spawn_files_sender(
-            reponame.clone(),
-            files_recv,
-            external_sender.clone(),
-            logger.clone(),
-        );
+        let (files_sender, files_recv) = mpsc::channel(config.files_channel_size);
+        sender_tasks.push(Self::report_errors(
+            error_tx.clone(),
+            Self::spawn_files_sender(
+                reponame.clone(),
+                files_recv,
+                external_sender.clone(),
+                logger.clone(),
+                config.assert_message_order,
+            ),
+        ));
 
         // Create channel for receiving trees
-        let (trees_sender, trees_recv) = mpsc::channel(TREES_CHANNEL_SIZE);
-        Self::spawn_trees_sender(
-            reponame.clone(),
-            trees_recv,
-            external_sender.clone(),
-            logger.clone(),
-        );
+        let (trees_sender, trees_recv) = mpsc::channel(config.trees_channel_size);
+        sender_tasks.push(Self::report_errors(
+            error_tx.clone(),
+            Self::spawn_trees_sender(
+                reponame.clone(),
+                trees_recv,
+                external_sender.clone(),
+                logger.clone(),
+                config.assert_message_order,
+            ),
+        ));
 
         // Create channel for receiving changesets
-        let (changeset_sender, changeset_recv) = mpsc::channel(CHANGESET_CHANNEL_SIZE);
-        Self::spawn_changeset_sender(
+        let (changeset_sender, changeset_recv) = mpsc::channel(config.changeset_channel_size);
+        sender_tasks.push(Self::report_errors(
+            error_tx.clone(),
+            Self::spawn_changeset_sender(
+                reponame.clone(),
+                changeset_recv,
+                external_sender.clone(),
+                logger.clone(),
+                config.verify_changeset_uploads,
+                config.assert_message_order,
+            ),
+        ));
+
+        Self::spawn_queue_depth_sampler(
             reponame.clone(),
-            changeset_recv,
-            external_sender.clone(),
-            logger.clone(),
+            content_sender.downgrade(),
+            files_sender.downgrade(),
+            trees_sender.downgrade(),
+            changeset_sender.downgrade(),
         );
 
-        Self {
-            content_sender,
-            files_sender,
-            trees_sender,
-            changeset_sender,
+        Ok((
+            Self {
+                content_sender,
+                files_sender,
+                trees_sender,
+                changeset_sender,
+                sender_tasks: Arc::new(Mutex::new(sender_tasks)),
+                reponame,
+            },
+            error_rx,
+        ))
+    }
+
+    /// Wrap a sender task so that its failure is reported promptly on the
+    /// error channel, rather than only when the driver next awaits a Done
+    /// message.
+    fn report_errors(
+        error_tx: mpsc::Sender<anyhow::Error>,
+        task: JoinHandle<Result<()>>,
+    ) -> JoinHandle<Result<()>> {
+        mononoke::spawn_task(async move {
+            match task.await {
+                Ok(Ok(())) => Ok(()),
+                Ok(Err(e)) => {
+                    // The driver may have stopped listening; that's fine.
+                    let _ = error_tx.try_send(anyhow::anyhow!("{:?}", e));
+                    Err(e)
+                }
+                Err(e) => {
+                    let e = anyhow::anyhow!("Sender task panicked: {:?}", e);
+                    let _ = error_tx.try_send(anyhow::anyhow!("{:?}", e));
+                    Err(e)
+                }
+            }
+        })
+    }
+
+    /// Periodically report how full each channel is, so that operators can
+    /// tell which stage is the bottleneck. Holds only weak senders so it
+    /// doesn't keep the channels open, and exits once they are all closed.
+    fn spawn_queue_depth_sampler(
+        reponame: String,
+        content_sender: mpsc::WeakSender<ContentMessage>,
+        files_sender: mpsc::WeakSender<FileMessage>,
+        trees_sender: mpsc::WeakSender<TreeMessage>,
+        changeset_sender: mpsc::WeakSender<ChangesetMessage>,
+    ) {
+        fn queue_depth<T>(sender: &Sender<T>) -> i64 {
+            (sender.max_capacity() - sender.capacity()) as i64
         }
+
+        mononoke::spawn_task(async move {
+            let mut timer = interval(QUEUE_DEPTH_SAMPLE_INTERVAL);
+            loop {
+                timer.tick().await;
+                match (
+                    content_sender.upgrade(),
+                    files_sender.upgrade(),
+                    trees_sender.upgrade(),
+                    changeset_sender.upgrade(),
+                ) {
+                    (Some(content), Some(files), Some(trees), Some(changesets)) => {
+                        STATS::content_queue_depth
+                            .add_value(queue_depth(&content), (reponame.clone(),));
+                        STATS::files_queue_depth
+                            .add_value(queue_depth(&files), (reponame.clone(),));
+                        STATS::trees_queue_depth
+                            .add_value(queue_depth(&trees), (reponame.clone(),));
+                        STATS::changesets_queue_depth
+                            .add_value(queue_depth(&changesets), (reponame.clone(),));
+                    }
+                    // The manager (and all clones) are gone.
+                    _ => break,
+                }
+            }
+        });
     }
 
     fn spawn_content_sender(
@@ -152,22 +363,52 @@ impl SendManager {
         mut content_recv: mpsc::Receiver<ContentMessage>,
         content_es: Arc<EdenapiSender>,
         content_logger: Logger,
-    ) {
+        upload_concurrency: usize,
+    ) -> JoinHandle<Result<()>> {
+        /// Kick off the upload of the current batch, returning a future to be
+        /// tracked in the in-flight set.
+        fn start_upload(
+            content_es: &Arc<EdenapiSender>,
+            current_batch: &mut Vec<(AnyFileContentId, FileContents)>,
+            reponame: String,
+        ) -> Option<impl std::future::Future<Output = Result<()>>> {
+            if current_batch.is_empty() {
+                return None;
+            }
+            let batch = std::mem::take(current_batch);
+            let content_es = content_es.clone();
+            Some(async move {
+                let batch_len = batch.len() as i64;
+                let start = std::time::Instant::now();
+                content_es.upload_contents(batch).await?;
+                let elapsed = start.elapsed().as_secs() / batch_len as u64;
+                STATS::content_upload_time_s.add_value(elapsed as i64, (reponame.clone(),));
+                STATS::synced_contents.add_value(batch_len, (reponame,));
+                Ok(())
+            })
+        }
+
         mononoke::spawn_task(async move {
-            let mut pending_messages = VecDeque::new();
+            let mut encountered_error: Option<anyhow::Error> = None;
+            let mut pending_messages: VecDeque<oneshot::Sender<Result<(), anyhow::Error>>> =
+                VecDeque::new();
             let mut current_batch = Vec::new();
             let mut current_batch_size = 0;
             let mut flush_timer = interval(CONTENTS_FLUSH_INTERVAL);
+            let mut in_flight = FuturesUnordered::new();
 
             loop {
                 tokio::select! {
                     msg = content_recv.recv() => {
                         match msg {
-                            Some(ContentMessage::Content((ct_id, fcs))) => {
+                            Some(ContentMessage::Content((ct_id, fcs)))
+                                if encountered_error.is_none() =>
+                            {
                                 let size = fcs.size();
                                 current_batch_size += size;
                                 current_batch.push((ct_id, fcs));
                             }
+                            Some(ContentMessage::Content(_)) => (),
                             Some(ContentMessage::ContentDone(files_sender, tree_sender)) => {
                                 pending_messages.push_back(files_sender);
                                 pending_messages.push_back(tree_sender);
@@ -176,57 +417,86 @@ impl SendManager {
                         }
 
                         if current_batch_size >= MAX_BLOB_BYTES || current_batch.len() >= MAX_CONTENT_BATCH_SIZE {
-                            if let Err(e) = flush_batch(&content_es, &mut current_batch, &mut pending_messages, &content_logger, reponame.clone()).await {
-                                error!(content_logger, "Error processing content: {:?}", e);
-                                return;
+                            if encountered_error.is_none() {
+                                if let Some(fut) = start_upload(&content_es, &mut current_batch, reponame.clone()) {
+                                    in_flight.push(fut);
+                                }
                             }
                             current_batch_size = 0;
+                            // Don't buffer more than the configured number of
+                            // uploads; block reading new messages instead.
+                            while in_flight.len() >= upload_concurrency {
+                                if let Some(Err(e)) = in_flight.next().await {
+                                    error!(content_logger, "Error processing content: {:?}", e);
+                                    encountered_error.get_or_insert(e);
+                                    current_batch.clear();
+                                }
+                            }
+                        }
+                    }
+                    Some(res) = in_flight.next() => {
+                        if let Err(e) = res {
+                            error!(content_logger, "Error processing content: {:?}", e);
+                            encountered_error.get_or_insert(e);
+                            current_batch.clear();
                         }
                     }
                     _ = flush_timer.tick() => {
-                        if current_batch_size > 0 || !pending_messages.is_empty() {
-                            if let Err(e) = flush_batch(&content_es, &mut current_batch, &mut pending_messages, &content_logger, reponame.clone()).await {
-                                error!(content_logger, "Error processing content: {:?}", e);
-                                return;
+                        if encountered_error.is_none() {
+                            if let Some(fut) = start_upload(&content_es, &mut current_batch, reponame.clone()) {
+                                in_flight.push(fut);
                             }
                             current_batch_size = 0;
                         }
                     }
                 }
-            }
 
-            async fn flush_batch(
-                content_es: &Arc<EdenapiSender>,
-                current_batch: &mut Vec<(AnyFileContentId, FileContents)>,
-                pending_messages: &mut VecDeque<oneshot::Sender<Result<(), anyhow::Error>>>,
-                content_logger: &Logger,
-                reponame: String,
-            ) -> Result<(), anyhow::Error> {
-                let current_batch_len = current_batch.len() as i64;
-                let start = std::time::Instant::now();
-                if current_batch_len > 0 {
-                    if let Err(e) = content_es
-                        .upload_contents(std::mem::take(current_batch))
-                        .await
-                    {
-                        error!(content_logger, "Error processing content: {:?}", e);
-                        return Err(e);
-                    } else {
-                        let elapsed = start.elapsed().as_secs() / current_batch_len as u64;
-                        STATS::content_upload_time_s.add_value(elapsed as i64, (reponame.clone(),));
-                        STATS::synced_contents.add_value(current_batch_len, (reponame.clone(),));
+                // Only release Done waiters once every upload issued before
+                // them has finished (conservatively: once nothing at all is
+                // queued or in flight).
+                if in_flight.is_empty() && current_batch.is_empty() {
+                    while let Some(sender) = pending_messages.pop_front() {
+                        let res = match &encountered_error {
+                            Some(e) => {
+                                sender.send(Err(anyhow::anyhow!("Error processing content: {:?}", e)))
+                            }
+                            None => sender.send(Ok(())),
+                        };
+                        if let Err(e) = res {
+                            return Err(anyhow::anyhow!("Error sending content ready: {:?}", e));
+                        }
                     }
                 }
+            }
 
-                while let Some(sender) = pending_messages.pop_front() {
-                    let res = sender.send(Ok(()));
-                    if let Err(e) = res {
-                        return Err(anyhow::anyhow!("Error sending content ready: {:?}", e));
-                    }
+            // Channel closed: drain whatever is still batched or in flight
+            // before exiting.
+            if encountered_error.is_none() {
+                if let Some(fut) = start_upload(&content_es, &mut current_batch, reponame.clone()) {
+                    in_flight.push(fut);
                 }
-                Ok(())
             }
-        });
+            while let Some(res) = in_flight.next().await {
+                if let Err(e) = res {
+                    error!(content_logger, "Error processing content: {:?}", e);
+                    encountered_error.get_or_insert(e);
+                }
+            }
+            while let Some(sender) = pending_messages.pop_front() {
+                let res = match &encountered_error {
+                    Some(e) => sender.send(Err(anyhow::anyhow!("Error processing content: {:?}", e))),
+                    None => sender.send(Ok(())),
+                };
+                if let Err(e) = res {
+                    return Err(anyhow::anyhow!("Error sending content ready: {:?}", e));
+                }
+            }
+            if let Some(e) = encountered_error {
+                return Err(e);
+            }
+
+            Ok(())
+        })
     }
 
     fn spawn_files_sender(
@@ -234,12 +504,15 @@ impl SendManager {
         mut files_recv: mpsc::Receiver<FileMessage>,
         files_es: Arc<EdenapiSender>,
         files_logger: Logger,
-    ) {
+        assert_order: bool,
+    ) -> JoinHandle<Result<()>> {
         mononoke::spawn_task(async move {
             let mut encountered_error: Option<anyhow::Error> = None;
+            let mut ordering = OrderingTracker::new(assert_order);
             while let Some(msg) = files_recv.recv().await {
                 match msg {
                     FileMessage::WaitForContents(receiver) => {
+                        ordering.wait_received();
                         // Read outcome from content upload
                         let start = std::time::Instant::now();
                         match receiver.await {
@@ -260,6 +533,7 @@ impl SendManager {
                         STATS::content_wait_time_s.add_value(elapsed as i64, (reponame.clone(),));
                     }
                     FileMessage::FileNode(f) if encountered_error.is_none() => {
+                        ordering.data_received(&files_logger, "FileMessage::FileNode");
                         // Upload the file nodes through sender
                         if let Err(e) = files_es.upload_filenodes(vec![(f)]).await {
                             encountered_error.get_or_insert(
@@ -270,22 +544,36 @@ impl SendManager {
                         }
                     }
                     FileMessage::FilesDone(sender) => {
+                        ordering.done_received();
                         if let Some(e) = encountered_error {
                             error!(files_logger, "Error processing files/trees: {:?}", e);
+                            let msg = format!("Error processing files: {:?}", e);
                             let _ = sender.send(Err(e));
-                            return;
+                            return Err(anyhow::anyhow!(msg));
                         } else {
                             let res = sender.send(Ok(()));
                             if let Err(e) = res {
                                 error!(files_logger, "Error sending content ready: {:?}", e);
-                                return;
+                                return Err(anyhow::anyhow!(
+                                    "Error sending content ready: {:?}",
+                                    e
+                                ));
                             }
                         }
                     }
-                    FileMessage::FileNode(_) => (),
+                    FileMessage::FileNode(_) => {
+                        ordering.data_received(&files_logger, "FileMessage::FileNode");
+                    }
                 }
             }
-        });
+
+            // Channel closed: surface any error we were sitting on.
+            if let Some(e) = encountered_error {
+                error!(files_logger, "Error processing files/trees: {:?}", e);
+                return Err(e);
+            }
+            Ok(())
+        })
     }
 
     fn spawn_trees_sender(
@@ -293,9 +581,11 @@ impl SendManager {
         mut trees_recv: mpsc::Receiver<TreeMessage>,
         trees_es: Arc<EdenapiSender>,
         trees_logger: Logger,
-    ) {
+        assert_order: bool,
+    ) -> JoinHandle<Result<()>> {
         mononoke::spawn_task(async move {
             let mut encountered_error: Option<anyhow::Error> = None;
+            let mut ordering = OrderingTracker::new(assert_order);
             let mut batch_trees = Vec::new();
             let mut batch_done_senders = VecDeque::new();
             let mut timer = interval(TREES_FLUSH_INTERVAL);
@@ -304,6 +594,7 @@ impl SendManager {
                     msg = trees_recv.recv() => {
                         match msg {
                             Some(TreeMessage::WaitForContents(receiver)) => {
+                                ordering.wait_received();
                                 // Read outcome from content upload
                                 let start = std::time::Instant::now();
                                 match receiver.await {
@@ -324,29 +615,52 @@ impl SendManager {
                                 STATS::content_wait_time_s.add_value(elapsed as i64, (reponame.clone(),));
                             }
                             Some(TreeMessage::Tree(t)) if encountered_error.is_none() => {
+                                ordering.data_received(&trees_logger, "TreeMessage::Tree");
                                 batch_trees.push(t);
                             }
                             Some(TreeMessage::TreesDone(sender)) => {
+                                ordering.done_received();
                                 batch_done_senders.push_back(sender);
                             }
-                            Some(TreeMessage::Tree(_)) => (),
+                            Some(TreeMessage::Tree(_)) => {
+                                ordering.data_received(&trees_logger, "TreeMessage::Tree");
+                            }
                             None => break,
                         }
                         if batch_trees.len() >= MAX_TREES_BATCH_SIZE {
                             if let Err(e) = flush_trees(&trees_es, &mut batch_trees, &mut batch_done_senders, &mut encountered_error, &reponame,  &trees_logger).await {
                                 error!(trees_logger, "Trees flush failed: {:?}", e);
-                                return;
+                                return Err(e);
                             }
                         }
                     }
                     _ = timer.tick() => {
                         if let Err(e) = flush_trees(&trees_es, &mut batch_trees, &mut batch_done_senders, &mut encountered_error, &reponame, &trees_logger).await {
                             error!(trees_logger, "Trees flush failed: {:?}", e);
-                            return;
+                            return Err(e);
                         }
                     }
                 }
             }
+
+            // Channel closed: drain the remaining batch and surface any error
+            // we were sitting on.
+            if let Err(e) = flush_trees(
+                &trees_es,
+                &mut batch_trees,
+                &mut batch_done_senders,
+                &mut encountered_error,
+                &reponame,
+                &trees_logger,
+            )
+            .await
+            {
+                error!(trees_logger, "Trees flush failed: {:?}", e);
+                return Err(e);
+            }
+            if let Some(e) = encountered_error {
+                return Err(e);
+            }
             async fn flush_trees(
                 trees_es: &Arc<EdenapiSender>,
                 batch_trees: &mut Vec<HgManifestId>,
@@ -384,7 +698,9 @@ impl SendManager {
                 }
                 Ok(())
             }
-        });
+
+            Ok(())
+        })
     }
 
     fn spawn_changeset_sender(
@@ -392,9 +708,12 @@ impl SendManager {
         mut changeset_recv: mpsc::Receiver<ChangesetMessage>,
         changeset_es: Arc<EdenapiSender>,
         changeset_logger: Logger,
-    ) {
+        verify_uploads: bool,
+        assert_order: bool,
+    ) -> JoinHandle<Result<()>> {
         mononoke::spawn_task(async move {
             let mut encountered_error: Option<anyhow::Error> = None;
+            let mut ordering = OrderingTracker::new(assert_order);
 
             let mut pending_messages = VecDeque::new();
             let mut pending_log = VecDeque::new();
@@ -407,6 +726,7 @@ impl SendManager {
                     msg = changeset_recv.recv() => {
                         match msg {
                             Some(ChangesetMessage::WaitForFilesAndTrees(files_receiver, trees_receiver)) => {
+                                ordering.wait_received();
                                 // Read outcome from files and trees upload
                                 let start = std::time::Instant::now();
                                 match tokio::try_join!(files_receiver, trees_receiver)  {
@@ -432,12 +752,17 @@ impl SendManager {
                             Some(ChangesetMessage::Changeset((hg_cs, bcs)))
                                 if encountered_error.is_none() =>
                             {
+                                ordering.data_received(
+                                    &changeset_logger,
+                                    "ChangesetMessage::Changeset",
+                                );
                                 current_batch.push((hg_cs, bcs));
                             }
 
                             Some(ChangesetMessage::ChangesetDone(sender))
                                 if encountered_error.is_none() =>
                             {
+                                ordering.done_received();
                                 pending_messages.push_back(sender);
                             }
 
@@ -458,8 +783,14 @@ impl SendManager {
                                 return Err(e);
                             }
 
-                            Some(ChangesetMessage::Log((_, _)))
-                            | Some(ChangesetMessage::Changeset(_)) => {}
+                            Some(ChangesetMessage::Changeset(_)) => {
+                                ordering.data_received(
+                                    &changeset_logger,
+                                    "ChangesetMessage::Changeset",
+                                );
+                            }
+
+                            Some(ChangesetMessage::Log((_, _))) => {}
 
                             None => break,
                         }
@@ -472,6 +803,7 @@ impl SendManager {
                                 &mut pending_log,
                                 &changeset_logger,
                                 reponame.clone(),
+                                verify_uploads,
                             )
                             .await
                             {
@@ -490,6 +822,7 @@ impl SendManager {
                             &mut pending_log,
                             &changeset_logger,
                             reponame.clone(),
+                            verify_uploads,
                         )
                         .await
                         {
@@ -499,6 +832,25 @@ impl SendManager {
                 }
             }
 
+            // Channel closed: drain the remaining batch and surface any error
+            // we were sitting on.
+            if let Err(e) = flush_batch(
+                &changeset_es,
+                &mut current_batch,
+                &mut pending_messages,
+                &mut pending_log,
+                &changeset_logger,
+                reponame.clone(),
+                verify_uploads,
+            )
+            .await
+            {
+                return Err(anyhow::anyhow!("Error processing changesets: {:?}", e));
+            }
+            if let Some(e) = encountered_error {
+                return Err(e);
+            }
+
             async fn flush_batch(
                 changeset_es: &Arc<EdenapiSender>,
                 current_batch: &mut Vec<(HgBlobChangeset, BonsaiChangeset)>,
@@ -506,10 +858,15 @@ impl SendManager {
                 pending_log: &mut VecDeque<Option<i64>>,
                 changeset_logger: &Logger,
                 reponame: String,
+                verify_uploads: bool,
             ) -> Result<(), anyhow::Error> {
                 if !current_batch.is_empty() {
                     let start = std::time::Instant::now();
                     let batch_size = current_batch.len();
+                    let hg_ids = current_batch
+                        .iter()
+                        .map(|(hg_cs, _)| hg_cs.get_changeset_id())
+                        .collect::<Vec<_>>();
                     if let Err(e) = changeset_es
                         .upload_identical_changeset(std::mem::take(current_batch))
                         .await
@@ -522,10 +879,26 @@ impl SendManager {
                             .add_value(elapsed as i64, (reponame.clone(),));
                         STATS::synced_commits.add_value(batch_size as i64, (reponame.clone(),));
                     }
+                    if verify_uploads {
+                        let missing = changeset_es.verify_uploaded(hg_ids).await?;
+                        if !missing.is_empty() {
+                            error!(
+                                changeset_logger,
+                                "Upload verification failed, missing: {:?}", missing
+                            );
+                            return Err(anyhow::anyhow!(
+                                "Changesets not present on the server after upload: {:?}",
+                                missing
+                            ));
+                        }
+                    }
                 }
 
                 while let Some(Some(lag)) = pending_log.pop_front() {
                     STATS::sync_lag_seconds.add_value(lag, (reponame.clone(),));
+                    // The average above hides tail latency; the histogram
+                    // makes p99 sync lag visible per repo.
+                    STATS::sync_lag_seconds_histogram.add_value(lag, (reponame.clone(),));
                 }
 
                 while let Some(sender) = pending_messages.pop_front() {
@@ -538,10 +911,11 @@ impl SendManager {
             }
 
             Ok(())
-        });
+        })
     }
 
     pub async fn send_content(&self, content_msg: ContentMessage) -> Result<()> {
+        self.record_if_blocked(&self.content_sender);
         self.content_sender
             .send(content_msg)
             .await
@@ -549,6 +923,7 @@ impl SendManager {
     }
 
     pub async fn send_file(&self, ft_msg: FileMessage) -> Result<()> {
+        self.record_if_blocked(&self.files_sender);
         self.files_sender
             .send(ft_msg)
             .await
@@ -556,6 +931,7 @@ impl SendManager {
     }
 
     pub async fn send_tree(&self, ft_msg: TreeMessage) -> Result<()> {
+        self.record_if_blocked(&self.trees_sender);
         self.trees_sender
             .send(ft_msg)
             .await
@@ -563,9 +939,56 @@ impl SendManager {
     }
 
     pub async fn send_changeset(&self, cs_msg: ChangesetMessage) -> Result<()> {
+        self.record_if_blocked(&self.changeset_sender);
         self.changeset_sender
             .send(cs_msg)
             .await
             .map_err(|err| err.into())
     }
+
+    /// Count sends that are about to block because the channel is full.
+    fn record_if_blocked<T>(&self, sender: &Sender<T>) {
+        if sender.capacity() == 0 {
+            STATS::send_blocked.add_value(1, (self.reponame.clone(),));
+        }
+    }
+
+    /// Close the channels and wait for the spawned sender tasks to drain any
+    /// queued messages. Returns the first error a sender task encountered.
+    ///
+    /// Only the channel clones held by this instance are dropped; for the
+    /// tasks to see the channels close, every other clone of the manager must
+    /// be dropped first.
+    pub async fn shutdown(self) -> Result<()> {
+        let Self {
+            content_sender,
+            files_sender,
+            trees_sender,
+            changeset_sender,
+            sender_tasks,
+            reponame: _,
+        } = self;
+        drop(content_sender);
+        drop(files_sender);
+        drop(trees_sender);
+        drop(changeset_sender);
+
+        let sender_tasks = std::mem::take(&mut *sender_tasks.lock().expect("poisoned lock"));
+        let mut first_error = None;
+        for task in sender_tasks {
+            match task.await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    first_error.get_or_insert(e);
+                }
+                Err(e) => {
+                    first_error.get_or_insert(anyhow::anyhow!("Sender task panicked: {:?}", e));
+                }
+            }
+        }
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
 }
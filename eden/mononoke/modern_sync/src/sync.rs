@@ -59,10 +59,12 @@ use url::Url;
 
 use crate::bul_util;
 use crate::sender::edenapi::EdenapiSender;
+use crate::sender::edenapi::OperationTimeouts;
 use crate::sender::manager::ChangesetMessage;
 use crate::sender::manager::ContentMessage;
 use crate::sender::manager::FileMessage;
 use crate::sender::manager::SendManager;
+use crate::sender::manager::SendManagerConfig;
 use crate::sender::manager::TreeMessage;
 use crate::ModernSyncArgs;
 use crate::Repo;
@@ -162,14 +164,25 @@ pub async fn sync(
                 tls_args,
                 ctx.clone(),
                 repo.repo_blobstore().clone(),
+                None,
+                OperationTimeouts::default(),
+                None,
             )
             .await?,
         )
     };
     info!(logger, "Established EdenAPI connection");
 
-    let send_manager = SendManager::new(sender.clone(), logger.clone(), repo_name.clone());
+    let (send_manager, error_rx) = SendManager::new(
+        SendManagerConfig::default(),
+        sender.clone(),
+        logger.clone(),
+        repo_name.clone(),
+    )?;
     info!(logger, "Initialized channels");
+    // Sender task failures surface here, letting us abort instead of
+    // continuing to produce work that can no longer be sent.
+    let error_rx = Arc::new(std::sync::Mutex::new(error_rx));
 
     let mut scuba_sample = ctx.scuba().clone();
     scuba_sample.add("repo", repo_name.clone());
@@ -184,7 +197,7 @@ pub async fn sync(
         repo.bookmark_update_log_arc(),
     )
     .then(|entries| {
-        cloned!(repo, logger, sender, mut send_manager, repo_name);
+        cloned!(repo, logger, sender, mut send_manager, repo_name, error_rx);
         borrowed!(ctx);
         async move {
             match entries {
@@ -196,6 +209,9 @@ pub async fn sync(
                     Err(e)
                 }
                 Ok(entries) => {
+                    if let Ok(e) = error_rx.lock().expect("poisoned lock").try_recv() {
+                        return Err(e.context("Sender task failed"));
+                    }
                     for entry in entries {
                         let to_cs = entry
                             .to_changeset_id
@@ -341,6 +357,7 @@ pub async fn sync(
                                     entry.bookmark_name.name().to_string(),
                                     from_changeset,
                                     to_changeset,
+                                    EdenapiSender::mirror_pushvars(),
                                 )
                                 .await?;
                         }
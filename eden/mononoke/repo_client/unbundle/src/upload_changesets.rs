@@ -342,6 +342,10 @@ pub async fn upload_changeset(
         cs_metadata,
         verify_origin_repo: maybe_backup_repo_source,
         upload_to_blobstore_only: bonsai.is_some(),
+        batch_graph_insert: None,
+        max_concurrent_uploads: None,
+        validator: None,
+        changed_files_base: None,
     };
     let scheduled_uploading = create_changeset.create(ctx, &repo, bonsai, scuba_logger);
 
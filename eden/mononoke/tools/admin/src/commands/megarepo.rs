@@ -6,22 +6,26 @@
  */
 
 pub(crate) mod common;
+mod list_redirected;
 mod merge;
 mod move_commit;
 mod pushredirection;
 mod run_mover;
 mod sync_diamond_merge;
+mod validate;
 
 use anyhow::Result;
 use clap::Parser;
 use clap::Subcommand;
 use mononoke_app::MononokeApp;
 
+use self::list_redirected::ListArgs;
 use self::merge::MergeArgs;
 use self::move_commit::MoveArgs;
 use self::pushredirection::PushRedirectionArgs;
 use self::run_mover::RunMoverArgs;
 use self::sync_diamond_merge::SyncDiamondMergeArgs;
+use self::validate::ValidateArgs;
 
 /// Manage megarepo
 #[derive(Parser)]
@@ -34,10 +38,14 @@ pub struct CommandArgs {
 enum MegarepoSubcommand {
     /// Manage which repos are pushredirected to the large repo
     PushRedirection(PushRedirectionArgs),
+    /// List which repos are currently push-redirected
+    ListRedirected(ListArgs),
     Merge(MergeArgs),
     MoveCommit(MoveArgs),
     RunMover(RunMoverArgs),
     SyncDiamondMerge(SyncDiamondMergeArgs),
+    /// Check that a small repo's push-redirection config is consistent
+    Validate(ValidateArgs),
 }
 
 pub async fn run(app: MononokeApp, args: CommandArgs) -> Result<()> {
@@ -45,12 +53,14 @@ pub async fn run(app: MononokeApp, args: CommandArgs) -> Result<()> {
 
     match args.subcommand {
         MegarepoSubcommand::PushRedirection(args) => pushredirection::run(&ctx, app, args).await?,
+        MegarepoSubcommand::ListRedirected(args) => list_redirected::run(&ctx, app, args).await?,
         MegarepoSubcommand::Merge(args) => merge::run(&ctx, app, args).await?,
         MegarepoSubcommand::MoveCommit(args) => move_commit::run(&ctx, app, args).await?,
         MegarepoSubcommand::RunMover(args) => run_mover::run(&ctx, app, args).await?,
         MegarepoSubcommand::SyncDiamondMerge(args) => {
             sync_diamond_merge::run(&ctx, app, args).await?
         }
+        MegarepoSubcommand::Validate(args) => validate::run(&ctx, app, args).await?,
     }
 
     Ok(())
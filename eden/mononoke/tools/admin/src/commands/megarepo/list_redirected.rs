@@ -0,0 +1,129 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::sync::Arc;
+
+use anyhow::Context;
+use anyhow::Result;
+use context::CoreContext;
+use live_commit_sync_config::CfgrLiveCommitSyncConfig;
+use live_commit_sync_config::LiveCommitSyncConfig;
+use mononoke_app::args::RepoArgs;
+use mononoke_app::MononokeApp;
+use pushredirect::NoopPushRedirectionConfig;
+use pushredirect::PushRedirectionConfig;
+use repo_identity::RepoIdentity;
+use repo_identity::RepoIdentityRef;
+use serde::Serialize;
+use slog::warn;
+
+/// List which repos are currently push-redirected
+#[derive(Debug, clap::Args)]
+pub struct ListArgs {
+    /// Format the output as JSON, one object per redirected repo
+    #[clap(long)]
+    json: bool,
+}
+
+#[facet::container]
+pub struct Repo {
+    #[facet]
+    repo_identity: RepoIdentity,
+
+    #[facet]
+    pub push_redirection_config: dyn PushRedirectionConfig,
+}
+
+#[derive(Serialize)]
+struct RedirectedRepo {
+    small_repo_id: i32,
+    small_repo_name: String,
+    large_repo_id: Option<i32>,
+    large_repo_name: Option<String>,
+    draft_push: bool,
+    public_push: bool,
+}
+
+pub async fn run(ctx: &CoreContext, app: MononokeApp, args: ListArgs) -> Result<()> {
+    let config_store = app.environment().config_store.clone();
+    // Only used to resolve the large repo from the common commit sync config,
+    // which doesn't touch the push-redirection db.
+    let live_commit_sync_config =
+        CfgrLiveCommitSyncConfig::new(&config_store, Arc::new(NoopPushRedirectionConfig {}))?;
+
+    let repo_configs = app.repo_configs();
+    let mut repo_ids: Vec<_> = repo_configs
+        .repos
+        .values()
+        .filter(|config| config.enabled)
+        .map(|config| config.repoid)
+        .collect();
+    repo_ids.sort();
+
+    let mut redirected = vec![];
+    for repo_id in repo_ids {
+        let repo: Repo = match app.open_repo(&RepoArgs::from_repo_id(repo_id.id())).await {
+            Ok(repo) => repo,
+            Err(e) => {
+                warn!(ctx.logger(), "Failed to open repo {}: {:#}", repo_id, e);
+                continue;
+            }
+        };
+
+        let entry = repo
+            .push_redirection_config
+            .get(ctx, repo_id)
+            .await
+            .with_context(|| format!("Failed to get push redirection config for {}", repo_id))?;
+        let (draft_push, public_push) = match entry {
+            Some(entry) if entry.draft_push || entry.public_push => {
+                (entry.draft_push, entry.public_push)
+            }
+            _ => continue,
+        };
+
+        let (large_repo_id, large_repo_name) =
+            match live_commit_sync_config.get_common_config_if_exists(repo_id)? {
+                Some(common) => {
+                    let large_repo_name = repo_configs
+                        .get_repo_config(common.large_repo_id)
+                        .map(|(name, _)| name.clone());
+                    (Some(common.large_repo_id.id()), large_repo_name)
+                }
+                None => (None, None),
+            };
+
+        redirected.push(RedirectedRepo {
+            small_repo_id: repo_id.id(),
+            small_repo_name: repo.repo_identity().name().to_string(),
+            large_repo_id,
+            large_repo_name,
+            draft_push,
+            public_push,
+        });
+    }
+
+    if args.json {
+        let json = serde_json::to_string_pretty(&redirected)
+            .context("Failed to convert output to JSON")?;
+        println!("{}", json);
+    } else {
+        for repo in redirected {
+            let large = match (repo.large_repo_id, repo.large_repo_name) {
+                (Some(id), Some(name)) => format!("{} ({})", name, id),
+                (Some(id), None) => format!("unknown name ({})", id),
+                _ => "no common commit sync config".to_string(),
+            };
+            println!(
+                "{} ({}) -> {}: draft={} public={}",
+                repo.small_repo_name, repo.small_repo_id, large, repo.draft_push, repo.public_push,
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -9,6 +9,7 @@ use anyhow::bail;
 use anyhow::format_err;
 use anyhow::Error;
 use anyhow::Result;
+use blobstore::Loadable;
 use bonsai_hg_mapping::BonsaiHgMappingRef;
 use cloned::cloned;
 use context::CoreContext;
@@ -23,6 +24,7 @@ use mononoke_app::args::ChangesetArgs;
 use mononoke_app::args::RepoArgs;
 use mononoke_app::MononokeApp;
 use mononoke_types::ChangesetId;
+use repo_blobstore::RepoBlobstoreRef;
 use slog::info;
 
 use super::common::ResultingChangesetArgs;
@@ -37,6 +39,11 @@ pub struct MergeArgs {
 
     #[command(flatten)]
     pub res_cs_args: ResultingChangesetArgs,
+
+    /// Perform all the validation and parent resolution, but stop before
+    /// creating the merge commit or moving any bookmark
+    #[clap(long)]
+    pub dry_run: bool,
 }
 
 async fn fail_on_path_conflicts(
@@ -90,6 +97,42 @@ pub async fn perform_merge(
     .await
 }
 
+async fn perform_dry_run_merge(
+    ctx: CoreContext,
+    repo: Repo,
+    first_bcs_id: ChangesetId,
+    second_bcs_id: ChangesetId,
+    res_cs_args: MegarepoNewChangesetArgs,
+) -> Result<(), Error> {
+    let (first_hg_cs_id, second_hg_cs_id) = try_join!(
+        repo.derive_hg_changeset(&ctx, first_bcs_id.clone()),
+        repo.derive_hg_changeset(&ctx, second_bcs_id.clone()),
+    )?;
+    fail_on_path_conflicts(&ctx, &repo, first_hg_cs_id, second_hg_cs_id).await?;
+
+    let (first_hg_cs, second_hg_cs) = try_join!(
+        first_hg_cs_id.load(&ctx, repo.repo_blobstore()),
+        second_hg_cs_id.load(&ctx, repo.repo_blobstore()),
+    )?;
+    info!(
+        ctx.logger(),
+        "Would create a merge bonsai changeset with parents: {:?}, {:?}",
+        &first_bcs_id,
+        &second_bcs_id
+    );
+    info!(
+        ctx.logger(),
+        "First parent manifest: {}, second parent manifest: {}",
+        first_hg_cs.manifestid(),
+        second_hg_cs.manifestid()
+    );
+    info!(
+        ctx.logger(),
+        "Would use changeset args: {:?}; not creating the commit (dry run)", res_cs_args
+    );
+    Ok(())
+}
+
 pub async fn run(ctx: &CoreContext, app: MononokeApp, args: MergeArgs) -> Result<()> {
     info!(ctx.logger(), "Creating a merge commit");
 
@@ -103,6 +146,17 @@ pub async fn run(ctx: &CoreContext, app: MononokeApp, args: MergeArgs) -> Result
 
     let res_cs_args = args.res_cs_args.try_into()?;
 
+    if args.dry_run {
+        return perform_dry_run_merge(
+            ctx.clone(),
+            repo.clone(),
+            first_parent,
+            second_parent,
+            res_cs_args,
+        )
+        .await;
+    }
+
     perform_merge(
         ctx.clone(),
         repo.clone(),
@@ -0,0 +1,139 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use context::CoreContext;
+use live_commit_sync_config::LiveCommitSyncConfig;
+use mononoke_api::Repo;
+use mononoke_app::args::RepoArgs;
+use mononoke_app::MononokeApp;
+use repo_identity::RepoIdentityRef;
+use slog::info;
+use slog::warn;
+
+use super::common::get_live_commit_sync_config;
+
+/// Check that a small repo's push-redirection config is internally
+/// consistent, without mutating anything
+#[derive(Debug, clap::Args)]
+pub struct ValidateArgs {
+    /// Small repo whose push-redirection config should be validated
+    #[clap(flatten)]
+    pub repo_args: RepoArgs,
+}
+
+pub async fn run(ctx: &CoreContext, app: MononokeApp, args: ValidateArgs) -> Result<()> {
+    let repo: Repo = app
+        .open_repo(&args.repo_args)
+        .await
+        .context("Failed to open repo")?;
+    let repo_id = repo.repo_identity().id();
+    let repo_name = repo.repo_identity().name().to_string();
+    info!(
+        ctx.logger(),
+        "Validating push-redirection config for {} ({})", repo_name, repo_id
+    );
+
+    let live_commit_sync_config = get_live_commit_sync_config(ctx, &app, args.repo_args)
+        .await
+        .context("Failed to get live commit sync config")?;
+
+    let mut problems = vec![];
+
+    let maybe_large_repo_id = match live_commit_sync_config.get_common_config_if_exists(repo_id)? {
+        None => {
+            problems.push(format!(
+                "{} is not a part of any common commit sync config",
+                repo_id
+            ));
+            None
+        }
+        Some(common) => {
+            if common.large_repo_id == repo_id {
+                problems.push(format!(
+                    "{} is configured as the large repo; expected a small repo",
+                    repo_id
+                ));
+            } else if !common.small_repos.contains_key(&repo_id) {
+                problems.push(format!(
+                    "{} is not listed among the small repos of large repo {}",
+                    repo_id, common.large_repo_id
+                ));
+            }
+
+            match app.repo_configs().get_repo_config(common.large_repo_id) {
+                Some((large_repo_name, _)) => {
+                    info!(
+                        ctx.logger(),
+                        "large repo: {} ({})", large_repo_name, common.large_repo_id
+                    );
+                }
+                None => {
+                    problems.push(format!(
+                        "large repo {} is not present in the repo configs",
+                        common.large_repo_id
+                    ));
+                }
+            }
+
+            Some(common.large_repo_id)
+        }
+    };
+
+    let versions = live_commit_sync_config
+        .get_all_commit_sync_config_versions(repo_id)
+        .await?;
+    if versions.is_empty() {
+        problems.push(format!(
+            "{} has no commit sync config versions at all",
+            repo_id
+        ));
+    }
+    let mut versions: Vec<_> = versions.into_iter().collect();
+    versions.sort_by(|(a, _), (b, _)| a.0.cmp(&b.0));
+    for (version, config) in versions {
+        if let Some(large_repo_id) = maybe_large_repo_id {
+            if config.large_repo_id != large_repo_id {
+                problems.push(format!(
+                    "version {} points to large repo {}, but the common config points to {}",
+                    version, config.large_repo_id, large_repo_id
+                ));
+            }
+        }
+        if config.small_repos.contains_key(&repo_id) {
+            info!(ctx.logger(), "mapping version {}: present", version);
+        } else {
+            problems.push(format!(
+                "version {} does not contain a mapping for {}",
+                version, repo_id
+            ));
+        }
+    }
+
+    let enabled_for_draft = live_commit_sync_config
+        .push_redirector_enabled_for_draft(ctx, repo_id)
+        .await?;
+    let enabled_for_public = live_commit_sync_config
+        .push_redirector_enabled_for_public(ctx, repo_id)
+        .await?;
+    info!(
+        ctx.logger(),
+        "push redirection: draft={} public={}", enabled_for_draft, enabled_for_public
+    );
+
+    if problems.is_empty() {
+        info!(ctx.logger(), "No inconsistencies found");
+        Ok(())
+    } else {
+        for problem in &problems {
+            warn!(ctx.logger(), "{}", problem);
+        }
+        bail!("Found {} inconsistencies", problems.len())
+    }
+}
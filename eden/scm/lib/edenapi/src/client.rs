@@ -10,6 +10,7 @@ use std::collections::HashSet;
 use std::fmt::Debug;
 use std::fs::create_dir_all;
 use std::future::ready;
+use std::io::Read;
 use std::num::NonZeroU64;
 use std::sync::atomic::AtomicI64;
 use std::sync::atomic::Ordering;
@@ -116,11 +117,13 @@ use edenapi_types::WorkspaceDataResponse;
 use edenapi_types::WorkspacesDataResponse;
 use futures::future::BoxFuture;
 use futures::prelude::*;
+use futures::stream::BoxStream;
 use hg_http::http_client;
 use http_client::AsyncResponse;
 use http_client::Encoding;
 use http_client::HttpClient;
 use http_client::Request;
+use http_client::StreamingBody;
 use itertools::Itertools;
 use metrics::Counter;
 use metrics::EntranceGuard;
@@ -648,13 +651,12 @@ impl Client {
         self.fetch_guard::<FileResponse>(requests, guards)
     }
 
-    /// Upload a single file
-    async fn process_single_file_upload(
+    fn build_file_upload_url(
         &self,
         item: AnyFileContentId,
-        raw_content: Bytes,
+        content_size: u64,
         bubble_id: Option<NonZeroU64>,
-    ) -> Result<UploadToken, SaplingRemoteApiError> {
+    ) -> Result<Url, SaplingRemoteApiError> {
         let mut url = self.build_url(paths::UPLOAD_FILE)?;
         match item {
             AnyFileContentId::ContentId(id) => {
@@ -673,12 +675,24 @@ impl Client {
 
         {
             let mut query = url.query_pairs_mut();
-            query.append_pair("content_size", &raw_content.len().to_string());
+            query.append_pair("content_size", &content_size.to_string());
             if let Some(bubble_id) = bubble_id {
                 query.append_pair("bubble_id", &bubble_id.to_string());
             }
         }
 
+        Ok(url)
+    }
+
+    /// Upload a single file
+    async fn process_single_file_upload(
+        &self,
+        item: AnyFileContentId,
+        raw_content: Bytes,
+        bubble_id: Option<NonZeroU64>,
+    ) -> Result<UploadToken, SaplingRemoteApiError> {
+        let url = self.build_file_upload_url(item, raw_content.len() as u64, bubble_id)?;
+
         tracing::info!("Requesting upload for {url}");
 
         self.fetch_single::<UploadToken>({
@@ -688,6 +702,39 @@ impl Client {
         .await
     }
 
+    /// Upload a single file whose content arrives as a stream of chunks.
+    ///
+    /// The chunks are handed to the HTTP transport as a streaming request
+    /// body, so the content is never buffered in full on this side - which
+    /// matters for multi-GB files. Fails if the stream ends before producing
+    /// `content_size` bytes; data past `content_size` is not sent.
+    pub async fn process_single_file_upload_stream(
+        &self,
+        item: AnyFileContentId,
+        content_size: u64,
+        chunks: impl Stream<Item = Result<Vec<u8>, SaplingRemoteApiError>> + Send + 'static,
+        bubble_id: Option<NonZeroU64>,
+    ) -> Result<UploadToken, SaplingRemoteApiError> {
+        let url = self.build_file_upload_url(item, content_size, bubble_id)?;
+
+        tracing::info!("Requesting streamed upload for {url}");
+
+        let reader = ChunkStreamReader {
+            handle: tokio::runtime::Handle::current(),
+            chunks: chunks.boxed(),
+            pending: Vec::new(),
+            offset: 0,
+            bytes_read: 0,
+            expected_size: content_size,
+        };
+
+        self.fetch_single::<UploadToken>({
+            self.configure_request(paths::UPLOAD_FILE, self.inner.client.put(url.clone()))?
+                .streaming_body(StreamingBody::new(reader, content_size))
+        })
+        .await
+    }
+
     // the request isn't batched, batching should be done outside if needed
     async fn upload_changesets_attempt(
         &self,
@@ -1173,6 +1220,9 @@ impl Client {
             CloudWorkspacesRequest {
                 prefix: prefix.to_string(),
                 reponame: reponame.to_string(),
+                // The python layer does its own archived filtering (`cloud
+                // list --all`), so keep returning everything.
+                include_archived: true,
             },
         )
         .await
@@ -1916,6 +1966,7 @@ impl SaplingRemoteApi for Client {
         &self,
         data: UpdateReferencesParams,
     ) -> Result<ReferencesDataResponse, SaplingRemoteApiError> {
+        data.validate()?;
         self.with_retry(|this| this.cloud_update_references_attempt(data.clone()).boxed())
             .await
     }
@@ -1991,6 +2042,50 @@ impl SaplingRemoteApi for Client {
     }
 }
 
+/// Bridges an async stream of content chunks into the blocking `Read` that the
+/// HTTP transport's streaming request body expects. The transport calls `read`
+/// from a thread that isn't driving async tasks, so blocking on the stream
+/// there is safe.
+struct ChunkStreamReader {
+    handle: tokio::runtime::Handle,
+    chunks: BoxStream<'static, Result<Vec<u8>, SaplingRemoteApiError>>,
+    pending: Vec<u8>,
+    offset: usize,
+    bytes_read: u64,
+    expected_size: u64,
+}
+
+impl Read for ChunkStreamReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.offset == self.pending.len() {
+            match self.handle.block_on(self.chunks.try_next()) {
+                Ok(Some(chunk)) => {
+                    self.pending = chunk;
+                    self.offset = 0;
+                }
+                Ok(None) => {
+                    if self.bytes_read != self.expected_size {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::UnexpectedEof,
+                            format!(
+                                "content stream produced {} bytes, expected {}",
+                                self.bytes_read, self.expected_size
+                            ),
+                        ));
+                    }
+                    return Ok(0);
+                }
+                Err(err) => return Err(std::io::Error::new(std::io::ErrorKind::Other, err)),
+            }
+        }
+
+        let n = (&self.pending[self.offset..]).read(buf)?;
+        self.offset += n;
+        self.bytes_read += n as u64;
+        Ok(n)
+    }
+}
+
 /// Split up a collection of keys into batches of at most `batch_size`.
 fn split_into_batches<T>(
     keys: impl IntoIterator<Item = T>,
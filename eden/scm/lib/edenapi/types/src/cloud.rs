@@ -6,15 +6,21 @@
  */
 
 use std::collections::HashMap;
+use std::collections::HashSet;
 
+use anyhow::bail;
+use anyhow::Result;
 #[cfg(any(test, feature = "for-tests"))]
 use quickcheck_arbitrary_derive::Arbitrary;
 use serde_derive::Deserialize;
 use serde_derive::Serialize;
+use thiserror::Error;
 use type_macros::auto_wire;
 use types::HgId;
 
 use crate::ServerError;
+use crate::ToApi;
+use crate::ToWire;
 
 #[auto_wire]
 #[derive(Clone, Default, Debug, Deserialize, Serialize, Eq, PartialEq)]
@@ -32,6 +38,59 @@ pub struct WorkspaceData {
     pub timestamp: i64,
 }
 
+impl WorkspaceData {
+    /// Safe construction path: unlike `Default`, this guarantees the
+    /// workspace name and reponame are non-empty.
+    pub fn new(name: impl Into<String>, reponame: impl Into<String>) -> Result<Self> {
+        let name = name.into();
+        let reponame = reponame.into();
+        if name.is_empty() {
+            bail!("workspace name must be non-empty");
+        }
+        if reponame.is_empty() {
+            bail!("workspace reponame must be non-empty");
+        }
+        Ok(Self {
+            name,
+            reponame,
+            ..Default::default()
+        })
+    }
+
+    pub fn with_version(mut self, version: u64) -> Self {
+        self.version = version;
+        self
+    }
+
+    pub fn with_archived(mut self, archived: bool) -> Self {
+        self.archived = archived;
+        self
+    }
+
+    pub fn with_timestamp(mut self, timestamp: i64) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    /// Whether this freshly fetched workspace has moved past the given
+    /// version, i.e. an update built against `version` would be rejected
+    /// server-side as a lost update.
+    pub fn is_newer_than(&self, version: u64) -> bool {
+        self.version > version
+    }
+}
+
+/// A client tried to build an update against a version older than the
+/// workspace's current one. Retrying from the fresh version is required.
+#[derive(Clone, Debug, Eq, PartialEq, Error)]
+#[error(
+    "workspace is at version {current}, refusing to build an update against older version {proposed}"
+)]
+pub struct VersionConflict {
+    pub current: u64,
+    pub proposed: u64,
+}
+
 #[auto_wire]
 #[derive(Clone, Default, Debug, Deserialize, Serialize, Eq, PartialEq)]
 #[cfg_attr(any(test, feature = "for-tests"), derive(Arbitrary))]
@@ -50,6 +109,10 @@ pub struct CloudWorkspacesRequest {
     pub prefix: String,
     #[id(1)]
     pub reponame: String,
+    /// When false (the default, and what old clients that don't send the
+    /// field get), archived workspaces are filtered out of the response.
+    #[id(2)]
+    pub include_archived: bool,
 }
 
 #[auto_wire]
@@ -82,6 +145,180 @@ pub struct UpdateReferencesParams {
     pub client_info: Option<ClientInfo>,
 }
 
+impl ReferencesData {
+    /// Compare this snapshot against a newer one and build the
+    /// `UpdateReferencesParams` that would bring this one up to date. `None`
+    /// fields are treated the same as empty collections; the optional
+    /// remote-bookmark vectors in the result are `None` when nothing changed.
+    pub fn diff_to_update(
+        &self,
+        new: &ReferencesData,
+        workspace: impl Into<String>,
+        reponame: impl Into<String>,
+    ) -> UpdateReferencesParams {
+        fn as_slice<T>(v: &Option<Vec<T>>) -> &[T] {
+            v.as_deref().unwrap_or_default()
+        }
+
+        let old_heads: HashSet<&HgId> = as_slice(&self.heads).iter().collect();
+        let new_heads_set: HashSet<&HgId> = as_slice(&new.heads).iter().collect();
+        let new_heads = as_slice(&new.heads)
+            .iter()
+            .filter(|head| !old_heads.contains(*head))
+            .cloned()
+            .collect();
+        let removed_heads = as_slice(&self.heads)
+            .iter()
+            .filter(|head| !new_heads_set.contains(*head))
+            .cloned()
+            .collect();
+
+        let empty_bookmarks = HashMap::new();
+        let old_bookmarks = self.bookmarks.as_ref().unwrap_or(&empty_bookmarks);
+        let new_bookmarks = new.bookmarks.as_ref().unwrap_or(&empty_bookmarks);
+        let updated_bookmarks = new_bookmarks
+            .iter()
+            .filter(|(name, node)| old_bookmarks.get(*name) != Some(node))
+            .map(|(name, node)| (name.clone(), node.clone()))
+            .collect();
+        let removed_bookmarks = old_bookmarks
+            .keys()
+            .filter(|name| !new_bookmarks.contains_key(*name))
+            .cloned()
+            .collect();
+
+        let old_remote: HashMap<(&str, &str), &Option<HgId>> = as_slice(&self.remote_bookmarks)
+            .iter()
+            .map(|rb| ((rb.remote.as_str(), rb.name.as_str()), &rb.node))
+            .collect();
+        let new_remote: HashSet<(&str, &str)> = as_slice(&new.remote_bookmarks)
+            .iter()
+            .map(|rb| (rb.remote.as_str(), rb.name.as_str()))
+            .collect();
+        let updated_remote_bookmarks: Vec<RemoteBookmark> = as_slice(&new.remote_bookmarks)
+            .iter()
+            .filter(|rb| {
+                old_remote.get(&(rb.remote.as_str(), rb.name.as_str())) != Some(&&rb.node)
+            })
+            .cloned()
+            .collect();
+        let removed_remote_bookmarks: Vec<RemoteBookmark> = as_slice(&self.remote_bookmarks)
+            .iter()
+            .filter(|rb| !new_remote.contains(&(rb.remote.as_str(), rb.name.as_str())))
+            .cloned()
+            .collect();
+
+        let old_snapshots: HashSet<&HgId> = as_slice(&self.snapshots).iter().collect();
+        let new_snapshots_set: HashSet<&HgId> = as_slice(&new.snapshots).iter().collect();
+        let new_snapshots = as_slice(&new.snapshots)
+            .iter()
+            .filter(|snapshot| !old_snapshots.contains(*snapshot))
+            .cloned()
+            .collect();
+        let removed_snapshots = as_slice(&self.snapshots)
+            .iter()
+            .filter(|snapshot| !new_snapshots_set.contains(*snapshot))
+            .cloned()
+            .collect();
+
+        UpdateReferencesParams {
+            workspace: workspace.into(),
+            reponame: reponame.into(),
+            version: new.version,
+            removed_heads,
+            new_heads,
+            updated_bookmarks,
+            removed_bookmarks,
+            updated_remote_bookmarks: if updated_remote_bookmarks.is_empty() {
+                None
+            } else {
+                Some(updated_remote_bookmarks)
+            },
+            removed_remote_bookmarks: if removed_remote_bookmarks.is_empty() {
+                None
+            } else {
+                Some(removed_remote_bookmarks)
+            },
+            new_snapshots,
+            removed_snapshots,
+            client_info: None,
+        }
+    }
+
+    /// Drop the subsets of this data that `filters` doesn't ask for, leaving
+    /// the corresponding fields `None`. An empty filter list means
+    /// "everything", so old clients - which never send filters - keep getting
+    /// the full response.
+    pub fn apply_filters(&mut self, filters: &[GetReferencesFilter]) {
+        if filters.is_empty() {
+            return;
+        }
+        if !filters.contains(&GetReferencesFilter::Heads) {
+            self.heads = None;
+            self.heads_dates = None;
+        }
+        if !filters.contains(&GetReferencesFilter::Bookmarks) {
+            self.bookmarks = None;
+        }
+        if !filters.contains(&GetReferencesFilter::RemoteBookmarks) {
+            self.remote_bookmarks = None;
+        }
+        if !filters.contains(&GetReferencesFilter::Snapshots) {
+            self.snapshots = None;
+        }
+    }
+}
+
+impl UpdateReferencesParams {
+    /// Check this update's `version` against a freshly fetched workspace and
+    /// fail early with a [`VersionConflict`] if the workspace has already
+    /// moved past it, instead of waiting for the server to reject the update.
+    pub fn with_expected_version(
+        self,
+        workspace: &WorkspaceData,
+    ) -> Result<Self, VersionConflict> {
+        if workspace.is_newer_than(self.version) {
+            return Err(VersionConflict {
+                current: workspace.version,
+                proposed: self.version,
+            });
+        }
+        Ok(self)
+    }
+
+    /// Reject updates the server can only interpret ambiguously: the same id
+    /// listed as both added and removed, or a workspace that isn't identified.
+    /// Called client-side before the update is sent.
+    pub fn validate(&self) -> Result<()> {
+        if self.workspace.is_empty() {
+            bail!("workspace name must not be empty");
+        }
+        if self.reponame.is_empty() {
+            bail!("reponame must not be empty");
+        }
+
+        let new_heads: HashSet<&HgId> = self.new_heads.iter().collect();
+        if let Some(head) = self
+            .removed_heads
+            .iter()
+            .find(|head| new_heads.contains(head))
+        {
+            bail!("head {} is listed as both added and removed", head);
+        }
+
+        let new_snapshots: HashSet<&HgId> = self.new_snapshots.iter().collect();
+        if let Some(snapshot) = self
+            .removed_snapshots
+            .iter()
+            .find(|snapshot| new_snapshots.contains(snapshot))
+        {
+            bail!("snapshot {} is listed as both added and removed", snapshot);
+        }
+
+        Ok(())
+    }
+}
+
 #[auto_wire]
 #[derive(Clone, Default, Debug, Deserialize, Serialize, Eq, PartialEq)]
 #[cfg_attr(any(test, feature = "for-tests"), derive(Arbitrary))]
@@ -94,6 +331,35 @@ pub struct GetReferencesParams {
     pub version: u64,
     #[id(3)]
     pub client_info: Option<ClientInfo>,
+    /// Which subsets of `ReferencesData` the client wants populated; the
+    /// server leaves the other `Option` fields `None` (see
+    /// [`ReferencesData::apply_filters`]). Both `None` and `Some(vec![])`
+    /// explicitly mean "everything": old clients never send the field, and
+    /// servers that predate filtering return everything regardless, so the
+    /// client must treat the filter as an optimization, not a guarantee.
+    #[id(4)]
+    pub filters: Option<Vec<GetReferencesFilter>>,
+}
+
+#[auto_wire]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "for-tests"), derive(Arbitrary))]
+pub enum GetReferencesFilter {
+    #[id(1)]
+    Heads,
+    #[id(2)]
+    Bookmarks,
+    #[id(3)]
+    RemoteBookmarks,
+    #[id(4)]
+    Snapshots,
+}
+
+// Wire requires a default value, shouldn't be used
+impl Default for GetReferencesFilter {
+    fn default() -> Self {
+        Self::Heads
+    }
 }
 
 #[auto_wire]
@@ -128,6 +394,57 @@ pub struct RemoteBookmark {
     pub node: Option<HgId>,
 }
 
+impl RemoteBookmark {
+    /// The `remote/name` identity of the bookmark, regardless of which node
+    /// it currently points to.
+    pub fn full_name(&self) -> String {
+        format!("{}/{}", self.remote, self.name)
+    }
+}
+
+/// Reconcile a remote bookmark list: apply `updates` keyed by `full_name()`
+/// (moving existing bookmarks or appending new ones) and drop `removals`.
+/// The input order of `existing` is preserved, with new bookmarks appended in
+/// `updates` order.
+pub fn merge_remote_bookmarks(
+    existing: &[RemoteBookmark],
+    updates: &[RemoteBookmark],
+    removals: &[RemoteBookmark],
+) -> Vec<RemoteBookmark> {
+    let mut updates_by_name: HashMap<String, &RemoteBookmark> = updates
+        .iter()
+        .map(|bookmark| (bookmark.full_name(), bookmark))
+        .collect();
+    let removed_names: HashSet<String> = removals
+        .iter()
+        .map(|bookmark| bookmark.full_name())
+        .collect();
+
+    let mut merged = Vec::with_capacity(existing.len() + updates.len());
+    for bookmark in existing {
+        let full_name = bookmark.full_name();
+        if removed_names.contains(&full_name) {
+            continue;
+        }
+        match updates_by_name.remove(&full_name) {
+            Some(updated) => merged.push(updated.clone()),
+            None => merged.push(bookmark.clone()),
+        }
+    }
+    // Updates that didn't match an existing bookmark are new. Remove each
+    // identity as it is emitted so a duplicated update only appears once,
+    // with the last occurrence winning like in the loop above.
+    for bookmark in updates {
+        let full_name = bookmark.full_name();
+        if let Some(updated) = updates_by_name.remove(&full_name) {
+            if !removed_names.contains(&full_name) {
+                merged.push(updated.clone());
+            }
+        }
+    }
+    merged
+}
+
 #[auto_wire]
 #[derive(Clone, Default, Debug, Deserialize, Serialize, Eq, PartialEq)]
 #[cfg_attr(any(test, feature = "for-tests"), derive(Arbitrary))]
@@ -177,6 +494,11 @@ pub struct GetSmartlogParams {
     pub reponame: String,
     #[id(2)]
     pub flags: Vec<GetSmartlogFlag>,
+    /// When set, the server filters out `SmartlogNode`s whose date is older
+    /// than this timestamp. Defaults to `None` (no pruning), which keeps the
+    /// wire format compatible with older clients.
+    #[id(3)]
+    pub since_timestamp: Option<i64>,
 }
 
 #[auto_wire]
@@ -232,6 +554,19 @@ pub struct SmartlogData {
     pub timestamp: Option<i64>,
 }
 
+impl SmartlogData {
+    /// Serialize to a compact binary blob via the wire representation, much
+    /// smaller than JSON. Intended for caching smartlog responses locally.
+    pub fn to_compact_bytes(&self) -> Result<Vec<u8>> {
+        Ok(mincode::serialize(&self.clone().to_wire())?)
+    }
+
+    /// Inverse of [`Self::to_compact_bytes`].
+    pub fn from_compact_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(mincode::deserialize::<WireSmartlogData>(bytes)?.to_api()?)
+    }
+}
+
 #[auto_wire]
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 #[cfg_attr(any(test, feature = "for-tests"), derive(Arbitrary))]
@@ -401,3 +736,292 @@ pub struct RollbackWorkspaceResponse {
     #[no_default]
     pub data: Result<String, ServerError>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_workspace_data_new() -> Result<()> {
+        let data = WorkspaceData::new("user/test/default", "fbsource")?
+            .with_version(5)
+            .with_archived(true)
+            .with_timestamp(1234567890);
+        assert_eq!(
+            data,
+            WorkspaceData {
+                name: "user/test/default".to_string(),
+                reponame: "fbsource".to_string(),
+                version: 5,
+                archived: true,
+                timestamp: 1234567890,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_workspace_data_new_rejects_empty() {
+        assert!(WorkspaceData::new("", "fbsource").is_err());
+        assert!(WorkspaceData::new("user/test/default", "").is_err());
+    }
+
+    #[test]
+    fn test_with_expected_version() {
+        let workspace = WorkspaceData::new("user/test/default", "fbsource")
+            .unwrap()
+            .with_version(3);
+        assert!(workspace.is_newer_than(2));
+        assert!(!workspace.is_newer_than(3));
+
+        let update = UpdateReferencesParams {
+            version: 3,
+            ..Default::default()
+        };
+        assert!(update.clone().with_expected_version(&workspace).is_ok());
+
+        let stale = UpdateReferencesParams {
+            version: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            stale.with_expected_version(&workspace),
+            Err(VersionConflict {
+                current: 3,
+                proposed: 2
+            })
+        );
+    }
+
+    fn hgid(byte: u8) -> HgId {
+        HgId::from_byte_array([byte; 20])
+    }
+
+    fn valid_update() -> UpdateReferencesParams {
+        UpdateReferencesParams {
+            workspace: "user/test/default".to_string(),
+            reponame: "fbsource".to_string(),
+            new_heads: vec![hgid(1)],
+            removed_heads: vec![hgid(2)],
+            new_snapshots: vec![hgid(3)],
+            removed_snapshots: vec![hgid(4)],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_validate_ok() {
+        assert!(valid_update().validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_empty_workspace_or_reponame() {
+        let mut update = valid_update();
+        update.workspace = String::new();
+        assert!(update.validate().is_err());
+
+        let mut update = valid_update();
+        update.reponame = String::new();
+        assert!(update.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_overlapping_heads() {
+        let mut update = valid_update();
+        update.removed_heads.push(hgid(1));
+        let err = update.validate().unwrap_err();
+        assert!(err.to_string().contains("head"), "{}", err);
+    }
+
+    #[test]
+    fn test_validate_overlapping_snapshots() {
+        let mut update = valid_update();
+        update.removed_snapshots.push(hgid(3));
+        let err = update.validate().unwrap_err();
+        assert!(err.to_string().contains("snapshot"), "{}", err);
+    }
+
+    fn remote_bookmark(name: &str, node: Option<HgId>) -> RemoteBookmark {
+        RemoteBookmark {
+            remote: "remote".to_string(),
+            name: name.to_string(),
+            node,
+        }
+    }
+
+    #[test]
+    fn test_smartlog_data_compact_roundtrip() -> Result<()> {
+        let data = SmartlogData {
+            nodes: vec![SmartlogNode {
+                node: hgid(1),
+                phase: "draft".to_string(),
+                author: "test".to_string(),
+                date: 1234567890,
+                message: "commit message".to_string(),
+                parents: vec![hgid(2)],
+                bookmarks: vec!["local".to_string()],
+                remote_bookmarks: Some(vec![remote_bookmark("main", Some(hgid(3)))]),
+            }],
+            version: Some(4),
+            timestamp: Some(1234567890),
+        };
+        let bytes = data.to_compact_bytes()?;
+        assert_eq!(SmartlogData::from_compact_bytes(&bytes)?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_remote_bookmarks() {
+        let existing = vec![
+            remote_bookmark("main", Some(hgid(1))),
+            remote_bookmark("stable", Some(hgid(2))),
+            remote_bookmark("gone", Some(hgid(3))),
+        ];
+        let updates = vec![
+            remote_bookmark("main", Some(hgid(4))),
+            remote_bookmark("new", Some(hgid(5))),
+        ];
+        let removals = vec![remote_bookmark("gone", None)];
+
+        let merged = merge_remote_bookmarks(&existing, &updates, &removals);
+        assert_eq!(
+            merged,
+            vec![
+                remote_bookmark("main", Some(hgid(4))),
+                remote_bookmark("stable", Some(hgid(2))),
+                remote_bookmark("new", Some(hgid(5))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_remote_bookmarks_duplicate_update() {
+        let existing = vec![remote_bookmark("main", Some(hgid(1)))];
+        let updates = vec![
+            remote_bookmark("new", Some(hgid(2))),
+            remote_bookmark("new", Some(hgid(3))),
+        ];
+
+        // A duplicated identity is emitted once, with the last occurrence
+        // winning.
+        let merged = merge_remote_bookmarks(&existing, &updates, &[]);
+        assert_eq!(
+            merged,
+            vec![
+                remote_bookmark("main", Some(hgid(1))),
+                remote_bookmark("new", Some(hgid(3))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_remote_bookmarks_removal_wins() {
+        let existing = vec![remote_bookmark("main", Some(hgid(1)))];
+        let updates = vec![remote_bookmark("main", Some(hgid(2)))];
+        let removals = vec![remote_bookmark("main", None)];
+        assert!(merge_remote_bookmarks(&existing, &updates, &removals).is_empty());
+    }
+
+    #[test]
+    fn test_apply_filters() {
+        let full = ReferencesData {
+            version: 1,
+            heads: Some(vec![hgid(1)]),
+            bookmarks: Some(HashMap::from([("book".to_string(), hgid(2))])),
+            heads_dates: Some(HashMap::from([(hgid(1), 1234567890)])),
+            remote_bookmarks: Some(vec![remote_bookmark("main", Some(hgid(3)))]),
+            snapshots: Some(vec![hgid(4)]),
+            timestamp: Some(1234567890),
+        };
+
+        // An empty filter list means "everything".
+        let mut data = full.clone();
+        data.apply_filters(&[]);
+        assert_eq!(data, full);
+
+        let mut data = full.clone();
+        data.apply_filters(&[GetReferencesFilter::Heads, GetReferencesFilter::Snapshots]);
+        assert_eq!(data.heads, full.heads);
+        assert_eq!(data.heads_dates, full.heads_dates);
+        assert_eq!(data.snapshots, full.snapshots);
+        assert_eq!(data.bookmarks, None);
+        assert_eq!(data.remote_bookmarks, None);
+        // Version and timestamp are always kept.
+        assert_eq!(data.version, full.version);
+        assert_eq!(data.timestamp, full.timestamp);
+    }
+
+    #[test]
+    fn test_diff_to_update() {
+        let old = ReferencesData {
+            version: 1,
+            heads: Some(vec![hgid(1), hgid(2)]),
+            bookmarks: Some(HashMap::from([
+                ("kept".to_string(), hgid(1)),
+                ("moved".to_string(), hgid(2)),
+                ("deleted".to_string(), hgid(3)),
+            ])),
+            heads_dates: None,
+            remote_bookmarks: Some(vec![
+                remote_bookmark("main", Some(hgid(1))),
+                remote_bookmark("gone", Some(hgid(2))),
+            ]),
+            snapshots: None,
+            timestamp: None,
+        };
+        let new = ReferencesData {
+            version: 2,
+            heads: Some(vec![hgid(2), hgid(3)]),
+            bookmarks: Some(HashMap::from([
+                ("kept".to_string(), hgid(1)),
+                ("moved".to_string(), hgid(4)),
+                ("added".to_string(), hgid(5)),
+            ])),
+            heads_dates: None,
+            remote_bookmarks: Some(vec![remote_bookmark("main", Some(hgid(6)))]),
+            snapshots: Some(vec![hgid(7)]),
+            timestamp: None,
+        };
+
+        let update = old.diff_to_update(&new, "workspace", "repo");
+        assert_eq!(update.workspace, "workspace");
+        assert_eq!(update.reponame, "repo");
+        assert_eq!(update.version, 2);
+        assert_eq!(update.new_heads, vec![hgid(3)]);
+        assert_eq!(update.removed_heads, vec![hgid(1)]);
+        assert_eq!(
+            update.updated_bookmarks,
+            HashMap::from([("moved".to_string(), hgid(4)), ("added".to_string(), hgid(5))])
+        );
+        let mut removed_bookmarks = update.removed_bookmarks.clone();
+        removed_bookmarks.sort();
+        assert_eq!(removed_bookmarks, vec!["deleted".to_string()]);
+        assert_eq!(
+            update.updated_remote_bookmarks,
+            Some(vec![remote_bookmark("main", Some(hgid(6)))])
+        );
+        assert_eq!(
+            update.removed_remote_bookmarks,
+            Some(vec![remote_bookmark("gone", Some(hgid(2)))])
+        );
+        assert_eq!(update.new_snapshots, vec![hgid(7)]);
+        assert!(update.removed_snapshots.is_empty());
+    }
+
+    #[test]
+    fn test_diff_to_update_none_equals_empty() {
+        let old = ReferencesData::default();
+        let new = ReferencesData {
+            heads: Some(vec![]),
+            bookmarks: Some(HashMap::new()),
+            ..Default::default()
+        };
+        let update = old.diff_to_update(&new, "workspace", "repo");
+        assert!(update.new_heads.is_empty());
+        assert!(update.removed_heads.is_empty());
+        assert!(update.updated_bookmarks.is_empty());
+        assert!(update.removed_bookmarks.is_empty());
+        assert_eq!(update.updated_remote_bookmarks, None);
+        assert_eq!(update.removed_remote_bookmarks, None);
+    }
+}
@@ -81,10 +81,12 @@ pub use crate::cloud::CloudShareWorkspaceRequest;
 pub use crate::cloud::CloudShareWorkspaceResponse;
 pub use crate::cloud::CloudWorkspaceRequest;
 pub use crate::cloud::CloudWorkspacesRequest;
+pub use crate::cloud::GetReferencesFilter;
 pub use crate::cloud::GetReferencesParams;
 pub use crate::cloud::GetSmartlogByVersionParams;
 pub use crate::cloud::GetSmartlogFlag;
 pub use crate::cloud::GetSmartlogParams;
+pub use crate::cloud::merge_remote_bookmarks;
 pub use crate::cloud::HistoricalVersion;
 pub use crate::cloud::HistoricalVersionsData;
 pub use crate::cloud::HistoricalVersionsParams;
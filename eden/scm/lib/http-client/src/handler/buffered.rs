@@ -106,6 +106,19 @@ impl Handler for Buffered {
                 .event_listeners
                 .trigger_download_bytes(self.request_context(), sent);
             sent
+        } else if let Some(body) = &self.request_context.streaming_body {
+            let sent = match body.read(data) {
+                Ok(sent) => sent,
+                Err(err) => {
+                    tracing::error!(?err, "failed to read streaming request body");
+                    return Err(ReadError::Abort);
+                }
+            };
+            self.bytes_sent += sent;
+            self.request_context
+                .event_listeners
+                .trigger_download_bytes(self.request_context(), sent);
+            sent
         } else {
             0
         })
@@ -74,6 +74,7 @@ pub use request::Request;
 pub use request::RequestContext;
 pub use request::RequestInfo;
 pub use request::StreamRequest;
+pub use request::StreamingBody;
 pub use response::AsyncBody;
 pub use response::AsyncResponse;
 pub use response::Response;
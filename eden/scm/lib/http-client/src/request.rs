@@ -14,6 +14,7 @@ use std::path::Path;
 use std::path::PathBuf;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering::AcqRel;
+use std::sync::Arc;
 use std::time::Duration;
 use std::time::SystemTime;
 
@@ -162,9 +163,48 @@ impl RequestInfo {
 pub struct RequestContext {
     pub(crate) info: RequestInfo,
     pub(crate) body: Option<Vec<u8>>,
+    pub(crate) streaming_body: Option<StreamingBody>,
     pub(crate) event_listeners: RequestEventListeners,
 }
 
+/// A request body produced incrementally by a reader instead of being buffered
+/// in memory up front. The total size must be known so it can be reported to
+/// the server. The reader is shared behind a mutex so that `Request` stays
+/// `Clone`; clones share the reader's position, so a request with a streaming
+/// body can only be sent once. The transfer fails if the server requires a
+/// rewind (e.g. on a redirect), since the reader can't seek.
+#[derive(Clone)]
+pub struct StreamingBody {
+    reader: Arc<Mutex<dyn Read + Send>>,
+    size: u64,
+}
+
+impl StreamingBody {
+    pub fn new(reader: impl Read + Send + 'static, size: u64) -> Self {
+        Self {
+            reader: Arc::new(Mutex::new(reader)),
+            size,
+        }
+    }
+
+    /// Total number of bytes the reader will produce.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub(crate) fn read(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.reader.lock().read(buf)
+    }
+}
+
+impl fmt::Debug for StreamingBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamingBody")
+            .field("size", &self.size)
+            .finish()
+    }
+}
+
 /// Identity of a request.
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct RequestId(usize);
@@ -214,6 +254,7 @@ impl RequestContext {
         Self {
             info: RequestInfo { id, url, method },
             body: None,
+            streaming_body: None,
             event_listeners: Default::default(),
         }
     }
@@ -243,13 +284,26 @@ impl RequestContext {
 
     /// Set the data to be uploaded in the request body.
     pub fn body<B: Into<Vec<u8>>>(mut self, data: B) -> Self {
-        self.body = Some(data.into());
+        self.set_body(data);
         self
     }
 
     /// Set the data to be uploaded in the request body.
     pub fn set_body<B: Into<Vec<u8>>>(&mut self, data: B) {
         self.body = Some(data.into());
+        self.streaming_body = None;
+    }
+
+    /// Set a reader producing the data to be uploaded in the request body.
+    pub fn streaming_body(mut self, body: StreamingBody) -> Self {
+        self.set_streaming_body(body);
+        self
+    }
+
+    /// Set a reader producing the data to be uploaded in the request body.
+    pub fn set_streaming_body(&mut self, body: StreamingBody) {
+        self.streaming_body = Some(body);
+        self.body = None;
     }
 
     /// Provide a way to register event callbacks.
@@ -319,6 +373,18 @@ impl Request {
         self
     }
 
+    /// Set a reader producing the data to be uploaded in the request body.
+    pub fn streaming_body(mut self, body: StreamingBody) -> Self {
+        self.set_streaming_body(body);
+        self
+    }
+
+    /// Set a reader producing the data to be uploaded in the request body.
+    pub fn set_streaming_body(&mut self, body: StreamingBody) -> &mut Self {
+        self.ctx.set_streaming_body(body);
+        self
+    }
+
     /// Set the http version for this request. Defaults to HTTP/2.
     pub fn http_version(mut self, version: HttpVersion) -> Self {
         self.set_http_version(version);
@@ -661,7 +727,12 @@ impl Request {
             .read()
             .trigger_new_request(&mut self);
 
-        let body_size = self.ctx.body.as_ref().map(|body| body.len() as u64);
+        let body_size = self
+            .ctx
+            .body
+            .as_ref()
+            .map(|body| body.len() as u64)
+            .or_else(|| self.ctx.streaming_body.as_ref().map(|body| body.size()));
         let mut url = self.ctx.url().clone();
         if self.auth_proxy_socket_path.is_some() {
             url.set_scheme("http")
@@ -1113,6 +1184,34 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_put_streaming_body() -> Result<()> {
+        let body = "Hello, world!";
+
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock("PUT", "/test")
+            .with_status(201)
+            .match_body(Matcher::Exact(body.into()))
+            .create();
+
+        let client = HttpClient::new();
+
+        let url = Url::parse(&server.url())?.join("test")?;
+        let res = client
+            .put(url)
+            .streaming_body(StreamingBody::new(
+                std::io::Cursor::new(body.as_bytes()),
+                body.len() as u64,
+            ))
+            .send()?;
+
+        mock.assert();
+        assert_eq!(res.head.status, StatusCode::CREATED);
+
+        Ok(())
+    }
+
     #[test]
     fn test_put() -> Result<()> {
         let body = "Hello, world!";
@@ -0,0 +1,2540 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::bail;
+use anyhow::format_err;
+use anyhow::Result;
+use async_runtime::spawn_blocking;
+use configmodel::convert::ByteCount;
+use configmodel::Config;
+use configmodel::ConfigExt;
+use minibytes::Bytes;
+use parking_lot::Mutex;
+use storemodel::SerializationFormat;
+use types::HgId;
+use types::Key;
+use types::Parents;
+
+use crate::datastore::Delta;
+use crate::datastore::HgIdDataStore;
+use crate::datastore::HgIdMutableDeltaStore;
+use crate::datastore::Metadata;
+use crate::datastore::RemoteDataStore;
+use crate::datastore::StoreResult;
+use crate::indexedlogdatastore::IndexedLogHgIdDataStore;
+use crate::indexedlogdatastore::IndexedLogHgIdDataStoreConfig;
+use crate::indexedlogutil::StoreType;
+use crate::lfs::with_prefetch_deadline;
+use crate::lfs::LfsStore;
+use crate::localstore::LocalStore;
+use crate::remotestore::HgIdRemoteStore;
+use crate::scmstore::builder::plan_cache_buster;
+use crate::scmstore::builder::CacheBusterPlan;
+use crate::types::StoreKey;
+use crate::uniondatastore::UnionHgIdDataStore;
+use crate::util::get_cache_path;
+use crate::util::get_indexedlogdatastore_path;
+use crate::util::get_local_path;
+use crate::ToKeys;
+
+/// A `ContentStore` aggregate all the local and remote stores and expose them as one. Both local and
+/// remote stores can be queried and accessed via the `HgIdDataStore` trait. The local store can also
+/// be written to via the `HgIdMutableDeltaStore` trait, this is intended to be used to store local
+/// commit data.
+pub struct ContentStore {
+    datastore: UnionHgIdDataStore<Arc<dyn HgIdDataStore>>,
+    /// The subset of `datastore` that can be queried without hitting the network.
+    local_datastore: UnionHgIdDataStore<Arc<dyn HgIdDataStore>>,
+    /// The same stores as `datastore`, in the same order, tagged with their provenance.
+    sourced_datastores: Vec<(StoreSource, Arc<dyn HgIdDataStore>)>,
+    local_mutabledatastore: Option<Arc<IndexedLogHgIdDataStore>>,
+    shared_mutabledatastore: Arc<IndexedLogHgIdDataStore>,
+    remote_store: Option<Arc<dyn RemoteDataStore>>,
+
+    shared_lfs_store: Option<Arc<LfsStore>>,
+    local_lfs_store: Option<Arc<LfsStore>>,
+
+    local_path: Option<PathBuf>,
+    cache_path: Option<PathBuf>,
+    extstored_policy: ExtStoredPolicy,
+    read_only: bool,
+
+    /// Builder inputs retained so `with_suffix` can construct a sibling store.
+    /// `local_path` above already has the suffix applied, so the original root
+    /// is kept separately.
+    base_local_path: Option<PathBuf>,
+    remotestores: Vec<Arc<dyn HgIdRemoteStore>>,
+    format: SerializationFormat,
+    cache_on_read: bool,
+}
+
+/// Which underlying store satisfied a request, as reported by
+/// `ContentStore::get_with_source`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StoreSource {
+    /// The in-memory overlay seeded via `ContentStoreBuilder::memory_overlay`.
+    Memory,
+    /// The local (permanent) IndexedLog.
+    LocalIndexedLog,
+    /// The shared (rotated) IndexedLog cache.
+    SharedIndexedLog,
+    /// One of the LFS stores, local or shared.
+    Lfs,
+    /// The remote store. Repeating the same request will report the shared cache instead,
+    /// as remote fetches write through it.
+    Remote,
+}
+
+/// What to do with entries that carry the LFS flag, ie: entries whose stored content is an
+/// LFS pointer instead of the actual blob.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExtStoredPolicy {
+    /// Return the raw pointer content.
+    Use,
+    /// Treat flagged entries as missing.
+    Ignore,
+}
+
+/// Health of one of the subsystems a `ContentStore` is comprised of.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SubsystemHealth {
+    /// The subsystem is either functioning normally, or isn't configured.
+    Ok,
+    /// The subsystem is usable, but not functioning normally.
+    Degraded(String),
+    /// The subsystem is unusable.
+    Failed(String),
+}
+
+impl SubsystemHealth {
+    pub fn is_ok(&self) -> bool {
+        matches!(self, SubsystemHealth::Ok)
+    }
+
+    pub fn is_failed(&self) -> bool {
+        matches!(self, SubsystemHealth::Failed(_))
+    }
+}
+
+/// On-disk footprint of a `ContentStore`, as returned by `ContentStore::disk_usage`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DiskUsage {
+    /// On-disk bytes of the local (permanent) indexedlog. Zero for stores built
+    /// without a local store.
+    pub local_bytes: u64,
+    /// Approximate entry count of the local indexedlog.
+    pub local_entries: u64,
+    /// On-disk bytes of the shared (rotated) cache.
+    pub shared_bytes: u64,
+    /// Approximate entry count of the shared cache.
+    pub shared_entries: u64,
+}
+
+impl DiskUsage {
+    pub fn total_bytes(&self) -> u64 {
+        self.local_bytes + self.shared_bytes
+    }
+
+    pub fn total_entries(&self) -> u64 {
+        self.local_entries + self.shared_entries
+    }
+}
+
+/// Per-subsystem health of a `ContentStore`, as returned by `ContentStore::health_check`.
+#[derive(Clone, Debug)]
+pub struct HealthReport {
+    /// The local (permanent) indexedlog.
+    pub local_store: SubsystemHealth,
+    /// The shared (rotated) indexedlog cache.
+    pub shared_store: SubsystemHealth,
+    /// Writability of the shared cache directory.
+    pub cache_path: SubsystemHealth,
+    /// The local and shared LFS stores.
+    pub lfs: SubsystemHealth,
+    /// Reachability of the remote store.
+    pub remote: SubsystemHealth,
+}
+
+impl HealthReport {
+    /// Whether the store is usable. A `Degraded` subsystem doesn't prevent the store from
+    /// being used, a `Failed` one does.
+    pub fn is_healthy(&self) -> bool {
+        !self.local_store.is_failed()
+            && !self.shared_store.is_failed()
+            && !self.cache_path.is_failed()
+            && !self.lfs.is_failed()
+            && !self.remote.is_failed()
+    }
+}
+
+impl ContentStore {
+    pub fn new(local_path: impl AsRef<Path>, config: &dyn Config) -> Result<Self> {
+        ContentStoreBuilder::new(config)
+            .local_path(&local_path)
+            .build()
+    }
+
+    /// Probe the health of all the subsystems this `ContentStore` is comprised of.
+    ///
+    /// This is meant to power readiness endpoints for services embedding the store: all the
+    /// probes are lightweight and non-destructive, no full verification of the underlying
+    /// stores is performed. Subsystems that aren't configured report `SubsystemHealth::Ok`.
+    pub fn health_check(&self) -> HealthReport {
+        let local_store = match self.local_mutabledatastore.as_ref() {
+            Some(local) => probe_refresh(local.as_ref()),
+            None => SubsystemHealth::Ok,
+        };
+
+        let shared_store = probe_refresh(self.shared_mutabledatastore.as_ref());
+
+        let cache_path = match self.cache_path.as_ref() {
+            Some(cache_path) => match probe_writable(cache_path) {
+                Ok(()) => SubsystemHealth::Ok,
+                Err(e) => SubsystemHealth::Failed(format!(
+                    "cache path {:?} is not writable: {:#}",
+                    cache_path, e
+                )),
+            },
+            None => SubsystemHealth::Ok,
+        };
+
+        let lfs = match (self.local_lfs_store.as_ref(), self.shared_lfs_store.as_ref()) {
+            (None, None) => SubsystemHealth::Ok,
+            (local, shared) => {
+                let result = local
+                    .map(|store| store.refresh())
+                    .transpose()
+                    .and_then(|_| shared.map(|store| store.refresh()).transpose());
+                match result {
+                    Ok(_) => SubsystemHealth::Ok,
+                    Err(e) => SubsystemHealth::Failed(format!("lfs store: {:#}", e)),
+                }
+            }
+        };
+
+        // An empty prefetch exercises the remote store machinery without fetching
+        // anything, failures indicate that the remote isn't reachable.
+        let remote = match self.remote_store.as_ref() {
+            Some(remote) => match remote.prefetch(&[]) {
+                Ok(_) => SubsystemHealth::Ok,
+                Err(e) => SubsystemHealth::Degraded(format!("remote store: {:#}", e)),
+            },
+            None => SubsystemHealth::Ok,
+        };
+
+        HealthReport {
+            local_store,
+            shared_store,
+            cache_path,
+            lfs,
+            remote,
+        }
+    }
+
+    /// Fetch the content of multiple keys at once.
+    ///
+    /// Whereas `get` walks all the underlying stores for each key, this walks each underlying
+    /// store once for the whole set of keys, only forwarding the still-missing keys to the next
+    /// store, and does at most one remote round-trip for the keys that aren't present locally.
+    /// Results are returned in the same order as `keys`, keys that are nowhere to be found are
+    /// reported as `StoreResult::NotFound` instead of failing the whole batch.
+    pub fn get_batch(&self, keys: &[StoreKey]) -> Result<Vec<StoreResult<Vec<u8>>>> {
+        let mut results: Vec<Option<StoreResult<Vec<u8>>>> =
+            keys.iter().map(|_| None).collect();
+        let mut pending: Vec<(usize, StoreKey)> = keys.iter().cloned().enumerate().collect();
+
+        pending = self.get_batch_local(pending, &mut results)?;
+
+        if let Some(remote_store) = self.remote_store.as_ref() {
+            if !pending.is_empty() {
+                let missing: Vec<StoreKey> =
+                    pending.iter().map(|(_, key)| key.clone()).collect();
+                // A failed prefetch may still have brought some of the keys in, the second
+                // local walk below is what decides what was actually found.
+                let _ = remote_store.prefetch(&missing);
+                pending = self.get_batch_local(pending, &mut results)?;
+            }
+        }
+
+        for (idx, key) in pending {
+            results[idx] = Some(StoreResult::NotFound(key));
+        }
+
+        Ok(results
+            .into_iter()
+            .map(|result| result.expect("all keys are either found or missing"))
+            .collect())
+    }
+
+    /// Walk the local stores once per store, resolving `pending` into `results`. Returns the
+    /// keys that none of the local stores contain.
+    fn get_batch_local(
+        &self,
+        pending: Vec<(usize, StoreKey)>,
+        results: &mut [Option<StoreResult<Vec<u8>>>],
+    ) -> Result<Vec<(usize, StoreKey)>> {
+        let mut pending = pending;
+        for store in &self.local_datastore {
+            if pending.is_empty() {
+                break;
+            }
+            let mut still_missing = Vec::with_capacity(pending.len());
+            for (idx, key) in pending {
+                match store.get(key)? {
+                    StoreResult::Found(data) => results[idx] = Some(StoreResult::Found(data)),
+                    StoreResult::NotFound(next) => still_missing.push((idx, next)),
+                }
+            }
+            pending = still_missing;
+        }
+        Ok(pending)
+    }
+
+    /// The resolved path to the local store, with the builder's suffix applied.
+    pub fn local_path(&self) -> Option<&Path> {
+        self.local_path.as_deref()
+    }
+
+    /// The resolved path to the shared cache, with the builder's suffix applied.
+    pub fn cache_path(&self) -> Option<&Path> {
+        self.cache_path.as_deref()
+    }
+
+    /// Same as `HgIdDataStore::get`, but also report which store satisfied the request.
+    pub fn get_with_source(&self, key: StoreKey) -> Result<StoreResult<(Vec<u8>, StoreSource)>> {
+        let mut key = key;
+        for (source, store) in self.sourced_datastores.iter() {
+            match store.get(key)? {
+                StoreResult::Found(data) => return Ok(StoreResult::Found((data, *source))),
+                StoreResult::NotFound(next) => key = next,
+            }
+        }
+        Ok(StoreResult::NotFound(key))
+    }
+
+    /// Async variant of `HgIdDataStore::get` for callers already on a tokio runtime.
+    ///
+    /// The local lookups are cheap and performed synchronously. The remote fetch is moved to
+    /// the blocking threadpool so that an async worker thread isn't tied up for its whole
+    /// duration: the `LfsClient` drives the actual transfer on the async runtime, the
+    /// blocking task merely parks on its completion.
+    pub async fn get_async(&self, key: StoreKey) -> Result<StoreResult<Vec<u8>>> {
+        let key = match self.local_datastore.get(key)? {
+            StoreResult::Found(data) => return Ok(StoreResult::Found(data)),
+            StoreResult::NotFound(next) => next,
+        };
+
+        if let Some(remote_store) = self.remote_store.clone() {
+            let missing = vec![key.clone()];
+            // A failed fetch simply leaves the key missing from the local stores, the
+            // lookup below reports it as such.
+            let _ = spawn_blocking(move || remote_store.prefetch(&missing)).await?;
+            self.local_datastore.get(key)
+        } else {
+            Ok(StoreResult::NotFound(key))
+        }
+    }
+
+    /// Same as `HgIdDataStore::get`, but overrides the build-time ext-stored policy for
+    /// this one call.
+    ///
+    /// With `ExtStoredPolicy::Ignore`, entries flagged as ext-stored are treated as missing
+    /// and reported as `NotFound`, they do not fall back to the LFS stores.
+    pub fn get_with_policy(
+        &self,
+        key: StoreKey,
+        policy: ExtStoredPolicy,
+    ) -> Result<StoreResult<Vec<u8>>> {
+        if policy == ExtStoredPolicy::Ignore {
+            if let StoreKey::HgId(hgid_key) = &key {
+                for store in [
+                    Some(&self.shared_mutabledatastore),
+                    self.local_mutabledatastore.as_ref(),
+                ]
+                .into_iter()
+                .flatten()
+                {
+                    if let Some(entry) = store.get_raw_entry(&hgid_key.hgid)? {
+                        if entry.metadata().is_lfs() {
+                            return Ok(StoreResult::NotFound(key));
+                        }
+                        return Ok(StoreResult::Found(entry.content()?.as_ref().to_vec()));
+                    }
+                }
+            }
+        }
+        self.datastore.get(key)
+    }
+
+    /// Fetch the size of the content designated by `key`, without materializing the blob.
+    ///
+    /// For LFS content, the size is read from the pointer. For regular content, it is read
+    /// from the headers of the locally stored blob. The remote is never consulted as there
+    /// is no metadata-only fetch API: downloading the blob is exactly what this method is
+    /// meant to avoid, so content not available locally is reported as `NotFound`.
+    pub fn content_size(&self, key: StoreKey) -> Result<StoreResult<u64>> {
+        for lfs_store in [self.local_lfs_store.as_ref(), self.shared_lfs_store.as_ref()]
+            .into_iter()
+            .flatten()
+        {
+            if let StoreResult::Found(metadata) = lfs_store.metadata(key.clone())? {
+                return Ok(StoreResult::Found(metadata.size as u64));
+            }
+        }
+
+        if let StoreKey::HgId(hgid_key) = &key {
+            for store in [
+                Some(&self.shared_mutabledatastore),
+                self.local_mutabledatastore.as_ref(),
+            ]
+            .into_iter()
+            .flatten()
+            {
+                if let Some(entry) = store.get_raw_entry(&hgid_key.hgid)? {
+                    return Ok(StoreResult::Found(entry.content_len()?));
+                }
+            }
+        }
+
+        Ok(StoreResult::NotFound(key))
+    }
+
+    /// Report what `check_cache_buster` would delete from the shared cache for `config`,
+    /// without deleting anything or recording the run-once markers. Stores built without a
+    /// shared cache report an empty plan.
+    pub fn plan_cache_purge(&self, config: &dyn Config) -> Result<CacheBusterPlan> {
+        match self.cache_path.as_ref() {
+            Some(cache_path) => plan_cache_buster(config, cache_path),
+            None => Ok(CacheBusterPlan::default()),
+        }
+    }
+
+    /// Same as `RemoteDataStore::prefetch`, but gives up once `deadline` passes.
+    ///
+    /// The remote LFS retry loop will not start a new retry past the deadline, so the amount
+    /// of time an in-flight request can overshoot it is bounded by the HTTP timeouts. Once the
+    /// deadline passes, the keys that were not fetched are reported as still missing instead
+    /// of blocking forever or failing the whole prefetch.
+    pub fn prefetch_with_deadline(
+        &self,
+        keys: &[StoreKey],
+        deadline: Instant,
+    ) -> Result<Vec<StoreKey>> {
+        if let Some(remote_store) = self.remote_store.as_ref() {
+            let missing = self.get_missing(keys)?;
+            if missing.is_empty() {
+                return Ok(vec![]);
+            }
+            match with_prefetch_deadline(deadline, || remote_store.prefetch(&missing)) {
+                Ok(still_missing) => Ok(still_missing),
+                Err(_) if Instant::now() >= deadline => self.get_missing(&missing),
+                Err(e) => Err(e),
+            }
+        } else {
+            // There is no remote store, let's pretend everything is fine.
+            Ok(vec![])
+        }
+    }
+
+    /// Iterate over all the keys present in the local IndexedLog.
+    ///
+    /// Only the local (permanent) store is covered, the shared cache is not. Note that the
+    /// indexedlog does not record the path component of the keys, only their hgid is
+    /// meaningful. Returns an error for stores built without a local store.
+    pub fn local_keys(&self) -> Result<impl Iterator<Item = Result<Key>>> {
+        let local = self.local_mutabledatastore.as_ref().ok_or_else(|| {
+            format_err!("iterating the keys of a non-local ContentStore is not allowed")
+        })?;
+        Ok(local.to_keys().into_iter())
+    }
+
+    /// Report the on-disk footprint of the indexedlog stores, for capacity planning.
+    ///
+    /// The byte counts are read from the log metadata and only cover flushed data; the
+    /// LFS stores are not included. For stores built without a shared cache, the local
+    /// store doubles as the shared one and is reported as local only.
+    pub fn disk_usage(&self) -> Result<DiskUsage> {
+        let (local_bytes, local_entries) = match self.local_mutabledatastore.as_ref() {
+            Some(local) => (local.disk_bytes(), local.entry_count()),
+            None => (0, 0),
+        };
+
+        let shared = &self.shared_mutabledatastore;
+        let (shared_bytes, shared_entries) = if self
+            .local_mutabledatastore
+            .as_ref()
+            .is_some_and(|local| Arc::ptr_eq(local, shared))
+        {
+            (0, 0)
+        } else {
+            (shared.disk_bytes(), shared.entry_count())
+        };
+
+        Ok(DiskUsage {
+            local_bytes,
+            local_entries,
+            shared_bytes,
+            shared_entries,
+        })
+    }
+
+    /// Same as `LocalStore::get_missing`, but only consults the local and shared stores,
+    /// never the remote.
+    ///
+    /// This is what a prefetch planner wants: `get_missing` walks the full union datastore,
+    /// which includes the remote store, while this reports what would actually have to be
+    /// fetched.
+    pub fn get_missing_local(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+        self.local_datastore.get_missing(keys)
+    }
+
+    /// Copy local entries into the shared cache so that other processes get cache hits.
+    ///
+    /// This is meant to be called after a local commit has been landed remotely: the data
+    /// is then known to be available on the server, which is the invariant the shared cache
+    /// relies on. Entries stored as LFS locally are promoted through the shared LFS store,
+    /// so the routing applied when the blob was first written is preserved. Returns the
+    /// keys that couldn't be promoted because they are not present locally.
+    pub fn promote_to_shared(&self, keys: &[Key]) -> Result<Vec<Key>> {
+        let local = self.local_mutabledatastore.as_ref().ok_or_else(|| {
+            format_err!("promoting from a non-local ContentStore is not allowed")
+        })?;
+
+        let mut not_promoted = Vec::new();
+        for key in keys {
+            let entry = match local.get_raw_entry(&key.hgid)? {
+                Some(entry) => entry,
+                None => {
+                    not_promoted.push(key.clone());
+                    continue;
+                }
+            };
+
+            if entry.metadata().is_lfs() {
+                // The local entry is an LFS pointer, the blob lives in the local LFS
+                // store. Promote the blob through the shared LFS store, which takes
+                // care of rewriting both the pointer and the blob.
+                let blob = match (self.local_lfs_store.as_ref(), self.shared_lfs_store.as_ref()) {
+                    (Some(local_lfs), Some(shared_lfs)) => {
+                        match local_lfs.get(StoreKey::hgid(key.clone()))? {
+                            StoreResult::Found(blob) => Some((shared_lfs, blob)),
+                            StoreResult::NotFound(_) => None,
+                        }
+                    }
+                    _ => None,
+                };
+                match blob {
+                    Some((shared_lfs, blob)) => {
+                        let delta = Delta {
+                            data: blob.into(),
+                            base: None,
+                            key: key.clone(),
+                        };
+                        shared_lfs.add(&delta, &Default::default())?;
+                    }
+                    None => not_promoted.push(key.clone()),
+                }
+            } else {
+                let delta = Delta {
+                    data: entry.content()?,
+                    base: None,
+                    key: key.clone(),
+                };
+                self.shared_mutabledatastore.add(&delta, entry.metadata())?;
+            }
+        }
+
+        self.shared_mutabledatastore.flush()?;
+        if let Some(shared_lfs) = self.shared_lfs_store.as_ref() {
+            shared_lfs.flush()?;
+        }
+
+        Ok(not_promoted)
+    }
+
+    /// Verify the integrity of the locally stored content for `keys`.
+    ///
+    /// LFS blobs are re-hashed and compared against the Sha256 oid recorded in their
+    /// pointer, a check that is skipped at read time for performance. For regular entries
+    /// the filenode hash is recomputed from the stored content; the content store doesn't
+    /// record parents, so only parentless content can be fully checked that way, entries
+    /// with parents are checked to be readable and to match the size recorded in their
+    /// metadata. Keys with no content stored locally are skipped.
+    ///
+    /// Returns the keys whose stored content doesn't match its expected hash.
+    pub fn verify(&self, keys: &[Key]) -> Result<Vec<Key>> {
+        let mut mismatched = Vec::new();
+
+        'keys: for key in keys {
+            for lfs_store in [self.shared_lfs_store.as_ref(), self.local_lfs_store.as_ref()]
+                .into_iter()
+                .flatten()
+            {
+                if let Some(valid) = lfs_store.verify_blob(key)? {
+                    if !valid {
+                        mismatched.push(key.clone());
+                    }
+                    continue 'keys;
+                }
+            }
+
+            let stores = [
+                Some(&self.shared_mutabledatastore),
+                self.local_mutabledatastore.as_ref(),
+            ];
+            for store in stores.into_iter().flatten() {
+                let entry = match store.get_raw_entry(&key.hgid)? {
+                    Some(entry) => entry,
+                    None => continue,
+                };
+                if entry.metadata().is_lfs() {
+                    // An LFS pointer whose blob wasn't found in the LFS stores above,
+                    // nothing further to check here.
+                    continue 'keys;
+                }
+                match entry.content() {
+                    Ok(content) => {
+                        // The filenode hash covers the parents, which the content store
+                        // doesn't record; recomputing with null parents can therefore
+                        // only confirm parentless content. Fall back to the size in the
+                        // entry's metadata for everything else.
+                        if HgId::from_content(&content, Parents::None) != key.hgid {
+                            if let Some(size) = entry.metadata().size {
+                                if size != content.len() as u64 {
+                                    mismatched.push(key.clone());
+                                }
+                            }
+                        }
+                    }
+                    Err(_) => mismatched.push(key.clone()),
+                }
+                continue 'keys;
+            }
+        }
+
+        Ok(mismatched)
+    }
+
+    /// Construct a sibling store sharing this store's remote stores and settings, but
+    /// with its caches namespaced under `suffix`.
+    ///
+    /// The suffix changes the on-disk paths, so the sibling opens its own indexedlog and
+    /// LFS handles; only the remote store connections are shared. The config has to be
+    /// passed again because the builder only borrows it. Memory-only knobs
+    /// (`memory_overlay`, `memory_cache_only`, `on_remote_fetch`) are not carried over.
+    pub fn with_suffix(
+        &self,
+        suffix: impl AsRef<Path>,
+        config: &dyn Config,
+    ) -> Result<ContentStore> {
+        let mut builder = ContentStoreBuilder::new(config)
+            .suffix(suffix)
+            .format(self.format)
+            .extstored_policy(self.extstored_policy);
+
+        builder = match self.base_local_path.as_ref() {
+            Some(local_path) => builder.local_path(local_path),
+            None => builder.no_local_store(),
+        };
+        if self.read_only {
+            builder = builder.read_only();
+        }
+        if !self.cache_on_read {
+            builder = builder.no_cache_on_read();
+        }
+        for remotestore in self.remotestores.iter() {
+            builder = builder.add_remotestore(remotestore.clone());
+        }
+
+        builder.build()
+    }
+
+    /// Attempt to repair the underlying stores that the `ContentStore` is comprised of.
+    ///
+    /// As this may violate some of the stores asumptions, care must be taken to call this only
+    /// when no other `ContentStore` have been created for the `shared_path`.
+    ///
+    /// Repair removes entries that can't be read back. For the shared cache this is always
+    /// safe since anything dropped can be refetched from the server. For the local store it
+    /// is lossy: data of local-only commits isn't present on the server and can't be
+    /// recovered once removed. The indexedlog stores also self-repair when opened, so this
+    /// entry point is mostly useful for fsck-style tooling that wants the repair report.
+    pub fn repair(
+        shared_path: impl AsRef<Path>,
+        local_path: Option<impl AsRef<Path>>,
+        suffix: Option<impl AsRef<Path>>,
+        config: &dyn Config,
+    ) -> Result<String> {
+        let mut repair_str = String::new();
+        let mut shared_path = shared_path.as_ref().to_path_buf();
+        if let Some(suffix) = suffix.as_ref() {
+            shared_path.push(suffix);
+        }
+        let local_path = local_path
+            .map(|p| get_local_path(p.as_ref().to_path_buf(), &suffix))
+            .transpose()?;
+
+        let log_config = IndexedLogHgIdDataStoreConfig {
+            max_log_count: None,
+            max_bytes_per_log: None,
+            max_bytes: None,
+        };
+
+        repair_str += &IndexedLogHgIdDataStore::repair(
+            config,
+            get_indexedlogdatastore_path(&shared_path)?,
+            &log_config,
+            StoreType::Rotated,
+        )?;
+        if let Some(local_path) = local_path {
+            repair_str += &IndexedLogHgIdDataStore::repair(
+                config,
+                get_indexedlogdatastore_path(local_path)?,
+                &log_config,
+                StoreType::Permanent,
+            )?;
+        }
+        repair_str += &LfsStore::repair(&shared_path)?;
+        Ok(repair_str)
+    }
+}
+
+/// Prints which stores are configured and where they live, but never their
+/// contents: a `ContentStore` can hold gigabytes, and `{:?}` shows up in test
+/// failures and log lines.
+impl fmt::Debug for ContentStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ContentStore")
+            .field("local_path", &self.local_path)
+            .field("cache_path", &self.cache_path)
+            .field("local_indexedlog", &self.local_mutabledatastore.is_some())
+            .field("shared_indexedlog", &true)
+            .field("local_lfs", &self.local_lfs_store.is_some())
+            .field("shared_lfs", &self.shared_lfs_store.is_some())
+            .field("remote", &self.remote_store.is_some())
+            .field("extstored_policy", &self.extstored_policy)
+            .field("read_only", &self.read_only)
+            .finish()
+    }
+}
+
+/// Probe that an indexedlog backed store can be synced with its on-disk state.
+fn probe_refresh(store: &dyn HgIdDataStore) -> SubsystemHealth {
+    match store.refresh() {
+        Ok(()) => SubsystemHealth::Ok,
+        Err(e) => SubsystemHealth::Failed(format!("indexedlog: {:#}", e)),
+    }
+}
+
+/// Probe that `path` is writable by creating (and removing) a file in it.
+fn probe_writable(path: &Path) -> Result<()> {
+    fs::create_dir_all(path)?;
+    let probe = path.join(".health_check");
+    fs::write(&probe, b"")?;
+    fs::remove_file(&probe)?;
+    Ok(())
+}
+
+/// Recursively `fsync(2)` every file under `path`, along with the directories themselves.
+fn fsync_recursive(path: &Path) -> Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            fsync_recursive(&entry.path())?;
+        } else {
+            fs::File::open(entry.path())?.sync_all()?;
+        }
+    }
+    // Syncing a directory handle isn't supported on all platforms (notably Windows),
+    // so this part is best-effort.
+    if let Ok(dir) = fs::File::open(path) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+impl HgIdDataStore for ContentStore {
+    fn get(&self, key: StoreKey) -> Result<StoreResult<Vec<u8>>> {
+        self.get_with_policy(key, self.extstored_policy)
+    }
+
+    fn refresh(&self) -> Result<()> {
+        self.datastore.refresh()
+    }
+}
+
+impl RemoteDataStore for ContentStore {
+    fn prefetch(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+        if let Some(remote_store) = self.remote_store.as_ref() {
+            let missing = self.get_missing(keys)?;
+            if missing == vec![] {
+                Ok(vec![])
+            } else {
+                let span = tracing::info_span!(
+                    "remote prefetch",
+                    keys = missing.len(),
+                    missing = tracing::field::Empty,
+                    downloaded = tracing::field::Empty,
+                );
+                let _enter = span.enter();
+
+                let still_missing = remote_store.prefetch(&missing)?;
+
+                // Bytes
+                let mut downloaded = 0;
+                for key in missing.iter() {
+                    if still_missing.contains(key) {
+                        continue;
+                    }
+                    if let StoreResult::Found(size) = self.content_size(key.clone())? {
+                        downloaded += size;
+                    }
+                }
+                span.record("missing", still_missing.len());
+                span.record("downloaded", downloaded);
+
+                Ok(still_missing)
+            }
+        } else {
+            // There is no remote store, let's pretend everything is fine.
+            Ok(vec![])
+        }
+    }
+
+    fn upload(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+        if let Some(remote_store) = self.remote_store.as_ref() {
+            remote_store.upload(keys)
+        } else {
+            Ok(keys.to_vec())
+        }
+    }
+}
+
+impl LocalStore for ContentStore {
+    fn get_missing(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+        self.datastore.get_missing(keys)
+    }
+}
+
+impl Drop for ContentStore {
+    /// The shared store is a cache, so let's flush all pending data when the `ContentStore` goes
+    /// out of scope.
+    fn drop(&mut self) {
+        let _ = self.shared_mutabledatastore.flush();
+    }
+}
+
+impl HgIdMutableDeltaStore for ContentStore {
+    fn add(&self, delta: &Delta, metadata: &Metadata) -> Result<()> {
+        if self.read_only {
+            return Err(format_err!(
+                "writing to a read-only ContentStore is not allowed"
+            ));
+        }
+        self.local_mutabledatastore
+            .as_ref()
+            .ok_or_else(|| format_err!("writing to a non-local ContentStore is not allowed"))?
+            .add(delta, metadata)
+    }
+
+    fn flush(&self) -> Result<Option<Vec<PathBuf>>> {
+        if self.read_only {
+            return Err(format_err!(
+                "flushing a read-only ContentStore is not allowed"
+            ));
+        }
+        self.shared_mutabledatastore.as_ref().flush()?;
+        if let Some(lfs_store) = self.shared_lfs_store.as_ref() {
+            lfs_store.flush()?;
+        }
+        if let Some(lfs_store) = self.local_lfs_store.as_ref() {
+            lfs_store.flush()?;
+        }
+        self.local_mutabledatastore
+            .as_ref()
+            .ok_or_else(|| format_err!("flushing a non-local ContentStore is not allowed"))?
+            .flush()
+    }
+
+    /// Flush, then `fsync(2)` the underlying IndexedLog and pack files and their containing
+    /// directories. This gives a durability guarantee that `flush` alone doesn't: once this
+    /// returns, the data has hit the disk. Syncing every file makes this much slower than
+    /// `flush`, reserve it for when a user is about to be told their data is safe.
+    fn flush_durable(&self) -> Result<Option<Vec<PathBuf>>> {
+        let flushed = self.flush()?;
+        if let Some(local_path) = self.local_path.as_deref() {
+            fsync_recursive(local_path)?;
+        }
+        if let Some(cache_path) = self.cache_path.as_deref() {
+            fsync_recursive(cache_path)?;
+        }
+        Ok(flushed)
+    }
+}
+
+/// In-memory sink for remote fetches when the write-through to the shared cache is
+/// disabled via `ContentStoreBuilder::no_cache_on_read`. Remote stores need somewhere
+/// to write the fetched data before it can be read back, this store keeps it out of
+/// the on-disk caches and is simply dropped with the `ContentStore`.
+struct ScratchStore {
+    entries: Mutex<HashMap<Key, Vec<u8>>>,
+}
+
+impl ScratchStore {
+    fn new() -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl HgIdMutableDeltaStore for ScratchStore {
+    fn add(&self, delta: &Delta, _metadata: &Metadata) -> Result<()> {
+        if delta.base.is_some() {
+            bail!("ScratchStore doesn't support deltas");
+        }
+        self.entries
+            .lock()
+            .insert(delta.key.clone(), delta.data.as_ref().to_vec());
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<Option<Vec<PathBuf>>> {
+        Ok(None)
+    }
+}
+
+impl HgIdDataStore for ScratchStore {
+    fn get(&self, key: StoreKey) -> Result<StoreResult<Vec<u8>>> {
+        match &key {
+            StoreKey::HgId(hgid_key) => match self.entries.lock().get(hgid_key) {
+                Some(data) => Ok(StoreResult::Found(data.clone())),
+                None => Ok(StoreResult::NotFound(key)),
+            },
+            StoreKey::Content(_, _) => Ok(StoreResult::NotFound(key)),
+        }
+    }
+
+    fn refresh(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl LocalStore for ScratchStore {
+    fn get_missing(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+        let entries = self.entries.lock();
+        Ok(keys
+            .iter()
+            .filter(|key| match key {
+                StoreKey::HgId(hgid_key) => !entries.contains_key(hgid_key),
+                StoreKey::Content(_, _) => true,
+            })
+            .cloned()
+            .collect())
+    }
+}
+
+/// In-memory, size-bounded LRU cache for remote fetches, used by
+/// `ContentStoreBuilder::memory_cache_only`. Unlike `ScratchStore` it is consulted by reads
+/// before the remote store, and once `max_bytes` is exceeded the least recently used
+/// entries are simply dropped; a later read refetches them from the remote store.
+struct MemoryLruStore {
+    inner: Mutex<MemoryLruInner>,
+}
+
+struct MemoryLruInner {
+    max_bytes: usize,
+    total_bytes: usize,
+    entries: HashMap<Key, MemoryLruEntry>,
+    /// Access sequence to key, from least to most recently used. Sequences
+    /// are handed out monotonically, so touching is an O(log n) remove and
+    /// re-insert instead of a scan.
+    order: BTreeMap<u64, Key>,
+    next_seq: u64,
+}
+
+struct MemoryLruEntry {
+    data: Vec<u8>,
+    seq: u64,
+}
+
+impl MemoryLruStore {
+    fn new(max_bytes: usize) -> Self {
+        Self {
+            inner: Mutex::new(MemoryLruInner {
+                max_bytes,
+                total_bytes: 0,
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+                next_seq: 0,
+            }),
+        }
+    }
+}
+
+impl MemoryLruInner {
+    fn touch(&mut self, key: &Key) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.order.remove(&entry.seq);
+            entry.seq = self.next_seq;
+            self.next_seq += 1;
+            self.order.insert(entry.seq, key.clone());
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.total_bytes > self.max_bytes {
+            match self.order.pop_first() {
+                Some((_seq, key)) => {
+                    if let Some(entry) = self.entries.remove(&key) {
+                        self.total_bytes -= entry.data.len();
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+impl HgIdMutableDeltaStore for MemoryLruStore {
+    fn add(&self, delta: &Delta, _metadata: &Metadata) -> Result<()> {
+        if delta.base.is_some() {
+            bail!("MemoryLruStore doesn't support deltas");
+        }
+        let mut inner = self.inner.lock();
+        let data = delta.data.as_ref().to_vec();
+        inner.total_bytes += data.len();
+        let seq = inner.next_seq;
+        inner.next_seq += 1;
+        if let Some(old) = inner
+            .entries
+            .insert(delta.key.clone(), MemoryLruEntry { data, seq })
+        {
+            inner.total_bytes -= old.data.len();
+            inner.order.remove(&old.seq);
+        }
+        inner.order.insert(seq, delta.key.clone());
+        inner.evict();
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<Option<Vec<PathBuf>>> {
+        Ok(None)
+    }
+}
+
+impl HgIdDataStore for MemoryLruStore {
+    fn get(&self, key: StoreKey) -> Result<StoreResult<Vec<u8>>> {
+        match &key {
+            StoreKey::HgId(hgid_key) => {
+                let mut inner = self.inner.lock();
+                match inner.entries.get(hgid_key) {
+                    Some(entry) => {
+                        let data = entry.data.clone();
+                        inner.touch(hgid_key);
+                        Ok(StoreResult::Found(data))
+                    }
+                    None => Ok(StoreResult::NotFound(key)),
+                }
+            }
+            StoreKey::Content(_, _) => Ok(StoreResult::NotFound(key)),
+        }
+    }
+
+    fn refresh(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl LocalStore for MemoryLruStore {
+    fn get_missing(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+        let inner = self.inner.lock();
+        Ok(keys
+            .iter()
+            .filter(|key| match key {
+                StoreKey::HgId(hgid_key) => !inner.entries.contains_key(hgid_key),
+                StoreKey::Content(_, _) => true,
+            })
+            .cloned()
+            .collect())
+    }
+}
+
+/// Write-through wrapper implementing `ContentStoreBuilder::on_remote_fetch`. Remote
+/// stores only write to their shared store for data they actually fetched, so keys
+/// satisfied by the local or shared caches never reach the callback.
+struct RemoteFetchObserver {
+    store: Arc<dyn HgIdMutableDeltaStore>,
+    on_fetch: Arc<dyn Fn(&StoreKey, usize) + Send + Sync>,
+}
+
+impl HgIdMutableDeltaStore for RemoteFetchObserver {
+    fn add(&self, delta: &Delta, metadata: &Metadata) -> Result<()> {
+        self.store.add(delta, metadata)?;
+        (self.on_fetch)(&StoreKey::hgid(delta.key.clone()), delta.data.len());
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<Option<Vec<PathBuf>>> {
+        self.store.flush()
+    }
+}
+
+impl HgIdDataStore for RemoteFetchObserver {
+    fn get(&self, key: StoreKey) -> Result<StoreResult<Vec<u8>>> {
+        self.store.get(key)
+    }
+
+    fn refresh(&self) -> Result<()> {
+        self.store.refresh()
+    }
+}
+
+impl LocalStore for RemoteFetchObserver {
+    fn get_missing(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+        self.store.get_missing(keys)
+    }
+}
+
+/// In-memory store seeded via `ContentStoreBuilder::memory_overlay`. Reads hit it before any
+/// on-disk or remote store, and nothing is ever written to it.
+struct MemoryOverlayStore {
+    entries: HashMap<Key, (Bytes, Metadata)>,
+}
+
+impl MemoryOverlayStore {
+    fn new(entries: Vec<(Key, Bytes, Metadata)>) -> Self {
+        Self {
+            entries: entries
+                .into_iter()
+                .map(|(key, data, metadata)| (key, (data, metadata)))
+                .collect(),
+        }
+    }
+}
+
+impl HgIdDataStore for MemoryOverlayStore {
+    fn get(&self, key: StoreKey) -> Result<StoreResult<Vec<u8>>> {
+        match &key {
+            StoreKey::HgId(hgid_key) => match self.entries.get(hgid_key) {
+                Some((data, _)) => Ok(StoreResult::Found(data.as_ref().to_vec())),
+                None => Ok(StoreResult::NotFound(key)),
+            },
+            StoreKey::Content(_, _) => Ok(StoreResult::NotFound(key)),
+        }
+    }
+
+    fn refresh(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl LocalStore for MemoryOverlayStore {
+    fn get_missing(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+        Ok(keys
+            .iter()
+            .filter(|key| match key {
+                StoreKey::HgId(hgid_key) => !self.entries.contains_key(hgid_key),
+                StoreKey::Content(_, _) => true,
+            })
+            .cloned()
+            .collect())
+    }
+}
+
+/// Builder for `ContentStore`. An `impl AsRef<Path>` represents the path to the store and a
+/// `dyn Config` of the Mercurial configuration are required to build a `ContentStore`.
+pub struct ContentStoreBuilder<'a> {
+    local_path: Option<PathBuf>,
+    no_local_store: bool,
+    config: &'a dyn Config,
+    remotestores: Vec<Arc<dyn HgIdRemoteStore>>,
+    suffix: Option<PathBuf>,
+    format: Option<SerializationFormat>,
+    memory_overlay: Vec<(Key, Bytes, Metadata)>,
+    read_only: bool,
+    cache_on_read: bool,
+    extstored_policy: Option<ExtStoredPolicy>,
+    memory_cache_bytes: Option<ByteCount>,
+    on_remote_fetch: Option<Arc<dyn Fn(&StoreKey, usize) + Send + Sync>>,
+}
+
+impl<'a> ContentStoreBuilder<'a> {
+    pub fn new(config: &'a dyn Config) -> Self {
+        Self {
+            local_path: None,
+            no_local_store: false,
+            config,
+            remotestores: Vec::new(),
+            suffix: None,
+            format: None,
+            memory_overlay: Vec::new(),
+            read_only: false,
+            cache_on_read: true,
+            extstored_policy: None,
+            memory_cache_bytes: None,
+            on_remote_fetch: None,
+        }
+    }
+
+    /// Path to the local store.
+    pub fn local_path(mut self, local_path: impl AsRef<Path>) -> Self {
+        self.local_path = Some(local_path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Allows a ContentStore to be created without a local store.
+    ///
+    /// This should be used in very specific cases that do not want a local store. Unless you know
+    /// exactly that this is what you want, do not use.
+    pub fn no_local_store(mut self) -> Self {
+        self.no_local_store = true;
+        self
+    }
+
+    pub fn remotestore(self, remotestore: Arc<dyn HgIdRemoteStore>) -> Self {
+        self.add_remotestore(remotestore)
+    }
+
+    /// Add a remote store to fall back to. Can be called multiple times, remotes are
+    /// consulted in insertion order: keys the first remote reports as missing are
+    /// requested from the second, and so on.
+    pub fn add_remotestore(mut self, remotestore: Arc<dyn HgIdRemoteStore>) -> Self {
+        self.remotestores.push(remotestore);
+        self
+    }
+
+    pub fn suffix(mut self, suffix: impl AsRef<Path>) -> Self {
+        self.suffix = Some(suffix.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn format(mut self, format: SerializationFormat) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    /// Force the ext-stored policy, taking precedence over the config-derived value.
+    /// Mostly useful for tests and tools that shouldn't have to construct a fake config
+    /// just to exercise the `Ignore` path.
+    pub fn extstored_policy(mut self, policy: ExtStoredPolicy) -> Self {
+        self.extstored_policy = Some(policy);
+        self
+    }
+
+    /// Seed the store with in-memory `(key, data, metadata)` entries that are consulted
+    /// before any on-disk or remote store.
+    ///
+    /// The overlay is purely a read cache: `add` never writes to it, and it works for
+    /// stores built with `no_local_store`.
+    pub fn memory_overlay(mut self, entries: Vec<(Key, Bytes, Metadata)>) -> Self {
+        self.memory_overlay = entries;
+        self
+    }
+
+    /// Cache remote fetches only in memory, bounded to `max_bytes` with LRU eviction,
+    /// and don't create a local store.
+    ///
+    /// For stateless services that must not write fetched data to disk: the on-disk
+    /// shared cache (if configured) is still read, but remote fetches land in the
+    /// in-memory cache and are dropped with the store. This is distinct from
+    /// `memory_overlay`, which read-only seeds the store with caller-provided data.
+    pub fn memory_cache_only(mut self, max_bytes: ByteCount) -> Self {
+        self.memory_cache_bytes = Some(max_bytes);
+        self.no_local_store = true;
+        self
+    }
+
+    /// Invoke `callback` with the key and size of every blob actually fetched from a
+    /// remote store, e.g. for audit logging. It fires once per fetched key, and not
+    /// for keys satisfied by the local or shared caches.
+    pub fn on_remote_fetch(
+        mut self,
+        callback: Arc<dyn Fn(&StoreKey, usize) + Send + Sync>,
+    ) -> Self {
+        self.on_remote_fetch = Some(callback);
+        self
+    }
+
+    /// Don't write remotely fetched data through to the shared cache.
+    ///
+    /// By default a remote fetch populates the shared cache as a side effect. For
+    /// throwaway diagnostic reads this pollutes the cache: with this option, fetched
+    /// data is kept in memory for the duration of the store and the on-disk caches
+    /// are left untouched.
+    pub fn no_cache_on_read(mut self) -> Self {
+        self.cache_on_read = false;
+        self
+    }
+
+    /// Construct a store that rejects all writes with an error.
+    ///
+    /// The store can still be read from, and remote fetches still populate the shared cache
+    /// as that is internal bookkeeping, but `add` and `flush` will fail cleanly instead of
+    /// mutating the local store.
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    fn get_format(&self) -> SerializationFormat {
+        self.format.unwrap_or(SerializationFormat::Hg)
+    }
+
+    fn use_lfs(&self) -> Result<bool> {
+        Ok(self.config.get_or_default::<bool>("remotefilelog", "lfs")?)
+    }
+
+    pub fn build(self) -> Result<ContentStore> {
+        let base_local_path = self.local_path.clone();
+        let remotestores = self.remotestores.clone();
+        let format = self.get_format();
+        let cache_on_read = self.cache_on_read;
+
+        let local_path = self
+            .local_path
+            .as_ref()
+            .map(|p| get_local_path(p.clone(), &self.suffix))
+            .transpose()?;
+        let cache_path = get_cache_path(self.config, &self.suffix)?;
+
+        let mut datastore: UnionHgIdDataStore<Arc<dyn HgIdDataStore>> = UnionHgIdDataStore::new();
+        let mut local_datastore: UnionHgIdDataStore<Arc<dyn HgIdDataStore>> =
+            UnionHgIdDataStore::new();
+
+        let mut sourced_datastores: Vec<(StoreSource, Arc<dyn HgIdDataStore>)> = Vec::new();
+
+        if !self.memory_overlay.is_empty() {
+            let overlay = Arc::new(MemoryOverlayStore::new(self.memory_overlay));
+            datastore.add(overlay.clone());
+            local_datastore.add(overlay.clone());
+            sourced_datastores.push((StoreSource::Memory, overlay));
+        }
+
+        let shared_indexedlogdatastore = match cache_path.as_ref() {
+            Some(cache_path) => {
+                let max_log_count = self
+                    .config
+                    .get_opt::<u8>("indexedlog", "data.max-log-count")?;
+                let max_bytes_per_log = self
+                    .config
+                    .get_opt::<ByteCount>("indexedlog", "data.max-bytes-per-log")?;
+                let max_bytes = self
+                    .config
+                    .get_opt::<ByteCount>("remotefilelog", "cachelimit")?;
+                let log_config = IndexedLogHgIdDataStoreConfig {
+                    max_log_count,
+                    max_bytes_per_log,
+                    max_bytes,
+                };
+                Some(Arc::new(IndexedLogHgIdDataStore::new(
+                    self.config,
+                    get_indexedlogdatastore_path(cache_path)?,
+                    &log_config,
+                    StoreType::Rotated,
+                    self.get_format(),
+                )?))
+            }
+            None => None,
+        };
+
+        // The shared store should precede the local one since it is expected that the number of
+        // blobs and the number of requests satisfied by the shared cache to be significantly
+        // higher than ones in the local store.
+        let primary: Option<Arc<IndexedLogHgIdDataStore>> = {
+            // Put the indexedlog first, since recent data will have gone there.
+            if let Some(shared_indexedlogdatastore) = shared_indexedlogdatastore.clone() {
+                datastore.add(shared_indexedlogdatastore.clone());
+                local_datastore.add(shared_indexedlogdatastore.clone());
+                sourced_datastores
+                    .push((StoreSource::SharedIndexedLog, shared_indexedlogdatastore));
+            }
+            shared_indexedlogdatastore
+        };
+
+        let shared_lfs_store = match (self.use_lfs()?, cache_path.as_ref()) {
+            (true, Some(cache_path)) => {
+                let shared_lfs_store = Arc::new(LfsStore::rotated(cache_path, self.config)?);
+                datastore.add(shared_lfs_store.clone());
+                local_datastore.add(shared_lfs_store.clone());
+                sourced_datastores.push((StoreSource::Lfs, shared_lfs_store.clone()));
+                Some(shared_lfs_store)
+            }
+            _ => None,
+        };
+
+        let (local_mutabledatastore, local_lfs_store): (
+            Option<Arc<IndexedLogHgIdDataStore>>,
+            Option<Arc<LfsStore>>,
+        ) = if let Some(local_path) = local_path.as_ref() {
+            let log_config = IndexedLogHgIdDataStoreConfig {
+                max_log_count: None,
+                max_bytes_per_log: None,
+                max_bytes: None,
+            };
+            let local_indexedlogdatastore = Arc::new(IndexedLogHgIdDataStore::new(
+                self.config,
+                get_indexedlogdatastore_path(local_path)?,
+                &log_config,
+                StoreType::Permanent,
+                self.get_format(),
+            )?);
+            datastore.add(local_indexedlogdatastore.clone());
+            local_datastore.add(local_indexedlogdatastore.clone());
+            sourced_datastores.push((
+                StoreSource::LocalIndexedLog,
+                local_indexedlogdatastore.clone(),
+            ));
+
+            let local_lfs_store = if self.use_lfs()? {
+                let local_lfs_store = Arc::new(LfsStore::permanent(local_path, self.config)?);
+                datastore.add(local_lfs_store.clone());
+                local_datastore.add(local_lfs_store.clone());
+                sourced_datastores.push((StoreSource::Lfs, local_lfs_store.clone()));
+                Some(local_lfs_store)
+            } else {
+                None
+            };
+
+            (Some(local_indexedlogdatastore), local_lfs_store)
+        } else {
+            if !self.no_local_store {
+                return Err(format_err!(
+                    "a ContentStore cannot be built without a local store"
+                ));
+            }
+            (None, None)
+        };
+
+        let primary = match primary {
+            Some(primary) => primary,
+            None => match local_mutabledatastore.as_ref() {
+                Some(local) => local.clone(),
+                None => bail!("ContentStore requires at least one of local store or shared store"),
+            },
+        };
+
+        let memory_cache_store: Option<Arc<MemoryLruStore>> = match self.memory_cache_bytes {
+            Some(max_bytes) => {
+                let memory_cache = Arc::new(MemoryLruStore::new(max_bytes.value() as usize));
+                datastore.add(memory_cache.clone());
+                local_datastore.add(memory_cache.clone());
+                sourced_datastores.push((StoreSource::Memory, memory_cache.clone()));
+                Some(memory_cache)
+            }
+            None => None,
+        };
+
+        let remote_store: Option<Arc<dyn RemoteDataStore>> = if self.remotestores.is_empty() {
+            None
+        } else {
+            let scratch_store: Option<Arc<ScratchStore>> = if self.cache_on_read {
+                None
+            } else {
+                Some(Arc::new(ScratchStore::new()))
+            };
+            let mut remote_datastores: Vec<Arc<dyn RemoteDataStore>> = Vec::new();
+            let on_remote_fetch = self.on_remote_fetch;
+            for remotestore in self.remotestores {
+                let shared_store = if let Some(memory_cache) = memory_cache_store.as_ref() {
+                    memory_cache.clone() as Arc<dyn HgIdMutableDeltaStore>
+                } else {
+                    match scratch_store.as_ref() {
+                        Some(scratch_store) => {
+                            scratch_store.clone() as Arc<dyn HgIdMutableDeltaStore>
+                        }
+                        None => primary.clone() as Arc<dyn HgIdMutableDeltaStore>,
+                    }
+                };
+                let shared_store = match on_remote_fetch.as_ref() {
+                    Some(on_fetch) => Arc::new(RemoteFetchObserver {
+                        store: shared_store,
+                        on_fetch: on_fetch.clone(),
+                    }) as Arc<dyn HgIdMutableDeltaStore>,
+                    None => shared_store,
+                };
+                remote_datastores.push(remotestore.datastore(shared_store));
+            }
+            let remote_store: Arc<dyn RemoteDataStore> = if remote_datastores.len() == 1 {
+                remote_datastores.pop().unwrap()
+            } else {
+                let mut union_remote: UnionHgIdDataStore<Arc<dyn RemoteDataStore>> =
+                    UnionHgIdDataStore::new();
+                for remote_datastore in remote_datastores {
+                    union_remote.add(remote_datastore);
+                }
+                Arc::new(union_remote)
+            };
+            datastore.add(Arc::new(remote_store.clone()));
+            sourced_datastores.push((StoreSource::Remote, Arc::new(remote_store.clone())));
+            Some(remote_store)
+        };
+
+        let extstored_policy = match self.extstored_policy {
+            Some(policy) => policy,
+            None => {
+                if self
+                    .config
+                    .get_or("remotefilelog", "useextstored", || true)?
+                {
+                    ExtStoredPolicy::Use
+                } else {
+                    ExtStoredPolicy::Ignore
+                }
+            }
+        };
+
+        Ok(ContentStore {
+            datastore,
+            local_datastore,
+            sourced_datastores,
+            local_mutabledatastore,
+            shared_mutabledatastore: primary,
+            remote_store,
+            shared_lfs_store,
+            local_lfs_store,
+            local_path,
+            cache_path,
+            extstored_policy,
+            read_only: self.read_only,
+            base_local_path,
+            remotestores,
+            format,
+            cache_on_read,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use async_runtime::block_on;
+    use minibytes::Bytes;
+    use tempfile::TempDir;
+    use types::testutil::*;
+
+    use super::*;
+    use crate::testutil::delta;
+    use crate::testutil::make_config;
+    use crate::testutil::FakeHgIdRemoteStore;
+
+    #[test]
+    fn test_new() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let localdir = TempDir::new()?;
+        let config = make_config(&cachedir);
+
+        ContentStore::new(&localdir, &config)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_get() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let localdir = TempDir::new()?;
+        let config = make_config(&cachedir);
+
+        let store = ContentStore::new(&localdir, &config)?;
+
+        let k1 = key("a", "2");
+        let delta = Delta {
+            data: Bytes::from(&[1, 2, 3, 4][..]),
+            base: None,
+            key: k1.clone(),
+        };
+        store.add(&delta, &Default::default())?;
+        let stored = store.get(StoreKey::hgid(k1))?;
+        assert_eq!(stored, StoreResult::Found(delta.data.as_ref().to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_suffix_namespaces_caches() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let localdir = TempDir::new()?;
+        let config = make_config(&cachedir);
+
+        let store = ContentStore::new(&localdir, &config)?;
+        let sibling = store.with_suffix("namespace", &config)?;
+
+        let k1 = key("a", "2");
+        let delta = Delta {
+            data: Bytes::from(&[1, 2, 3, 4][..]),
+            base: None,
+            key: k1.clone(),
+        };
+        store.add(&delta, &Default::default())?;
+        store.flush()?;
+
+        // The sibling lives under its own suffix and doesn't see the data.
+        assert_eq!(
+            sibling.get(StoreKey::hgid(k1.clone()))?,
+            StoreResult::NotFound(StoreKey::hgid(k1.clone()))
+        );
+
+        sibling.add(&delta, &Default::default())?;
+        assert_eq!(
+            sibling.get(StoreKey::hgid(k1))?,
+            StoreResult::Found(delta.data.as_ref().to_vec())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_dropped() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let localdir = TempDir::new()?;
+        let config = make_config(&cachedir);
+
+        let store = ContentStore::new(&localdir, &config)?;
+
+        let k1 = key("a", "2");
+        let delta = Delta {
+            data: Bytes::from(&[1, 2, 3, 4][..]),
+            base: None,
+            key: k1.clone(),
+        };
+        store.add(&delta, &Default::default())?;
+        drop(store);
+
+        let store = ContentStore::new(&localdir, &config)?;
+        let k = StoreKey::hgid(k1);
+        assert_eq!(store.get(k.clone())?, StoreResult::NotFound(k));
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_flush_get() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let localdir = TempDir::new()?;
+        let config = make_config(&cachedir);
+
+        let store = ContentStore::new(&localdir, &config)?;
+
+        let k1 = key("a", "2");
+        let delta = Delta {
+            data: Bytes::from(&[1, 2, 3, 4][..]),
+            base: None,
+            key: k1.clone(),
+        };
+        store.add(&delta, &Default::default())?;
+        store.flush()?;
+        let stored = store.get(StoreKey::hgid(k1))?;
+        assert_eq!(stored, StoreResult::Found(delta.data.as_ref().to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_flush_drop_get() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let localdir = TempDir::new()?;
+        let config = make_config(&cachedir);
+
+        let store = ContentStore::new(&localdir, &config)?;
+
+        let k1 = key("a", "2");
+        let delta = Delta {
+            data: Bytes::from(&[1, 2, 3, 4][..]),
+            base: None,
+            key: k1.clone(),
+        };
+        store.add(&delta, &Default::default())?;
+        store.flush()?;
+        drop(store);
+
+        let store = ContentStore::new(&localdir, &config)?;
+        let stored = store.get(StoreKey::hgid(k1))?;
+        assert_eq!(stored, StoreResult::Found(delta.data.as_ref().to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_async() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let localdir = TempDir::new()?;
+        let config = make_config(&cachedir);
+
+        let k1 = key("a", "1");
+        let k2 = key("b", "2");
+        let data = Bytes::from(&[1, 2, 3, 4][..]);
+
+        let mut map = HashMap::new();
+        map.insert(k1.clone(), (data.clone(), None));
+        let mut remotestore = FakeHgIdRemoteStore::new();
+        remotestore.data(map);
+
+        let store = ContentStoreBuilder::new(&config)
+            .local_path(&localdir)
+            .remotestore(Arc::new(remotestore))
+            .build()?;
+
+        block_on(async {
+            assert_eq!(
+                store.get_async(StoreKey::hgid(k1)).await?,
+                StoreResult::Found(data.as_ref().to_vec())
+            );
+
+            let k = StoreKey::hgid(k2);
+            assert_eq!(
+                store.get_async(k.clone()).await?,
+                StoreResult::NotFound(k)
+            );
+            Result::<()>::Ok(())
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_remote_store() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let localdir = TempDir::new()?;
+        let config = make_config(&cachedir);
+
+        let k = key("a", "1");
+        let data = Bytes::from(&[1, 2, 3, 4][..]);
+
+        let mut map = HashMap::new();
+        map.insert(k.clone(), (data.clone(), None));
+        let mut remotestore = FakeHgIdRemoteStore::new();
+        remotestore.data(map);
+
+        let store = ContentStoreBuilder::new(&config)
+            .local_path(&localdir)
+            .remotestore(Arc::new(remotestore))
+            .build()?;
+        let data_get = store.get(StoreKey::hgid(k))?;
+        assert_eq!(data_get, StoreResult::Found(data.as_ref().to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_multiple_remote_stores() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let localdir = TempDir::new()?;
+        let config = make_config(&cachedir);
+
+        let k1 = key("a", "1");
+        let k2 = key("b", "2");
+        let d1 = Bytes::from(&[1, 2, 3, 4][..]);
+        let d2 = Bytes::from(&[5, 6, 7, 8][..]);
+
+        let mut map = HashMap::new();
+        map.insert(k1.clone(), (d1.clone(), None));
+        let mut primary = FakeHgIdRemoteStore::new();
+        primary.data(map);
+
+        let mut map = HashMap::new();
+        map.insert(k2.clone(), (d2.clone(), None));
+        let mut mirror = FakeHgIdRemoteStore::new();
+        mirror.data(map);
+
+        let store = ContentStoreBuilder::new(&config)
+            .local_path(&localdir)
+            .add_remotestore(Arc::new(primary))
+            .add_remotestore(Arc::new(mirror))
+            .build()?;
+
+        // k1 comes from the primary, k2 only exists on the mirror.
+        assert_eq!(
+            store.get(StoreKey::hgid(k1))?,
+            StoreResult::Found(d1.as_ref().to_vec())
+        );
+        assert_eq!(
+            store.get(StoreKey::hgid(k2))?,
+            StoreResult::Found(d2.as_ref().to_vec())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_cache_on_read() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let localdir = TempDir::new()?;
+        let config = make_config(&cachedir);
+
+        let k = key("a", "1");
+        let data = Bytes::from(&[1, 2, 3, 4][..]);
+
+        let mut map = HashMap::new();
+        map.insert(k.clone(), (data.clone(), None));
+        let mut remotestore = FakeHgIdRemoteStore::new();
+        remotestore.data(map);
+
+        let store = ContentStoreBuilder::new(&config)
+            .local_path(&localdir)
+            .remotestore(Arc::new(remotestore))
+            .no_cache_on_read()
+            .build()?;
+
+        // The remote fetch succeeds, but the shared cache stays empty.
+        let k = StoreKey::hgid(k);
+        assert_eq!(
+            store.get(k.clone())?,
+            StoreResult::Found(data.as_ref().to_vec())
+        );
+        assert_eq!(
+            store.shared_mutabledatastore.get(k.clone())?,
+            StoreResult::NotFound(k.clone())
+        );
+
+        // Repeating the read still works, served from the in-memory scratch store.
+        assert_eq!(store.get(k)?, StoreResult::Found(data.as_ref().to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_on_remote_fetch() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let localdir = TempDir::new()?;
+        let config = make_config(&cachedir);
+
+        let k = key("a", "1");
+        let data = Bytes::from(&[1, 2, 3, 4][..]);
+
+        let mut map = HashMap::new();
+        map.insert(k.clone(), (data.clone(), None));
+        let mut remotestore = FakeHgIdRemoteStore::new();
+        remotestore.data(map);
+
+        let fetched = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let store = ContentStoreBuilder::new(&config)
+            .local_path(&localdir)
+            .remotestore(Arc::new(remotestore))
+            .on_remote_fetch({
+                let fetched = fetched.clone();
+                Arc::new(move |key, size| fetched.lock().push((key.clone(), size)))
+            })
+            .build()?;
+
+        let k = StoreKey::hgid(k);
+        assert_eq!(
+            store.get(k.clone())?,
+            StoreResult::Found(data.as_ref().to_vec())
+        );
+        // The second read is a shared cache hit and doesn't fire the callback.
+        assert_eq!(
+            store.get(k.clone())?,
+            StoreResult::Found(data.as_ref().to_vec())
+        );
+        assert_eq!(*fetched.lock(), vec![(k, data.len())]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_cache_only() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let config = make_config(&cachedir);
+
+        let k1 = key("a", "1");
+        let k2 = key("b", "2");
+        let d1 = Bytes::from(&[1, 2, 3, 4][..]);
+        let d2 = Bytes::from(&[5, 6, 7, 8][..]);
+
+        let mut map = HashMap::new();
+        map.insert(k1.clone(), (d1.clone(), None));
+        map.insert(k2.clone(), (d2.clone(), None));
+        let mut remotestore = FakeHgIdRemoteStore::new();
+        remotestore.data(map);
+
+        // The cache only has room for a single entry.
+        let store = ContentStoreBuilder::new(&config)
+            .remotestore(Arc::new(remotestore))
+            .memory_cache_only(ByteCount::from(4))
+            .build()?;
+
+        let k1 = StoreKey::hgid(k1);
+        let k2 = StoreKey::hgid(k2);
+
+        // Remote fetches succeed, but the shared on-disk cache stays empty.
+        assert_eq!(
+            store.get(k1.clone())?,
+            StoreResult::Found(d1.as_ref().to_vec())
+        );
+        assert_eq!(
+            store.shared_mutabledatastore.get(k1.clone())?,
+            StoreResult::NotFound(k1.clone())
+        );
+
+        // Fetching k2 evicts k1 from the memory cache; both reads still
+        // succeed, k1 via a refetch.
+        assert_eq!(
+            store.get(k2.clone())?,
+            StoreResult::Found(d2.as_ref().to_vec())
+        );
+        assert_eq!(store.get(k1)?, StoreResult::Found(d1.as_ref().to_vec()));
+        assert_eq!(
+            store.shared_mutabledatastore.get(k2.clone())?,
+            StoreResult::NotFound(k2)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_not_in_remote_store() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let localdir = TempDir::new()?;
+        let config = make_config(&cachedir);
+
+        let map = HashMap::new();
+        let mut remotestore = FakeHgIdRemoteStore::new();
+        remotestore.data(map);
+
+        let store = ContentStoreBuilder::new(&config)
+            .local_path(&localdir)
+            .remotestore(Arc::new(remotestore))
+            .build()?;
+
+        let k = StoreKey::hgid(key("a", "1"));
+        assert_eq!(store.get(k.clone())?, StoreResult::NotFound(k));
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch_location() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let localdir = TempDir::new()?;
+        let config = make_config(&cachedir);
+
+        let k = key("a", "1");
+        let data = Bytes::from(&[1, 2, 3, 4][..]);
+
+        let mut map = HashMap::new();
+        map.insert(k.clone(), (data.clone(), None));
+        let mut remotestore = FakeHgIdRemoteStore::new();
+        remotestore.data(map);
+
+        let store = ContentStoreBuilder::new(&config)
+            .local_path(&localdir)
+            .remotestore(Arc::new(remotestore))
+            .build()?;
+        store.get(StoreKey::hgid(k.clone()))?;
+        let k = StoreKey::hgid(k);
+        assert_eq!(
+            store.shared_mutabledatastore.get(k.clone())?,
+            StoreResult::Found(data.as_ref().to_vec())
+        );
+        assert_eq!(
+            store.local_mutabledatastore.as_ref().unwrap().get(k.clone())?,
+            StoreResult::NotFound(k)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_batch() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let localdir = TempDir::new()?;
+        let config = make_config(&cachedir);
+
+        let k1 = key("a", "1");
+        let k2 = key("b", "2");
+        let k3 = key("c", "3");
+        let data = Bytes::from(&[1, 2, 3, 4][..]);
+
+        let mut map = HashMap::new();
+        map.insert(k1.clone(), (data.clone(), None));
+        let mut remotestore = FakeHgIdRemoteStore::new();
+        remotestore.data(map);
+
+        let store = ContentStoreBuilder::new(&config)
+            .local_path(&localdir)
+            .remotestore(Arc::new(remotestore))
+            .build()?;
+
+        let delta = Delta {
+            data: data.clone(),
+            base: None,
+            key: k2.clone(),
+        };
+        store.add(&delta, &Default::default())?;
+
+        // k1 comes from the remote, k2 from the local store, k3 is nowhere to be found.
+        let results = store.get_batch(&[
+            StoreKey::hgid(k1),
+            StoreKey::hgid(k2),
+            StoreKey::hgid(k3.clone()),
+        ])?;
+        assert_eq!(results[0], StoreResult::Found(data.as_ref().to_vec()));
+        assert_eq!(results[1], StoreResult::Found(data.as_ref().to_vec()));
+        assert_eq!(results[2], StoreResult::NotFound(StoreKey::hgid(k3)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefetch_with_deadline_expired() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let localdir = TempDir::new()?;
+        let config = make_config(&cachedir);
+
+        let k1 = key("a", "1");
+        let k2 = key("b", "2");
+        let data = Bytes::from(&[1, 2, 3, 4][..]);
+
+        let mut map = HashMap::new();
+        map.insert(k1.clone(), (data.clone(), None));
+        let mut remotestore = FakeHgIdRemoteStore::new();
+        remotestore.data(map);
+
+        let store = ContentStoreBuilder::new(&config)
+            .local_path(&localdir)
+            .remotestore(Arc::new(remotestore))
+            .build()?;
+
+        // k2 can't be fetched: with an already expired deadline this reports it as
+        // missing instead of erroring out.
+        let missing = store.prefetch_with_deadline(
+            &[StoreKey::hgid(k1.clone()), StoreKey::hgid(k2.clone())],
+            Instant::now(),
+        )?;
+        assert_eq!(missing, vec![StoreKey::hgid(k2)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_suffixed_paths() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let localdir = TempDir::new()?;
+        let config = make_config(&cachedir);
+
+        let store = ContentStoreBuilder::new(&config)
+            .local_path(&localdir)
+            .suffix("manifests")
+            .build()?;
+
+        assert_eq!(
+            store.local_path(),
+            Some(localdir.path().join("manifests").as_path())
+        );
+        assert!(store
+            .cache_path()
+            .is_some_and(|path| path.ends_with("manifests")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_cache_purge() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let localdir = TempDir::new()?;
+        let mut config = make_config(&cachedir);
+
+        let store = ContentStore::new(&localdir, &config)?;
+
+        let k1 = key("a", "2");
+        let delta = Delta {
+            data: Bytes::from(&[1, 2, 3, 4][..]),
+            base: None,
+            key: k1.clone(),
+        };
+        store.add(&delta, &Default::default())?;
+        store.flush()?;
+
+        // No hgcache-purge config, nothing to purge.
+        let plan = store.plan_cache_purge(&config)?;
+        assert!(!plan.would_purge());
+
+        // A purge scheduled for the far future would wipe the cache, but planning it must
+        // not delete anything.
+        config.insert("hgcache-purge.test-purge".into(), "2100-1-1".into());
+        let plan = store.plan_cache_purge(&config)?;
+        assert!(plan.would_purge());
+        assert_eq!(plan.keys, vec!["test-purge".to_string()]);
+        assert!(!plan.paths.is_empty());
+        assert!(plan.total_bytes > 0);
+
+        // Planning doesn't record the run-once marker, so the plan is reproducible.
+        assert!(store.plan_cache_purge(&config)?.would_purge());
+        Ok(())
+    }
+
+    #[test]
+    fn test_content_size() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let localdir = TempDir::new()?;
+        let config = make_config(&cachedir);
+
+        let store = ContentStore::new(&localdir, &config)?;
+
+        let k1 = key("a", "2");
+        let delta = Delta {
+            data: Bytes::from(&[1, 2, 3, 4][..]),
+            base: None,
+            key: k1.clone(),
+        };
+        store.add(&delta, &Default::default())?;
+        store.flush()?;
+
+        // Re-open the store so the content is only available in its compressed on-disk form.
+        drop(store);
+        let store = ContentStore::new(&localdir, &config)?;
+
+        assert_eq!(
+            store.content_size(StoreKey::hgid(k1))?,
+            StoreResult::Found(4)
+        );
+
+        let k2 = StoreKey::hgid(key("b", "3"));
+        assert_eq!(store.content_size(k2.clone())?, StoreResult::NotFound(k2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_with_policy() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let localdir = TempDir::new()?;
+        let config = make_config(&cachedir);
+
+        let store = ContentStore::new(&localdir, &config)?;
+
+        let k1 = key("a", "2");
+        let pointer = Bytes::from(&b"pointer"[..]);
+        let delta = Delta {
+            data: pointer.clone(),
+            base: None,
+            key: k1.clone(),
+        };
+        store.add(
+            &delta,
+            &Metadata {
+                size: None,
+                flags: Some(Metadata::LFS_FLAG),
+            },
+        )?;
+
+        // The default policy returns the raw pointer content.
+        let k = StoreKey::hgid(k1);
+        assert_eq!(
+            store.get(k.clone())?,
+            StoreResult::Found(pointer.as_ref().to_vec())
+        );
+        assert_eq!(
+            store.get_with_policy(k.clone(), ExtStoredPolicy::Use)?,
+            StoreResult::Found(pointer.as_ref().to_vec())
+        );
+
+        // Ignore treats the flagged entry as missing.
+        assert_eq!(
+            store.get_with_policy(k.clone(), ExtStoredPolicy::Ignore)?,
+            StoreResult::NotFound(k)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_extstored_policy_override() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let localdir = TempDir::new()?;
+        // The config defaults to `remotefilelog.useextstored=true`, i.e. `Use`.
+        let config = make_config(&cachedir);
+
+        let store = ContentStoreBuilder::new(&config)
+            .local_path(&localdir)
+            .extstored_policy(ExtStoredPolicy::Ignore)
+            .build()?;
+
+        let k1 = key("a", "2");
+        let pointer = Bytes::from(&b"pointer"[..]);
+        let delta = Delta {
+            data: pointer,
+            base: None,
+            key: k1.clone(),
+        };
+        store.add(
+            &delta,
+            &Metadata {
+                size: None,
+                flags: Some(Metadata::LFS_FLAG),
+            },
+        )?;
+
+        // The override wins over the config-derived policy.
+        let k = StoreKey::hgid(k1);
+        assert_eq!(store.get(k.clone())?, StoreResult::NotFound(k));
+        Ok(())
+    }
+
+    #[test]
+    fn test_flush_durable() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let localdir = TempDir::new()?;
+        let config = make_config(&cachedir);
+
+        let store = ContentStore::new(&localdir, &config)?;
+
+        let k1 = key("a", "2");
+        let delta = Delta {
+            data: Bytes::from(&[1, 2, 3, 4][..]),
+            base: None,
+            key: k1.clone(),
+        };
+        store.add(&delta, &Default::default())?;
+        store.flush_durable()?;
+
+        // Reopen the store to make sure the data was committed to disk.
+        drop(store);
+        let store = ContentStore::new(&localdir, &config)?;
+        assert_eq!(
+            store.get(StoreKey::hgid(k1))?,
+            StoreResult::Found(delta.data.as_ref().to_vec())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_local_keys() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let localdir = TempDir::new()?;
+        let config = make_config(&cachedir);
+
+        let store = ContentStore::new(&localdir, &config)?;
+
+        let k1 = key("a", "1");
+        let k2 = key("b", "2");
+        for k in [&k1, &k2] {
+            let delta = Delta {
+                data: Bytes::from(&[1, 2, 3, 4][..]),
+                base: None,
+                key: k.clone(),
+            };
+            store.add(&delta, &Default::default())?;
+        }
+        store.flush()?;
+
+        let mut hgids = store
+            .local_keys()?
+            .map(|k| Ok(k?.hgid))
+            .collect::<Result<Vec<_>>>()?;
+        hgids.sort();
+        let mut expected = vec![k1.hgid, k2.hgid];
+        expected.sort();
+        assert_eq!(hgids, expected);
+
+        let store = ContentStoreBuilder::new(&config).no_local_store().build()?;
+        assert!(store.local_keys().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_disk_usage() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let localdir = TempDir::new()?;
+        let config = make_config(&cachedir);
+
+        let store = ContentStore::new(&localdir, &config)?;
+
+        let usage = store.disk_usage()?;
+        assert_eq!(usage.local_entries, 0);
+        assert_eq!(usage.shared_entries, 0);
+
+        for k in [key("a", "1"), key("b", "2")] {
+            let delta = Delta {
+                data: Bytes::from(&[1, 2, 3, 4][..]),
+                base: None,
+                key: k,
+            };
+            store.add(&delta, &Default::default())?;
+        }
+        store.flush()?;
+
+        let usage = store.disk_usage()?;
+        assert_eq!(usage.local_entries, 2);
+        assert!(usage.local_bytes > 0);
+        assert_eq!(usage.shared_entries, 0);
+        assert_eq!(usage.total_entries(), 2);
+        assert_eq!(usage.total_bytes(), usage.local_bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_missing_local() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let localdir = TempDir::new()?;
+        let config = make_config(&cachedir);
+
+        let k1 = key("a", "1");
+        let k2 = key("b", "2");
+        let data = Bytes::from(&[1, 2, 3, 4][..]);
+
+        let mut map = HashMap::new();
+        map.insert(k2.clone(), (data.clone(), None));
+        let mut remotestore = FakeHgIdRemoteStore::new();
+        remotestore.data(map);
+
+        let store = ContentStoreBuilder::new(&config)
+            .local_path(&localdir)
+            .remotestore(Arc::new(remotestore))
+            .build()?;
+
+        let delta = Delta {
+            data,
+            base: None,
+            key: k1.clone(),
+        };
+        store.add(&delta, &Default::default())?;
+
+        // k2 is only available remotely: it is missing locally, and asking doesn't
+        // fetch it.
+        let missing = store.get_missing_local(&[StoreKey::hgid(k1), StoreKey::hgid(k2.clone())])?;
+        assert_eq!(missing, vec![StoreKey::hgid(k2.clone())]);
+
+        let k = StoreKey::hgid(k2);
+        assert_eq!(
+            store.shared_mutabledatastore.get(k.clone())?,
+            StoreResult::NotFound(k)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_promote_to_shared() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let localdir = TempDir::new()?;
+        let config = make_config(&cachedir);
+
+        let store = ContentStore::new(&localdir, &config)?;
+
+        let k1 = key("a", "1");
+        let k2 = key("b", "2");
+        let delta = Delta {
+            data: Bytes::from(&[1, 2, 3, 4][..]),
+            base: None,
+            key: k1.clone(),
+        };
+        store.add(&delta, &Default::default())?;
+
+        // k1 was written locally, k2 doesn't exist and is reported back.
+        let not_promoted = store.promote_to_shared(&[k1.clone(), k2.clone()])?;
+        assert_eq!(not_promoted, vec![k2]);
+
+        let k = StoreKey::hgid(k1);
+        assert_eq!(
+            store.shared_mutabledatastore.get(k)?,
+            StoreResult::Found(delta.data.as_ref().to_vec())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let localdir = TempDir::new()?;
+        let config = make_config(&cachedir);
+
+        let store = ContentStore::new(&localdir, &config)?;
+
+        // Parentless content hashing to its key verifies cleanly.
+        let data = Bytes::from(&[1, 2, 3, 4][..]);
+        let k1 = Key::new(
+            repo_path_buf("a"),
+            HgId::from_content(&data, Parents::None),
+        );
+        let delta = Delta {
+            data: data.clone(),
+            base: None,
+            key: k1.clone(),
+        };
+        store.add(&delta, &Default::default())?;
+
+        // An entry whose recorded size disagrees with the stored content is flagged.
+        let k2 = key("b", "2");
+        let delta = Delta {
+            data,
+            base: None,
+            key: k2.clone(),
+        };
+        let metadata = Metadata {
+            size: Some(42),
+            flags: None,
+        };
+        store.add(&delta, &metadata)?;
+
+        // k3 isn't stored at all and is skipped.
+        let k3 = key("c", "3");
+
+        assert_eq!(store.verify(&[k1, k2.clone(), k3])?, vec![k2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_only() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let localdir = TempDir::new()?;
+        let config = make_config(&cachedir);
+
+        let k1 = key("a", "2");
+        let delta = Delta {
+            data: Bytes::from(&[1, 2, 3, 4][..]),
+            base: None,
+            key: k1.clone(),
+        };
+
+        let store = ContentStore::new(&localdir, &config)?;
+        store.add(&delta, &Default::default())?;
+        store.flush()?;
+        drop(store);
+
+        let store = ContentStoreBuilder::new(&config)
+            .local_path(&localdir)
+            .read_only()
+            .build()?;
+
+        // Reads still work.
+        let stored = store.get(StoreKey::hgid(k1.clone()))?;
+        assert_eq!(stored, StoreResult::Found(delta.data.as_ref().to_vec()));
+
+        // Writes are cleanly rejected.
+        let delta2 = Delta {
+            data: Bytes::from(&[5, 6, 7, 8][..]),
+            base: None,
+            key: key("b", "3"),
+        };
+        assert!(store.add(&delta2, &Default::default()).is_err());
+        assert!(store.flush().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_with_source() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let localdir = TempDir::new()?;
+        let config = make_config(&cachedir);
+
+        let k1 = key("a", "1");
+        let k2 = key("b", "2");
+        let data = Bytes::from(&[1, 2, 3, 4][..]);
+
+        let mut map = HashMap::new();
+        map.insert(k2.clone(), (data.clone(), None));
+        let mut remotestore = FakeHgIdRemoteStore::new();
+        remotestore.data(map);
+
+        let store = ContentStoreBuilder::new(&config)
+            .local_path(&localdir)
+            .remotestore(Arc::new(remotestore))
+            .build()?;
+
+        let delta = Delta {
+            data: data.clone(),
+            base: None,
+            key: k1.clone(),
+        };
+        store.add(&delta, &Default::default())?;
+
+        assert_eq!(
+            store.get_with_source(StoreKey::hgid(k1))?,
+            StoreResult::Found((data.as_ref().to_vec(), StoreSource::LocalIndexedLog))
+        );
+
+        // The first fetch of k2 comes from the remote, repeating it hits the shared cache
+        // the remote fetch wrote through.
+        assert_eq!(
+            store.get_with_source(StoreKey::hgid(k2.clone()))?,
+            StoreResult::Found((data.as_ref().to_vec(), StoreSource::Remote))
+        );
+        assert_eq!(
+            store.get_with_source(StoreKey::hgid(k2))?,
+            StoreResult::Found((data.as_ref().to_vec(), StoreSource::SharedIndexedLog))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_overlay() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let localdir = TempDir::new()?;
+        let config = make_config(&cachedir);
+
+        let k1 = key("a", "2");
+        let overlay_data = Bytes::from(&b"overlay"[..]);
+
+        let store = ContentStoreBuilder::new(&config)
+            .local_path(&localdir)
+            .memory_overlay(vec![(k1.clone(), overlay_data.clone(), Default::default())])
+            .build()?;
+
+        // The overlay is consulted before the on-disk stores, even for keys that were
+        // written locally.
+        let delta = Delta {
+            data: Bytes::from(&b"on-disk"[..]),
+            base: None,
+            key: k1.clone(),
+        };
+        store.add(&delta, &Default::default())?;
+
+        assert_eq!(
+            store.get(StoreKey::hgid(k1))?,
+            StoreResult::Found(overlay_data.as_ref().to_vec())
+        );
+
+        // The overlay also works without a local store.
+        let k2 = key("b", "3");
+        let store = ContentStoreBuilder::new(&config)
+            .no_local_store()
+            .memory_overlay(vec![(k2.clone(), overlay_data.clone(), Default::default())])
+            .build()?;
+        assert_eq!(
+            store.get(StoreKey::hgid(k2))?,
+            StoreResult::Found(overlay_data.as_ref().to_vec())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_local_store() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let config = make_config(&cachedir);
+        assert!(ContentStoreBuilder::new(&config).build().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_health_check() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let localdir = TempDir::new()?;
+        let config = make_config(&cachedir);
+
+        let mut remotestore = FakeHgIdRemoteStore::new();
+        remotestore.data(HashMap::new());
+
+        let store = ContentStoreBuilder::new(&config)
+            .local_path(&localdir)
+            .remotestore(Arc::new(remotestore))
+            .build()?;
+
+        let report = store.health_check();
+        assert!(report.is_healthy());
+        assert_eq!(report.local_store, SubsystemHealth::Ok);
+        assert_eq!(report.shared_store, SubsystemHealth::Ok);
+        assert_eq!(report.cache_path, SubsystemHealth::Ok);
+        assert_eq!(report.remote, SubsystemHealth::Ok);
+        Ok(())
+    }
+
+    #[test]
+    fn test_health_check_no_local() -> Result<()> {
+        let cachedir = TempDir::new()?;
+        let config = make_config(&cachedir);
+
+        let store = ContentStoreBuilder::new(&config).no_local_store().build()?;
+        let report = store.health_check();
+        assert!(report.is_healthy());
+        assert_eq!(report.local_store, SubsystemHealth::Ok);
+        Ok(())
+    }
+}
@@ -46,6 +46,21 @@ impl<T> From<StoreResult<T>> for Option<T> {
     }
 }
 
+impl<T> StoreResult<T> {
+    /// Apply `f` to the found value, preserving the `NotFound` key as is.
+    pub fn map_found<U>(self, f: impl FnOnce(T) -> U) -> StoreResult<U> {
+        match self {
+            StoreResult::Found(v) => StoreResult::Found(f(v)),
+            StoreResult::NotFound(key) => StoreResult::NotFound(key),
+        }
+    }
+
+    /// Return the found value, discarding the `NotFound` key.
+    pub fn found(self) -> Option<T> {
+        self.into()
+    }
+}
+
 pub trait HgIdDataStore: LocalStore + Send + Sync {
     fn get(&self, key: StoreKey) -> Result<StoreResult<Vec<u8>>>;
     fn refresh(&self) -> Result<()>;
@@ -70,6 +85,13 @@ pub trait HgIdMutableDeltaStore: HgIdDataStore + Send + Sync {
     fn add(&self, delta: &Delta, metadata: &Metadata) -> Result<()>;
     fn flush(&self) -> Result<Option<Vec<PathBuf>>>;
 
+    /// Like `flush`, but also `fsync(2)` the flushed files before returning, so the data is
+    /// guaranteed to have hit the disk. Considerably slower than `flush`; only use this when
+    /// durability matters more than latency, e.g. before reporting a commit as done.
+    fn flush_durable(&self) -> Result<Option<Vec<PathBuf>>> {
+        self.flush()
+    }
+
     fn add_file(&self, entry: &FileEntry) -> Result<()> {
         let delta = Delta {
             data: entry.data()?,
@@ -142,6 +164,10 @@ impl<T: HgIdMutableDeltaStore + ?Sized, U: Deref<Target = T> + Send + Sync> HgId
     fn flush(&self) -> Result<Option<Vec<PathBuf>>> {
         T::flush(self)
     }
+
+    fn flush_durable(&self) -> Result<Option<Vec<PathBuf>>> {
+        T::flush_durable(self)
+    }
 }
 
 #[cfg(test)]
@@ -180,4 +206,25 @@ mod tests {
             flags: Some(9879489),
         });
     }
+
+    #[test]
+    fn test_store_result_map_found() {
+        let found: StoreResult<u32> = StoreResult::Found(21);
+        assert_eq!(found.map_found(|v| v * 2), StoreResult::Found(42));
+
+        let key = StoreKey::Content(ContentHash::sha256(&Bytes::from(b"foo".to_vec())), None);
+        let not_found: StoreResult<u32> = StoreResult::NotFound(key.clone());
+        assert_eq!(
+            not_found.map_found(|v| v.to_string()),
+            StoreResult::NotFound(key)
+        );
+    }
+
+    #[test]
+    fn test_store_result_found() {
+        assert_eq!(StoreResult::Found(42).found(), Some(42));
+
+        let key = StoreKey::Content(ContentHash::sha256(&Bytes::from(b"foo".to_vec())), None);
+        assert_eq!(StoreResult::<u32>::NotFound(key).found(), None);
+    }
 }
@@ -23,6 +23,7 @@ use edenapi_types::TreeEntry;
 use indexedlog::log::IndexOutput;
 use lz4_pyframe::compress;
 use lz4_pyframe::decompress;
+use lz4_pyframe::decompress_size;
 use minibytes::Bytes;
 use once_cell::sync::OnceCell;
 use storemodel::SerializationFormat;
@@ -185,6 +186,18 @@ impl Entry {
         &self.metadata
     }
 
+    /// Size of the uncompressed content, without materializing it. The size is read from the
+    /// lz4 header when the content hasn't been decompressed yet.
+    pub fn content_len(&self) -> Result<u64> {
+        if let Some(content) = self.content.get() {
+            return Ok(content.len() as u64);
+        }
+        if let Some(compressed) = self.compressed_content.as_ref() {
+            return Ok(decompress_size(compressed)? as u64);
+        }
+        bail!("No content");
+    }
+
     pub fn node(&self) -> Id20 {
         self.node
     }
@@ -293,6 +306,19 @@ impl IndexedLogHgIdDataStore {
     }
 
     /// Write an entry to the IndexedLog
+    /// Total on-disk size in bytes of the underlying log(s), read from the log metadata.
+    pub fn disk_bytes(&self) -> u64 {
+        self.store.disk_bytes()
+    }
+
+    /// Approximate number of entries in the store.
+    ///
+    /// Unlike `disk_bytes`, there is no metadata recording this, so the entry boundaries
+    /// are scanned (the entries themselves aren't deserialized).
+    pub fn entry_count(&self) -> u64 {
+        self.store.read().iter().count() as u64
+    }
+
     pub fn put_entry(&self, entry: Entry) -> Result<()> {
         entry.write_to_log(&self.store)
     }
@@ -413,8 +439,10 @@ mod tests {
     use types::testutil::*;
 
     use super::*;
+    use crate::lfs::LfsStore;
     use crate::scmstore::FileAttributes;
     use crate::scmstore::FileStore;
+    use crate::scmstore::LfsDecision;
     use crate::testutil::*;
 
     #[test]
@@ -773,6 +801,61 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_scmstore_write_lfs_predicate() -> Result<()> {
+        let lfs_key = key("a", "def6f29d7b61f9cb70b2f14f79cd5c43c38e21b2");
+        let nonlfs_key = key("b", "def6f29d7b61f9cb70b2f14f79cd5c43c38e21b3");
+        let content = Bytes::from(&b"1234"[..]);
+
+        // Setup local indexedlog
+        let tmp = TempDir::new()?;
+        let config = IndexedLogHgIdDataStoreConfig {
+            max_log_count: None,
+            max_bytes_per_log: None,
+            max_bytes: None,
+        };
+        let local = Arc::new(IndexedLogHgIdDataStore::new(
+            &BTreeMap::<&str, &str>::new(),
+            &tmp,
+            &config,
+            StoreType::Rotated,
+            SerializationFormat::Hg,
+        )?);
+
+        let lfs_tmp = TempDir::new()?;
+        let lfs_local = Arc::new(LfsStore::rotated(&lfs_tmp, &BTreeMap::<&str, &str>::new())?);
+
+        // No threshold is set, so without the predicate everything would be non-LFS.
+        let mut store = FileStore::empty();
+        store.indexedlog_local = Some(local.clone());
+        store.lfs_local = Some(lfs_local.clone());
+        let lfs_path = lfs_key.path.clone();
+        store.lfs_predicate = Some(Arc::new(move |k: &Key, _size| {
+            if k.path == lfs_path {
+                LfsDecision::Lfs
+            } else {
+                LfsDecision::NonLfs
+            }
+        }));
+
+        store.write_batch(
+            [
+                (lfs_key.clone(), content.clone(), Default::default()),
+                (nonlfs_key.clone(), content.clone(), Default::default()),
+            ]
+            .into_iter(),
+        )?;
+
+        // The small blob went to LFS solely because of the predicate.
+        assert_eq!(
+            lfs_local.get(StoreKey::hgid(lfs_key.clone()))?,
+            StoreResult::Found(content.as_ref().to_vec())
+        );
+        assert!(local.get_raw_entry(&lfs_key.hgid)?.is_none());
+        assert!(local.get_raw_entry(&nonlfs_key.hgid)?.is_some());
+        Ok(())
+    }
+
     #[test]
     fn test_scmstore_extstore_ignore() -> Result<()> {
         let tempdir = TempDir::new()?;
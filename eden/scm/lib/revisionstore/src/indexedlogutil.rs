@@ -21,6 +21,7 @@ use indexedlog::log::Log;
 use indexedlog::log::LogLookupIter;
 use indexedlog::rotate;
 use indexedlog::rotate::RotateLog;
+use indexedlog::rotate::RotateLowLevelExt;
 use indexedlog::rotate::RotateLogLookupIter;
 use indexedlog::OpenWithRepair;
 use indexedlog::Result as IndexedlogResult;
@@ -79,6 +80,11 @@ impl Store {
         self.write().flush()
     }
 
+    /// Total on-disk size in bytes of the underlying log(s).
+    pub fn disk_bytes(&self) -> u64 {
+        self.read().disk_bytes()
+    }
+
     fn sync_if_changed_on_disk(&self) -> RwLockReadGuard<'_, Inner> {
         let log = self.inner.read();
 
@@ -140,6 +146,15 @@ impl Inner {
         }
     }
 
+    /// Total on-disk size in bytes of the underlying log(s), read from the log metadata
+    /// rather than by scanning entries. In-memory data not yet flushed isn't counted.
+    pub fn disk_bytes(&self) -> u64 {
+        match self {
+            Self::Permanent(log) => log.version().1,
+            Self::Rotated(log) => log.logs().iter().map(|log| log.version().1).sum(),
+        }
+    }
+
     pub fn iter(&self) -> Box<dyn Iterator<Item = IndexedlogResult<&[u8]>> + '_> {
         match self {
             Self::Permanent(log) => Box::new(log.iter()),
@@ -5,6 +5,7 @@
  * LICENSE file in the root directory of this source tree.
  */
 
+use std::cell::Cell;
 use std::cmp::min;
 use std::collections::BTreeMap;
 use std::collections::HashMap;
@@ -72,6 +73,7 @@ use lfs_protocol::RequestBatch;
 use lfs_protocol::RequestObject;
 use lfs_protocol::ResponseBatch;
 use lfs_protocol::Sha256 as LfsSha256;
+use metrics::Counter;
 use mincode::deserialize;
 use mincode::serialize;
 use minibytes::Bytes;
@@ -119,6 +121,8 @@ use crate::util::get_lfs_blobs_path;
 use crate::util::get_lfs_objects_path;
 use crate::util::get_lfs_pointers_path;
 
+static LFS_IGNORED_PREFETCH_ERRORS: Counter = Counter::new_counter("lfs.ignored_prefetch_errors");
+
 /// The `LfsPointersStore` holds the mapping between a `HgId` and the content hash (sha256) of the LFS blob.
 struct LfsPointersStore(Store);
 
@@ -739,6 +743,29 @@ impl LfsStore {
         self.blobs.get(hash.sha256_ref(), pointer.size)
     }
 
+    /// Re-hash the locally stored blob for `key` and compare it against the Sha256 oid
+    /// recorded in its pointer. The indexedlog blobs store only checks the blob length on
+    /// read for performance, so this is where actual corruption gets caught.
+    ///
+    /// Returns `None` when no pointer or blob is stored for `key`.
+    pub(crate) fn verify_blob(&self, key: &Key) -> Result<Option<bool>> {
+        let pointer = match self.pointers.entry(&StoreKey::hgid(key.clone()))? {
+            None => return Ok(None),
+            Some(pointer) => pointer,
+        };
+        let hash = match pointer.content_hashes.get(&ContentHashType::Sha256) {
+            None => return Ok(None),
+            Some(hash) => hash.clone().unwrap_sha256(),
+        };
+        let blob = match self.blobs.get(&hash, pointer.size)? {
+            None => return Ok(None),
+            Some(blob) => blob,
+        };
+        Ok(Some(
+            blob.len() as u64 == pointer.size && ContentHash::sha256(&blob).unwrap_sha256() == hash,
+        ))
+    }
+
     pub fn add_blob(&self, hash: &Sha256, blob: Bytes) -> Result<()> {
         self.blobs.add(hash, blob)
     }
@@ -877,10 +904,7 @@ impl From<LfsPointersEntry> for ContentMetadata {
 
 impl LfsStore {
     fn blob(&self, key: StoreKey) -> Result<StoreResult<Bytes>> {
-        match self.blob_impl(key)? {
-            StoreResult::Found((_, blob)) => Ok(StoreResult::Found(blob)),
-            StoreResult::NotFound(key) => Ok(StoreResult::NotFound(key)),
-        }
+        Ok(self.blob_impl(key)?.map_found(|(_, blob)| blob))
     }
 
     pub fn metadata(&self, key: StoreKey) -> Result<StoreResult<ContentMetadata>> {
@@ -1010,6 +1034,27 @@ impl LfsPointersEntry {
     }
 }
 
+thread_local! {
+    /// Deadline past which `send_with_retry` will stop retrying. Set via
+    /// `with_prefetch_deadline` by `ContentStore::prefetch_with_deadline`.
+    static PREFETCH_DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// Run `f` with a deadline that the LFS retry loop will not retry past.
+pub(crate) fn with_prefetch_deadline<T>(deadline: Instant, f: impl FnOnce() -> T) -> T {
+    let previous = PREFETCH_DEADLINE.with(|cell| cell.replace(Some(deadline)));
+    let result = f();
+    PREFETCH_DEADLINE.with(|cell| cell.set(previous));
+    result
+}
+
+fn prefetch_deadline_exceeded() -> bool {
+    PREFETCH_DEADLINE.with(|cell| {
+        cell.get()
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    })
+}
+
 impl LfsRemote {
     pub fn from_config(config: &dyn Config) -> Result<Self> {
         let mut url: String = config.must_get("lfs", "url")?;
@@ -1294,6 +1339,13 @@ impl LfsRemote {
                 };
 
                 if let Some(backoff_time) = backoff_time {
+                    if prefetch_deadline_exceeded() {
+                        tracing::debug!(
+                            retry_strategy = ?retry_strategy,
+                            "prefetch deadline exceeded, not retrying",
+                        );
+                        return Err(FetchError { url, method, error });
+                    }
                     if backoff_time > 0.0 {
                         let sleep_time =
                             Duration::from_secs_f32(thread_rng().gen_range(0.0..backoff_time));
@@ -1865,7 +1917,13 @@ impl HgIdDataStore for LfsRemoteStore {
     fn get(&self, key: StoreKey) -> Result<StoreResult<Vec<u8>>> {
         match self.prefetch(&[key.clone()]) {
             Ok(_) => self.store.get(key),
-            Err(_) if self.remote.ignore_prefetch_errors => Ok(StoreResult::NotFound(key)),
+            Err(e) if self.remote.ignore_prefetch_errors => {
+                // Swallowing the error here hides LFS server issues entirely,
+                // make sure operators still have a signal.
+                LFS_IGNORED_PREFETCH_ERRORS.increment();
+                warn!("Ignoring LFS prefetch error for {:?}: {:#}", key, e);
+                Ok(StoreResult::NotFound(key))
+            }
             Err(e) => Err(e.context(format!("Failed to fetch: {:?}", key))),
         }
     }
@@ -107,6 +107,7 @@ mod sliceext;
 mod types;
 mod unionstore;
 
+pub mod contentstore;
 pub mod datastore;
 pub mod edenapi;
 pub mod error;
@@ -124,6 +125,13 @@ pub mod util;
 use ::types::Key;
 pub use revisionstore_types::*;
 
+pub use crate::contentstore::ContentStore;
+pub use crate::contentstore::ContentStoreBuilder;
+pub use crate::contentstore::DiskUsage;
+pub use crate::contentstore::ExtStoredPolicy;
+pub use crate::contentstore::HealthReport;
+pub use crate::contentstore::StoreSource;
+pub use crate::contentstore::SubsystemHealth;
 pub use crate::datastore::ContentMetadata;
 pub use crate::datastore::Delta;
 pub use crate::datastore::HgIdDataStore;
@@ -11,6 +11,8 @@ pub use self::fetch::KeyFetchError;
 pub use self::file::FileAttributes;
 pub use self::file::FileAuxData;
 pub use self::file::FileStore;
+pub use self::file::LfsDecision;
+pub use self::file::LfsPredicate;
 pub use self::file::StoreFile;
 pub use self::tree::TreeStore;
 pub use self::util::file_to_async_key_stream;
@@ -6,6 +6,7 @@
  */
 
 use std::fs;
+use std::io;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::Arc;
@@ -21,6 +22,7 @@ use hgtime::HgTime;
 use parking_lot::Mutex;
 use progress_model::AggregatingProgressBar;
 use storemodel::SerializationFormat;
+use types::Key;
 
 use crate::indexedlogauxstore::AuxStore;
 use crate::indexedlogdatastore::IndexedLogHgIdDataStore;
@@ -31,10 +33,13 @@ use crate::lfs::LfsClient;
 use crate::lfs::LfsStore;
 use crate::scmstore::activitylogger::ActivityLogger;
 use crate::scmstore::file::FileStoreMetrics;
+use crate::scmstore::file::LfsDecision;
+use crate::scmstore::file::LfsPredicate;
 use crate::scmstore::tree::TreeMetadataMode;
 use crate::scmstore::FileStore;
 use crate::scmstore::TreeStore;
 use crate::util::check_run_once;
+use crate::util::would_run_once;
 use crate::util::get_cache_path;
 use crate::util::get_indexedlogdatastore_aux_path;
 use crate::util::get_indexedlogdatastore_path;
@@ -60,6 +65,7 @@ pub struct FileStoreBuilder<'a> {
     edenapi: Option<Arc<SaplingRemoteApiFileStore>>,
     cas_client: Option<Arc<dyn CasClient>>,
     format: Option<SerializationFormat>,
+    lfs_predicate: Option<LfsPredicate>,
 }
 
 impl<'a> FileStoreBuilder<'a> {
@@ -76,6 +82,7 @@ impl<'a> FileStoreBuilder<'a> {
             edenapi: None,
             cas_client: None,
             format: None,
+            lfs_predicate: None,
         }
     }
 
@@ -129,6 +136,16 @@ impl<'a> FileStoreBuilder<'a> {
         self
     }
 
+    /// Set a per-key predicate overriding the `lfs.threshold` based routing of writes.
+    /// Without it, blobs are routed to LFS purely based on their size.
+    pub fn lfs_predicate(
+        mut self,
+        predicate: impl Fn(&Key, usize) -> LfsDecision + Send + Sync + 'static,
+    ) -> Self {
+        self.lfs_predicate = Some(Arc::new(predicate));
+        self
+    }
+
     #[context("unable to get LFS threshold")]
     fn get_lfs_threshold(&self) -> Result<Option<ByteCount>> {
         let enable_lfs = self.config.get_or_default::<bool>("remotefilelog", "lfs")?;
@@ -383,6 +400,7 @@ impl<'a> FileStoreBuilder<'a> {
         tracing::trace!(target: "revisionstore::filestore", "constructing FileStore");
         Ok(FileStore {
             lfs_threshold_bytes,
+            lfs_predicate: self.lfs_predicate.take(),
             edenapi_retries,
             allow_write_lfs_ptrs,
 
@@ -755,6 +773,24 @@ fn use_edenapi_via_config(config: &dyn Config) -> Result<bool> {
     Ok(use_edenapi)
 }
 
+/// What a hgcache purge would remove, as computed by `plan_cache_buster`.
+#[derive(Debug, Default)]
+pub struct CacheBusterPlan {
+    /// The hgcache-purge.$KEY config keys that would trigger a purge.
+    pub keys: Vec<String>,
+    /// Top-level paths in the hgcache that would be deleted.
+    pub paths: Vec<PathBuf>,
+    /// Total size of the files that would be deleted.
+    pub total_bytes: u64,
+}
+
+impl CacheBusterPlan {
+    /// Whether a purge would actually be triggered.
+    pub fn would_purge(&self) -> bool {
+        !self.keys.is_empty()
+    }
+}
+
 /// Reads the configs and deletes the hgcache if a hgcache-purge.$KEY=$DATE value hasn't already
 /// been processed.
 pub fn check_cache_buster(config: &dyn Config, store_path: &Path) {
@@ -764,32 +800,167 @@ pub fn check_cache_buster(config: &dyn Config, store_path: &Path) {
             .and_then(|c| HgTime::parse(&c))
         {
             if check_run_once(store_path, &key, cutoff) {
-                let _ = delete_hgcache(store_path);
+                match delete_hgcache(store_path) {
+                    Ok(errors) => {
+                        for (path, error) in errors {
+                            tracing::warn!(?path, %error, "error purging hgcache entry");
+                        }
+                    }
+                    Err(error) => tracing::warn!(%error, "error purging hgcache"),
+                }
                 break;
             }
         }
     }
 }
 
-/// Recursively deletes the contents of the path, excluding the run-once marker file.
-/// Ignores errors on individual files or directories.
-fn delete_hgcache(store_path: &Path) -> Result<()> {
-    for file in fs::read_dir(store_path)? {
-        let _ = (|| -> Result<()> {
+/// Dry-run version of `check_cache_buster`: report what a purge would delete without
+/// deleting anything or recording the run-once markers.
+pub fn plan_cache_buster(config: &dyn Config, store_path: &Path) -> Result<CacheBusterPlan> {
+    let mut plan = CacheBusterPlan::default();
+    for key in config.keys("hgcache-purge").into_iter() {
+        if let Some(cutoff) = config
+            .get("hgcache-purge", &key)
+            .and_then(|c| HgTime::parse(&c))
+        {
+            if would_run_once(store_path, &key, cutoff) {
+                plan.keys.push(key.to_string());
+            }
+        }
+    }
+
+    if plan.would_purge() {
+        for file in fs::read_dir(store_path)? {
             let file = file?;
             if file.file_name() == RUN_ONCE_FILENAME {
-                return Ok(());
+                continue;
+            }
+            plan.total_bytes += dir_size(&file.path());
+            plan.paths.push(file.path());
+        }
+    }
+
+    Ok(plan)
+}
+
+/// Total size of the files under `path`, ignoring files that can't be read.
+fn dir_size(path: &Path) -> u64 {
+    let mut size = 0;
+    if path.is_dir() {
+        if let Ok(entries) = fs::read_dir(path) {
+            for entry in entries.flatten() {
+                size += dir_size(&entry.path());
             }
+        }
+    } else if let Ok(metadata) = fs::symlink_metadata(path) {
+        size += metadata.len();
+    }
+    size
+}
 
-            let path = file.path();
-            let file_type = file.file_type()?;
-            if file_type.is_dir() {
-                fs::remove_dir_all(path)?;
-            } else if file_type.is_file() || file_type.is_symlink() {
-                fs::remove_file(path)?;
+/// Recursively deletes the contents of the path, excluding the run-once marker file.
+/// Top-level entries are deleted in parallel. Errors on individual files or directories
+/// don't stop the deletion, they are reported back so callers can log them.
+fn delete_hgcache(store_path: &Path) -> Result<Vec<(PathBuf, io::Error)>> {
+    let mut entries = Vec::new();
+    for file in fs::read_dir(store_path)? {
+        let file = file?;
+        if file.file_name() == RUN_ONCE_FILENAME {
+            continue;
+        }
+        entries.push(file.path());
+    }
+
+    Ok(std::thread::scope(|s| {
+        let handles: Vec<_> = entries
+            .iter()
+            .map(|path| {
+                s.spawn(move || {
+                    let mut errors = Vec::new();
+                    remove_recursive(path, &mut errors);
+                    errors
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap_or_default())
+            .collect()
+    }))
+}
+
+/// Recursively delete `path`, pushing the paths that couldn't be removed into `errors`.
+fn remove_recursive(path: &Path, errors: &mut Vec<(PathBuf, io::Error)>) {
+    let file_type = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata.file_type(),
+        Err(e) => {
+            errors.push((path.to_path_buf(), e));
+            return;
+        }
+    };
+
+    if file_type.is_dir() {
+        match fs::read_dir(path) {
+            Ok(files) => {
+                for file in files {
+                    match file {
+                        Ok(file) => remove_recursive(&file.path(), errors),
+                        Err(e) => errors.push((path.to_path_buf(), e)),
+                    }
+                }
+            }
+            Err(e) => {
+                errors.push((path.to_path_buf(), e));
+                return;
             }
-            Ok(())
-        })();
+        }
+        if let Err(e) = fs::remove_dir(path) {
+            errors.push((path.to_path_buf(), e));
+        }
+    } else if let Err(e) = fs::remove_file(path) {
+        errors.push((path.to_path_buf(), e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_delete_hgcache_keeps_marker() -> Result<()> {
+        let dir = TempDir::new()?;
+        fs::write(dir.path().join(RUN_ONCE_FILENAME), b"\nmarker\n")?;
+        fs::create_dir(dir.path().join("indexedlogdatastore"))?;
+        fs::write(dir.path().join("indexedlogdatastore").join("log"), b"data")?;
+        fs::write(dir.path().join("toplevel"), b"data")?;
+
+        let errors = delete_hgcache(dir.path())?;
+        assert!(errors.is_empty());
+        assert!(dir.path().join(RUN_ONCE_FILENAME).exists());
+        assert!(!dir.path().join("indexedlogdatastore").exists());
+        assert!(!dir.path().join("toplevel").exists());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_delete_hgcache_reports_errors() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new()?;
+        let subdir = dir.path().join("subdir");
+        fs::create_dir(&subdir)?;
+        let undeletable = subdir.join("undeletable");
+        fs::write(&undeletable, b"data")?;
+
+        // A read-only directory prevents the removal of the files within it.
+        fs::set_permissions(&subdir, fs::Permissions::from_mode(0o555))?;
+        let errors = delete_hgcache(dir.path())?;
+        fs::set_permissions(&subdir, fs::Permissions::from_mode(0o755))?;
+
+        assert!(errors.iter().any(|(path, _)| path == &undeletable));
+        Ok(())
     }
-    Ok(())
 }
@@ -68,11 +68,26 @@ use crate::SaplingRemoteApiFileStore;
 use crate::StoreKey;
 use crate::StoreResult;
 
+/// How a blob should be stored, as returned by the per-key LFS routing predicate.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LfsDecision {
+    /// Store the blob in the LFS store, regardless of its size.
+    Lfs,
+    /// Store the blob in the regular IndexedLog store, regardless of its size.
+    NonLfs,
+}
+
+/// Predicate deciding whether a blob of the given size should be stored as LFS,
+/// overriding the `lfs.threshold` based decision.
+pub type LfsPredicate = Arc<dyn Fn(&Key, usize) -> LfsDecision + Send + Sync>;
+
 #[derive(Clone)]
 pub struct FileStore {
     // Config
     // TODO(meyer): Move these to a separate config struct with default impl, etc.
     pub(crate) lfs_threshold_bytes: Option<u64>,
+    /// When set, overrides the `lfs_threshold_bytes` based routing of writes.
+    pub(crate) lfs_predicate: Option<LfsPredicate>,
     pub(crate) edenapi_retries: i32,
     /// Allow explicitly writing serialized LFS pointers outside of tests
     pub(crate) allow_write_lfs_ptrs: bool,
@@ -442,11 +457,14 @@ impl FileStore {
                 continue;
             }
             let hg_blob_len = bytes.len() as u64;
-            // Default to non-LFS if no LFS threshold is set
-            if self
-                .lfs_threshold_bytes
-                .map_or(false, |threshold| hg_blob_len > threshold)
-            {
+            let use_lfs = match self.lfs_predicate.as_ref() {
+                Some(predicate) => predicate(&key, bytes.len()) == LfsDecision::Lfs,
+                // Default to non-LFS if no LFS threshold is set
+                None => self
+                    .lfs_threshold_bytes
+                    .map_or(false, |threshold| hg_blob_len > threshold),
+            };
+            if use_lfs {
                 metrics.lfs.item(1);
                 if let Err(e) = self.write_lfs(key, bytes) {
                     metrics.lfs.err(1);
@@ -517,6 +535,7 @@ impl FileStore {
     pub fn empty() -> Self {
         FileStore {
             lfs_threshold_bytes: None,
+            lfs_predicate: None,
             edenapi_retries: 0,
             allow_write_lfs_ptrs: false,
 
@@ -566,6 +585,7 @@ impl FileStore {
 
         Self {
             lfs_threshold_bytes: self.lfs_threshold_bytes.clone(),
+            lfs_predicate: self.lfs_predicate.clone(),
             edenapi_retries: self.edenapi_retries.clone(),
             allow_write_lfs_ptrs: self.allow_write_lfs_ptrs,
 
@@ -166,31 +166,38 @@ pub fn get_lfs_blobs_path(store_path: impl AsRef<Path>) -> Result<PathBuf> {
 }
 
 pub const RUN_ONCE_FILENAME: &str = "runoncemarker";
-pub fn check_run_once(store_path: impl AsRef<Path>, key: &str, cutoff: HgTime) -> bool {
+
+/// Read-only version of `check_run_once`: report whether the run-once marker for `key` would
+/// trigger, without recording it as run.
+pub fn would_run_once(store_path: impl AsRef<Path>, key: &str, cutoff: HgTime) -> bool {
     if HgTime::now() > Some(cutoff) {
         return false;
     }
 
     let marker_path = store_path.as_ref().join(RUN_ONCE_FILENAME);
     let line = format!("\n{}\n", key);
-    let marked = match read_to_string(&marker_path) {
-        Ok(contents) => contents.contains(&line),
+    match read_to_string(&marker_path) {
+        Ok(contents) => !contents.contains(&line),
         // If the file doesn't exist, it hasn't run yet.
-        Err(e) if e.kind() == ErrorKind::NotFound => false,
+        Err(e) if e.kind() == ErrorKind::NotFound => true,
         // If it's some other IO error (permission denied, etc), just give up.
-        _ => return false,
-    };
+        _ => false,
+    }
+}
 
-    if !marked {
-        let mut fp = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(marker_path)
-            .unwrap();
-        return write!(fp, "{}", line).is_ok();
+pub fn check_run_once(store_path: impl AsRef<Path>, key: &str, cutoff: HgTime) -> bool {
+    if !would_run_once(&store_path, key, cutoff) {
+        return false;
     }
 
-    false
+    let marker_path = store_path.as_ref().join(RUN_ONCE_FILENAME);
+    let line = format!("\n{}\n", key);
+    let mut fp = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(marker_path)
+        .unwrap();
+    write!(fp, "{}", line).is_ok()
 }
 
 pub fn record_edenapi_stats(span: &Span, stats: &Stats) {
@@ -152,6 +152,9 @@ fn check(mut tc: TestCase) -> Result<()> {
             })
             .collect(),
         tc.wm_fresh_instance,
+        false,
+        false,
+        None,
         true,
     )?;
 
@@ -295,6 +298,217 @@ fn test_detect_changes_fresh_instance() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_fresh_instance_should_consider_deleted() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let mut ts = TreeState::new(dir.path(), false)?.0;
+
+    let inside = RepoPathBuf::from_string("repo/file".to_string())?;
+    let outside = RepoPathBuf::from_string("mount/file".to_string())?;
+
+    for path in [&inside, &outside] {
+        ts.insert(
+            path,
+            &FileStateV2 {
+                mode: 0,
+                size: 0,
+                mtime: 0,
+                copied: None,
+                state: EXIST_P1 | EXIST_NEXT,
+            },
+        )?;
+    }
+
+    // Fresh instance with nothing reported by watchman: tracked files are
+    // normally considered deleted, but paths excluded by the callback (e.g.
+    // outside watchman's watch root) must be left alone.
+    let mut changes = detect_changes(
+        Arc::new(AlwaysMatcher::new()),
+        Arc::new(NeverMatcher::new()),
+        false,
+        false,
+        TestFileChangeDetector::default(),
+        &mut ts,
+        Vec::new(),
+        true,
+        false,
+        false,
+        Some(&|path: &RepoPathBuf| !path.as_str().starts_with("mount/")),
+        true,
+    )?;
+
+    changes.update_treestate(&mut ts)?;
+
+    let pending = changes.into_iter().collect::<Result<Vec<_>>>()?;
+    assert_eq!(pending.len(), 1);
+    assert!(matches!(&pending[0], PendingChange::Deleted(p) if p == &inside));
+
+    // The excluded path is untouched; the reported one is marked NEED_CHECK.
+    assert_eq!(ts.get(&outside)?.unwrap().state, EXIST_P1 | EXIST_NEXT);
+    assert_eq!(
+        ts.get(&inside)?.unwrap().state,
+        EXIST_P1 | EXIST_NEXT | NEED_CHECK
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_detect_changes_fallback_walk() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let mut ts = TreeState::new(dir.path(), false)?.0;
+
+    let clean = RepoPathBuf::from_string("clean".to_string())?;
+    let changed = RepoPathBuf::from_string("changed".to_string())?;
+    let deleted = RepoPathBuf::from_string("deleted".to_string())?;
+
+    for path in [&clean, &changed, &deleted] {
+        ts.insert(
+            path,
+            &FileStateV2 {
+                mode: 0,
+                size: 0,
+                mtime: 0,
+                copied: None,
+                state: EXIST_P1 | EXIST_NEXT,
+            },
+        )?;
+    }
+
+    let mut stub_detector = TestFileChangeDetector::default();
+    stub_detector.changed_files.push(changed.clone());
+    stub_detector.deleted_files.push(deleted.clone());
+
+    // Watchman is unavailable: the caller synthesized a fresh instance with no
+    // files. Tracked files must go through the change detector rather than all
+    // being reported as deleted.
+    let mut changes = detect_changes(
+        Arc::new(AlwaysMatcher::new()),
+        Arc::new(NeverMatcher::new()),
+        false,
+        false,
+        stub_detector,
+        &mut ts,
+        Vec::new(),
+        true,
+        false,
+        true,
+        None,
+        true,
+    )?;
+
+    changes.update_treestate(&mut ts)?;
+
+    let pending = changes.into_iter().collect::<Result<Vec<_>>>()?;
+    assert_eq!(pending.len(), 2);
+    assert!(matches!(&pending[0], PendingChange::Changed(p) if p == &changed));
+    assert!(matches!(&pending[1], PendingChange::Deleted(p) if p == &deleted));
+
+    // The clean file isn't reported, but everything is marked for recheck once
+    // watchman is back.
+    for path in [&clean, &changed, &deleted] {
+        assert_eq!(
+            ts.get(path)?.unwrap().state,
+            EXIST_P1 | EXIST_NEXT | NEED_CHECK,
+            "{}",
+            path
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_case_folding_dedup() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let mut ts = TreeState::new(dir.path(), false)?.0;
+
+    let lower = RepoPathBuf::from_string("foo.txt".to_string())?;
+    let upper = RepoPathBuf::from_string("Foo.txt".to_string())?;
+
+    let mut stub_detector = TestFileChangeDetector::default();
+    stub_detector.changed_files.push(lower.clone());
+    stub_detector.changed_files.push(upper.clone());
+
+    // Watchman reports two spellings of the same (untracked) file on a case
+    // insensitive filesystem. They should collapse into a single change.
+    let changes = detect_changes(
+        Arc::new(AlwaysMatcher::new()),
+        Arc::new(NeverMatcher::new()),
+        false,
+        false,
+        stub_detector,
+        &mut ts,
+        vec![lower.clone(), upper]
+            .into_iter()
+            .map(|p| metadata::File {
+                path: p,
+                fs_meta: None,
+                ts_state: None,
+            })
+            .collect(),
+        false,
+        false,
+        false,
+        None,
+        false,
+    )?;
+
+    let pending = changes.into_iter().collect::<Result<Vec<_>>>()?;
+    assert_eq!(pending.len(), 1);
+    assert!(matches!(&pending[0], PendingChange::Changed(p) if p == &lower));
+
+    Ok(())
+}
+
+#[test]
+fn test_symlink_dir_replacement() -> Result<()> {
+    // (treestate mode, watchman mode): a directory replaced by a symlink and
+    // the reverse. Both should surface as a plain change even though the
+    // change detector has no opinion on the path.
+    for (ts_mode, wm_mode) in [(0o040755, 0o120777), (0o120777, 0o040755)] {
+        let dir = tempfile::tempdir()?;
+        let mut ts = TreeState::new(dir.path(), false)?.0;
+        let path = RepoPathBuf::from_string("some_path".to_string())?;
+
+        ts.insert(
+            &path,
+            &FileStateV2 {
+                mode: ts_mode,
+                size: 0,
+                mtime: 0,
+                copied: None,
+                state: EXIST_P1 | EXIST_NEXT,
+            },
+        )?;
+
+        let changes = detect_changes(
+            Arc::new(AlwaysMatcher::new()),
+            Arc::new(NeverMatcher::new()),
+            false,
+            false,
+            TestFileChangeDetector::default(),
+            &mut ts,
+            vec![metadata::File {
+                path: path.clone(),
+                fs_meta: Some(Some(metadata::Metadata::from_stat(wm_mode, 10, 100))),
+                ts_state: None,
+            }],
+            false,
+            false,
+            false,
+            None,
+            true,
+        )?;
+
+        let pending = changes.into_iter().collect::<Result<Vec<_>>>()?;
+        assert_eq!(pending.len(), 1);
+        assert!(matches!(&pending[0], PendingChange::Changed(p) if p == &path));
+    }
+
+    Ok(())
+}
+
 #[test]
 fn test_never_matcher() -> Result<()> {
     // Make sure a non-matching matcher doesn't mess up correctness of
@@ -21,6 +21,7 @@ use crate::metadata::Metadata;
 use crate::util::update_filestate_from_fs_meta;
 use crate::util::walk_treestate;
 
+/// Returns whether the treestate was actually mutated (i.e. NEED_CHECK wasn't already set).
 pub(crate) fn mark_needs_check(ts: &mut TreeState, path: &RepoPathBuf) -> Result<bool> {
     let state = ts.get(path)?;
     let filestate = match state {
@@ -52,6 +53,8 @@ pub(crate) fn mark_needs_check(ts: &mut TreeState, path: &RepoPathBuf) -> Result
     Ok(true)
 }
 
+/// Returns whether the treestate was actually mutated (i.e. NEED_CHECK was set, or the
+/// file metadata changed).
 pub(crate) fn clear_needs_check(
     ts: &mut TreeState,
     path: &RepoPathBuf,
@@ -59,6 +62,7 @@ pub(crate) fn clear_needs_check(
 ) -> Result<bool> {
     let state = ts.get(path)?;
     if let Some(filestate) = state {
+        let original = filestate.clone();
         let mut filestate = filestate.clone();
         if !filestate.state.intersects(StateFlags::NEED_CHECK) {
             tracing::trace!(%path, "updating metadata");
@@ -77,6 +81,10 @@ pub(crate) fn clear_needs_check(
             // ignored files anymore).
             tracing::trace!(%path, "empty after unsetting NEED_CHECK");
             ts.remove(path)?;
+        } else if filestate == original {
+            // Nothing changed - return early so we don't dirty the treestate.
+            tracing::trace!(%path, "no metadata changes");
+            return Ok(false);
         } else {
             ts.insert(path, &filestate)?;
         }
@@ -12,6 +12,7 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::anyhow;
+use anyhow::bail;
 use anyhow::Result;
 use configmodel::Config;
 use configmodel::ConfigExt;
@@ -27,6 +28,8 @@ use repolock::RepoLocker;
 use serde::Deserialize;
 use serde::Serialize;
 use termlogger::TermLogger;
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 use treestate::filestate::StateFlags;
 use treestate::treestate::TreeState;
 use types::path::ParseError;
@@ -164,6 +167,48 @@ impl WatchmanFileSystem {
         Ok(result)
     }
 
+    /// Issue a single watchman query and return the converted results.
+    ///
+    /// As opposed to `pending_changes`, this doesn't run the file change detection, and
+    /// neither the treestate nor the recorded watchman clock are touched. Intended for
+    /// debugging commands that want to know what watchman thinks changed.
+    pub fn raw_query(&self, since: Option<Clock>) -> Result<Vec<metadata::File>> {
+        let client = self.client.get()?;
+
+        let result = async_runtime::block_on(self.query_files(
+            client,
+            WatchmanConfig {
+                clock: since,
+                sync_timeout: Duration::from_secs(10),
+            },
+            Vec::new(),
+        ))?;
+
+        let mut errors = Vec::new();
+        let files = wm_files_to_needs_check(result.files.unwrap_or_default(), true, 1, &mut errors);
+        if let Some(error) = errors.into_iter().next() {
+            return Err(error.into());
+        }
+        Ok(files)
+    }
+
+    /// Read back the stats of the last watchman query from the treestate metadata,
+    /// without talking to watchman. Fields are `None` until a query has run.
+    pub fn last_query_stats(&self) -> Result<LastQueryStats> {
+        let ts = &mut *self.inner.treestate.lock();
+        let metadata = ts.metadata()?;
+        Ok(LastQueryStats {
+            clock: metadata.get(LAST_CLOCK_METADATA_KEY).map(|c| c.to_string()),
+            query_duration: metadata
+                .get(LAST_QUERY_MS_METADATA_KEY)
+                .and_then(|ms| ms.parse::<u64>().ok())
+                .map(Duration::from_millis),
+            file_count: metadata
+                .get(LAST_FILE_COUNT_METADATA_KEY)
+                .and_then(|count| count.parse::<u64>().ok()),
+        })
+    }
+
     #[tracing::instrument(skip_all)]
     fn pending_changes(
         &self,
@@ -173,32 +218,84 @@ impl WatchmanFileSystem {
         ignore_dirs: Vec<PathBuf>,
         include_ignored: bool,
     ) -> Result<Box<dyn Iterator<Item = Result<PendingChange>>>> {
-        let ts = &mut *self.inner.treestate.lock();
-
-        let treestate_started_dirty = ts.dirty();
+        // `block_on` must not run from within an async runtime - the nested
+        // runtime panics. Async callers should use `pending_changes_async`.
+        if tokio::runtime::Handle::try_current().is_ok() {
+            bail!(
+                "pending_changes called from within an async runtime - use pending_changes_async"
+            );
+        }
 
-        let ts_metadata = ts.metadata()?;
-        let mut prev_clock = get_clock(&ts_metadata)?;
+        async_runtime::block_on(self.pending_changes_async(
+            ctx,
+            matcher,
+            ignore_matcher,
+            ignore_dirs,
+            include_ignored,
+        ))
+    }
 
+    /// Async flavor of `pending_changes`. The watchman query is awaited
+    /// directly and the treestate work runs on the blocking thread pool, so
+    /// this is safe to call from within an existing runtime.
+    #[tracing::instrument(skip_all)]
+    pub async fn pending_changes_async(
+        &self,
+        ctx: &CoreContext,
+        matcher: DynMatcher,
+        ignore_matcher: DynMatcher,
+        ignore_dirs: Vec<PathBuf>,
+        include_ignored: bool,
+    ) -> Result<Box<dyn Iterator<Item = Result<PendingChange>>>> {
         let config = ctx.config.clone();
 
         let track_ignored = config.get_or_default::<bool>("fsmonitor", "track-ignore-files")?;
-        let ts_track_ignored = ts_metadata.get("track-ignored").map(|v| v.as_ref()) == Some("1");
-        if track_ignored != ts_track_ignored {
-            // If track-ignore-files has changed, trigger a migration by
-            // unsetting the clock. Watchman will do a full crawl and report
-            // fresh instance.
-            prev_clock = None;
 
-            // Store new value of track ignored so we don't migrate again.
-            let md_value = if track_ignored {
-                "1".to_string()
-            } else {
-                "0".to_string()
-            };
-            tracing::info!(track_ignored = md_value, "migrating track-ignored");
-            ts.update_metadata(&[("track-ignored".to_string(), Some(md_value))])?;
-        }
+        // Read the previous clock and run metadata migrations. Treestate access
+        // can touch disk, so keep it off the async worker threads.
+        let (treestate_started_dirty, mut prev_clock, avg_query_time, ts_len) = {
+            let treestate = self.inner.treestate.clone();
+            let config = config.clone();
+            async_runtime::spawn_blocking(move || -> Result<_> {
+                let ts = &mut *treestate.lock();
+
+                let treestate_started_dirty = ts.dirty();
+
+                let ts_metadata = ts.metadata()?;
+                let mut prev_clock = get_clock(&ts_metadata)?;
+
+                let ts_track_ignored =
+                    ts_metadata.get("track-ignored").map(|v| v.as_ref()) == Some("1");
+                if track_ignored != ts_track_ignored {
+                    // If track-ignore-files has changed, trigger a migration by
+                    // unsetting the clock. Watchman will do a full crawl and report
+                    // fresh instance.
+                    prev_clock = None;
+
+                    // Store new value of track ignored so we don't migrate again.
+                    let md_value = if track_ignored {
+                        "1".to_string()
+                    } else {
+                        "0".to_string()
+                    };
+                    tracing::info!(track_ignored = md_value, "migrating track-ignored");
+                    ts.update_metadata(&[("track-ignored".to_string(), Some(md_value))])?;
+                }
+
+                let avg_query_time = ts_metadata
+                    .get(QUERY_TIME_METADATA_KEY)
+                    .and_then(|ms| ms.parse::<u64>().ok())
+                    .map(Duration::from_millis);
+
+                Ok((
+                    treestate_started_dirty,
+                    prev_clock,
+                    avg_query_time,
+                    ts.len() as u64,
+                ))
+            })
+            .await??
+        };
 
         if include_ignored && !track_ignored {
             // TODO: give user a hint about fsmonitor.track-ignore-files
@@ -209,166 +306,273 @@ impl WatchmanFileSystem {
             prev_clock = None;
         }
 
+        // Directories the user never wants watchman to report (e.g. large generated
+        // directories). They are excluded the same way as the caller-provided ones.
+        let mut ignore_dirs = ignore_dirs;
+        for exclude in config.get_or_default::<Vec<String>>("fsmonitor", "exclude-dirs")? {
+            let exclude = PathBuf::from(exclude);
+            if exclude.is_absolute() {
+                bail!(
+                    "fsmonitor.exclude-dirs entry {} is not relative to the repo root",
+                    exclude.display(),
+                );
+            }
+            ignore_dirs.push(exclude);
+        }
+
+        // Cancelling the token stops the crawl progress task promptly, clearing its
+        // progress bar. This fires on Ctrl-C as well, so the spinner doesn't linger over
+        // the prompt if the user interrupts a long recrawl.
+        let progress_cancel = CancellationToken::new();
+        let _cancel_guard = progress_cancel.clone().drop_guard();
+
+        let signal_handle = async_runtime::spawn({
+            let cancel = progress_cancel.clone();
+            async move {
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    cancel.cancel();
+                }
+            }
+        });
+
         let progress_handle = async_runtime::spawn(crawl_progress(
+            progress_cancel.clone(),
             config.clone(),
+            self.client.sockpath().map(|p| p.to_path_buf()),
             self.inner.vfs.root().to_path_buf(),
-            ts.len() as u64,
+            ts_len,
         ));
 
-        let client = self.client.get()?;
+        let fallback_to_walk = config.get_or_default::<bool>("fsmonitor", "fallback-to-walk")?;
 
-        let result = {
-            // Instrument query_files() from outside to avoid async weirdness.
-            let _span = tracing::info_span!("query_files").entered();
-
-            async_runtime::block_on(self.query_files(
-                client,
-                WatchmanConfig {
-                    clock: prev_clock.clone(),
-                    sync_timeout:
-                        config.get_or::<Duration>("fsmonitor", "timeout", || {
-                            Duration::from_secs(10)
-                        })?,
-                },
-                ignore_dirs,
-            ))
+        let client = match self.client.get() {
+            Ok(client) => Some(client),
+            Err(err) => {
+                if !fallback_to_walk {
+                    progress_cancel.cancel();
+                    progress_handle.abort();
+                    signal_handle.abort();
+                    return Err(err);
+                }
+                // Continue without watchman: detect_changes walks the treestate and
+                // checks every tracked file on disk instead. Untracked files can't be
+                // discovered without watchman, so they won't show up until it is back.
+                ctx.logger.warn(format!(
+                    "warning: watchman is unavailable ({:#}) - falling back to filesystem walk\n",
+                    err
+                ));
+                None
+            }
         };
 
-        // Make sure we always abort - even in case of error.
-        progress_handle.abort();
+        let base_timeout =
+            config.get_or::<Duration>("fsmonitor", "timeout", || Duration::from_secs(10))?;
+        let max_timeout = config.get_or::<Duration>("fsmonitor", "max-timeout", || {
+            Duration::from_secs(60)
+        })?;
+        let sync_timeout = adaptive_sync_timeout(base_timeout, max_timeout, avg_query_time);
+
+        let query_retries = config.get_or("fsmonitor", "query-retries", || 2)?;
+
+        let query_start = std::time::Instant::now();
+        let result = match client {
+            Some(mut client) => {
+                let query = async {
+                    let mut attempt = 0;
+                    loop {
+                        match self
+                            .query_files(
+                                client.clone(),
+                                WatchmanConfig {
+                                    clock: prev_clock.clone(),
+                                    sync_timeout,
+                                },
+                                ignore_dirs.clone(),
+                            )
+                            .await
+                        {
+                            Ok(result) => break Ok(result),
+                            Err(err) if attempt < query_retries => {
+                                attempt += 1;
+                                tracing::warn!(
+                                    %err,
+                                    attempt,
+                                    "watchman query failed - reconnecting and retrying",
+                                );
+                                // Reconnect from scratch: the cached connection may be talking
+                                // to a watchman that is restarting. A fresh instance result
+                                // from the new connection flows through the regular handling
+                                // below, including the fresh-instance warning.
+                                match connect_watchman_async(&config, self.client.sockpath()).await
+                                {
+                                    Ok(new_client) => client = Arc::new(new_client),
+                                    Err(err) => break Err(err),
+                                }
+                            }
+                            Err(err) => break Err(err),
+                        }
+                    }
+                };
+                // Instrument the query from outside so the retries show up in the
+                // same span.
+                Some(query.instrument(tracing::info_span!("query_files")).await)
+            }
+            None => None,
+        };
 
-        let result = result?;
+        // Make sure we always stop the progress task - even in case of error.
+        progress_cancel.cancel();
+        progress_handle.abort();
+        signal_handle.abort();
+
+        let (wm_files, is_fresh_instance, clock, fallback_walk) = match result.transpose()? {
+            Some(result) => (
+                result.files.unwrap_or_default(),
+                result.is_fresh_instance,
+                Some(result.clock),
+                false,
+            ),
+            None => (Vec::new(), true, None, true),
+        };
 
-        tracing::debug!(
-            target: "watchman_info",
-            watchmanfreshinstances= if result.is_fresh_instance { 1 } else { 0 },
-            watchmanfilecount=result.files.as_ref().map_or(0, |f| f.len()),
-        );
+        // Everything below reads or writes the treestate, so it runs as one
+        // unit on the blocking thread pool.
+        let treestate = self.inner.treestate.clone();
+        let tree_resolver = self.inner.tree_resolver.clone();
+        let vfs = self.inner.vfs.clone();
+        let store = self.inner.store.clone();
+        let locker = self.inner.locker.clone();
+        let logger = ctx.logger.clone();
+        let query_elapsed = query_start.elapsed();
+
+        let pending_changes = async_runtime::spawn_blocking(move || -> Result<_> {
+            let ts = &mut *treestate.lock();
+
+            // Record the query stats into the treestate metadata. The rolling average feeds
+            // the adaptive sync timeout, the rest can be read back offline via
+            // `last_query_stats` to debug slow status reports after the fact.
+            if clock.is_some() {
+                let elapsed = query_elapsed.as_millis() as u64;
+                let new_avg = match avg_query_time {
+                    Some(avg) => (avg.as_millis() as u64 * 3 + elapsed) / 4,
+                    None => elapsed,
+                };
+                let clock_str = match clock.as_ref() {
+                    Some(Clock::Spec(ClockSpec::StringClock(clock_str))) => Some(clock_str.clone()),
+                    _ => None,
+                };
+                ts.update_metadata(&[
+                    (QUERY_TIME_METADATA_KEY.to_string(), Some(new_avg.to_string())),
+                    (
+                        LAST_QUERY_MS_METADATA_KEY.to_string(),
+                        Some(elapsed.to_string()),
+                    ),
+                    (
+                        LAST_FILE_COUNT_METADATA_KEY.to_string(),
+                        Some(wm_files.len().to_string()),
+                    ),
+                    (LAST_CLOCK_METADATA_KEY.to_string(), clock_str),
+                ])?;
+            }
 
-        let should_warn = config.get_or_default("fsmonitor", "warn-fresh-instance")?;
-        if result.is_fresh_instance && should_warn {
-            let _ = warn_about_fresh_instance(
-                &ctx.logger,
-                parse_watchman_pid(prev_clock.as_ref()),
-                parse_watchman_pid(Some(&result.clock)),
+            tracing::debug!(
+                target: "watchman_info",
+                watchmanfreshinstances= if is_fresh_instance { 1 } else { 0 },
+                watchmanfilecount=wm_files.len(),
             );
-        }
-
-        let file_change_threshold =
-            config.get_or("fsmonitor", "watchman-changed-file-threshold", || 200)?;
-        let should_update_clock = result.is_fresh_instance
-            || result
-                .files
-                .as_ref()
-                .map_or(false, |f| f.len() > file_change_threshold);
-
-        let manifests = WorkingCopy::current_manifests(ts, &self.inner.tree_resolver)?;
-
-        let mut wm_errors: Vec<ParseError> = Vec::new();
-        let use_watchman_metadata =
-            config.get_or::<bool>("workingcopy", "use-watchman-metadata", || true)?;
-        let wm_needs_check: Vec<metadata::File> = result
-            .files
-            .unwrap_or_default()
-            .into_iter()
-            .filter_map(
-                |file| match RepoPathBuf::from_utf8(file.name.into_inner().into_bytes()) {
-                    Ok(path) => {
-                        tracing::trace!(
-                            ?path,
-                            mode = *file.mode,
-                            size = *file.size,
-                            mtime = *file.mtime,
-                            exists = *file.exists,
-                            "watchman file"
-                        );
 
-                        let meta = Metadata::from_stat(
-                            file.mode.into_inner() as u32,
-                            file.size.into_inner(),
-                            file.mtime.into_inner(),
-                        );
+            let should_warn = config.get_or_default("fsmonitor", "warn-fresh-instance")?;
+            if is_fresh_instance && should_warn && clock.is_some() {
+                let _ = warn_about_fresh_instance(
+                    &logger,
+                    parse_watchman_pid(prev_clock.as_ref()),
+                    parse_watchman_pid(clock.as_ref()),
+                );
+            }
 
-                        let fs_meta = if *file.exists {
-                            if use_watchman_metadata {
-                                Some(Some(meta))
-                            } else {
-                                None
-                            }
-                        } else {
-                            // If watchman says the file doesn't exist, indicate
-                            // that via the metadata being None. This is
-                            // important when a file moves behind a symlink;
-                            // Watchman will report it as deleted, but a naive
-                            // lstat() call would show the file to still exist.
-                            Some(None)
-                        };
-
-                        Some(metadata::File {
-                            path,
-                            fs_meta,
-                            ts_state: None,
-                        })
-                    }
-                    Err(err) => {
-                        wm_errors.push(err);
-                        None
-                    }
-                },
-            )
-            .collect();
+            let file_change_threshold =
+                config.get_or("fsmonitor", "watchman-changed-file-threshold", || 200)?;
+            let should_update_clock =
+                is_fresh_instance || wm_files.len() > file_change_threshold;
+
+            let manifests = WorkingCopy::current_manifests(ts, &tree_resolver)?;
+
+            let mut wm_errors: Vec<WatchmanPathError> = Vec::new();
+            let use_watchman_metadata =
+                config.get_or::<bool>("workingcopy", "use-watchman-metadata", || true)?;
+            let worker_count = config
+                .get_opt::<usize>("workingcopy", "worker-count")?
+                .unwrap_or(10);
+            let wm_needs_check = wm_files_to_needs_check(
+                wm_files,
+                use_watchman_metadata,
+                worker_count,
+                &mut wm_errors,
+            );
 
-        let detector = FileChangeDetector::new(
-            self.inner.vfs.clone(),
-            manifests[0].clone(),
-            self.inner.store.clone(),
-            config.get_opt("workingcopy", "worker-count")?,
-        );
-        let mut pending_changes = detect_changes(
-            matcher,
-            ignore_matcher,
-            track_ignored,
-            include_ignored,
-            detector,
-            ts,
-            wm_needs_check,
-            result.is_fresh_instance,
-            self.inner.vfs.case_sensitive(),
-        )?;
+            let detector = FileChangeDetector::new(
+                vfs.clone(),
+                manifests[0].clone(),
+                store.clone(),
+                config.get_opt("workingcopy", "worker-count")?,
+            );
+            let mut pending_changes = detect_changes(
+                matcher,
+                ignore_matcher,
+                track_ignored,
+                include_ignored,
+                detector,
+                ts,
+                wm_needs_check,
+                is_fresh_instance,
+                config.get_or_default("fsmonitor", "skip-fresh-delete-scan")?,
+                fallback_walk,
+                None,
+                vfs.case_sensitive(),
+            )?;
 
-        // Add back path errors into the pending changes. The caller
-        // of pending_changes must choose how to handle these.
-        pending_changes
-            .pending_changes
-            .extend(wm_errors.into_iter().map(|e| Err(anyhow!(e))));
+            // Add back path errors into the pending changes. The caller
+            // of pending_changes must choose how to handle these.
+            pending_changes
+                .pending_changes
+                .extend(wm_errors.into_iter().map(|e| Err(anyhow!(e))));
+
+            let did_something = pending_changes.update_treestate(ts)?;
+            if let Some(clock) = clock {
+                if did_something || should_update_clock {
+                    // If we had something to update in the treestate, make sure clock is updated
+                    // as well. Without watchman there is no clock to record.
+                    set_clock(ts, clock)?;
+                }
+            }
 
-        let did_something = pending_changes.update_treestate(ts)?;
-        if did_something || should_update_clock {
-            // If we had something to update in the treestate, make sure clock is updated as well.
-            set_clock(ts, result.clock)?;
-        }
+            // Don't flush treestate if it was already dirty. If we are inside a
+            // Python transaction with uncommitted, substantial dirstate changes,
+            // those changes should not be written out until the transaction
+            // finishes.
+            if treestate_started_dirty {
+                tracing::debug!("treestate was dirty - skipping flush");
+            } else {
+                maybe_flush_treestate(
+                    vfs.root(),
+                    ts,
+                    &locker,
+                    dirstate_write_time_override(&config),
+                )?;
+            }
 
-        // Don't flush treestate if it was already dirty. If we are inside a
-        // Python transaction with uncommitted, substantial dirstate changes,
-        // those changes should not be written out until the transaction
-        // finishes.
-        if treestate_started_dirty {
-            tracing::debug!("treestate was dirty - skipping flush");
-        } else {
-            maybe_flush_treestate(
-                self.inner.vfs.root(),
-                ts,
-                &self.inner.locker,
-                dirstate_write_time_override(&config),
-            )?;
-        }
+            Ok(pending_changes)
+        })
+        .await??;
 
         Ok(Box::new(pending_changes.into_iter()))
     }
 }
 
 async fn crawl_progress(
+    cancel: CancellationToken,
     config: Arc<dyn Config>,
+    sockpath: Option<PathBuf>,
     root: PathBuf,
     approx_file_count: u64,
 ) -> Result<()> {
@@ -379,14 +583,24 @@ async fn crawl_progress(
         // query_files), this connect gets stuck indefinitely. Work around by
         // timing out and retrying until we get through.
         loop {
-            match tokio::time::timeout(Duration::from_secs(1), connect_watchman_async(&config))
-                .await
+            if cancel.is_cancelled() {
+                return Ok(());
+            }
+
+            match tokio::time::timeout(
+                Duration::from_secs(1),
+                connect_watchman_async(&config, sockpath.as_deref()),
+            )
+            .await
             {
                 Ok(client) => break client?,
                 Err(_) => {}
             };
 
-            tokio::time::sleep(Duration::from_secs(1)).await;
+            tokio::select! {
+                _ = cancel.cancelled() => return Ok(()),
+                _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+            }
         }
     };
 
@@ -398,7 +612,11 @@ async fn crawl_progress(
     );
 
     loop {
-        let response: DebugRootStatusResponse = client.generic_request(req.clone()).await?;
+        let response: DebugRootStatusResponse = tokio::select! {
+            // Returning drops the progress bar so the UI clears right away.
+            _ = cancel.cancelled() => return Ok(()),
+            response = client.generic_request(req.clone()) => response?,
+        };
 
         if let Some(RootStatus {
             recrawl_info: Some(RecrawlInfo { stats: Some(stats) }),
@@ -412,7 +630,10 @@ async fn crawl_progress(
             return Ok(());
         }
 
-        tokio::time::sleep(Duration::from_millis(100)).await;
+        tokio::select! {
+            _ = cancel.cancelled() => return Ok(()),
+            _ = tokio::time::sleep(Duration::from_millis(100)) => {}
+        }
     }
 }
 
@@ -478,6 +699,180 @@ impl FileSystem for WatchmanFileSystem {
     }
 }
 
+/// Convert the files of a watchman response into `metadata::File`, accumulating the paths
+/// that couldn't be converted into `errors`.
+///
+/// The conversion is spread over `worker_count` threads for large responses (e.g. a fresh
+/// instance on a big repo). Results and errors come back in the same order as `files`.
+fn wm_files_to_needs_check(
+    files: Vec<StatusQuery>,
+    use_watchman_metadata: bool,
+    worker_count: usize,
+    errors: &mut Vec<WatchmanPathError>,
+) -> Vec<metadata::File> {
+    // Check once up front so the per-file logging doesn't slow down the hot loop.
+    let trace_files = tracing::enabled!(tracing::Level::TRACE);
+
+    let worker_count = worker_count.clamp(1, files.len().max(1));
+    let chunk_size = files.len().div_ceil(worker_count);
+    let mut chunks: Vec<Vec<StatusQuery>> = Vec::with_capacity(worker_count);
+    let mut files = files.into_iter();
+    loop {
+        let chunk: Vec<_> = files.by_ref().take(chunk_size).collect();
+        if chunk.is_empty() {
+            break;
+        }
+        chunks.push(chunk);
+    }
+
+    let results = std::thread::scope(|s| {
+        let handles: Vec<_> = chunks
+            .into_iter()
+            .map(|chunk| {
+                s.spawn(move || {
+                    let mut chunk_files = Vec::with_capacity(chunk.len());
+                    let mut chunk_errors = Vec::new();
+                    for file in chunk {
+                        match wm_file_to_needs_check(file, use_watchman_metadata, trace_files) {
+                            Ok(file) => chunk_files.push(file),
+                            Err(err) => chunk_errors.push(err),
+                        }
+                    }
+                    (chunk_files, chunk_errors)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("conversion worker panicked"))
+            .collect::<Vec<_>>()
+    });
+
+    let mut needs_check = Vec::new();
+    for (chunk_files, chunk_errors) in results {
+        needs_check.extend(chunk_files);
+        errors.extend(chunk_errors);
+    }
+    needs_check
+}
+
+fn wm_file_to_needs_check(
+    file: StatusQuery,
+    use_watchman_metadata: bool,
+    trace_files: bool,
+) -> Result<metadata::File, WatchmanPathError> {
+    let raw_name = file.name.into_inner().into_bytes();
+    match RepoPathBuf::from_utf8(raw_name.clone()) {
+        Ok(path) => {
+            if trace_files {
+                tracing::trace!(
+                    ?path,
+                    mode = *file.mode,
+                    size = *file.size,
+                    mtime = *file.mtime,
+                    exists = *file.exists,
+                    "watchman file"
+                );
+            }
+
+            let meta = Metadata::from_stat(
+                file.mode.into_inner() as u32,
+                file.size.into_inner(),
+                file.mtime.into_inner(),
+            );
+
+            let fs_meta = if *file.exists {
+                if use_watchman_metadata {
+                    Some(Some(meta))
+                } else {
+                    None
+                }
+            } else {
+                // If watchman says the file doesn't exist, indicate
+                // that via the metadata being None. This is
+                // important when a file moves behind a symlink;
+                // Watchman will report it as deleted, but a naive
+                // lstat() call would show the file to still exist.
+                Some(None)
+            };
+
+            Ok(metadata::File {
+                path,
+                fs_meta,
+                ts_state: None,
+            })
+        }
+        Err(err) => Err(WatchmanPathError {
+            raw_name,
+            mode: file.mode.into_inner() as u64,
+            size: file.size.into_inner(),
+            source: err,
+        }),
+    }
+}
+
+/// A path in the watchman response that couldn't be turned into a `RepoPathBuf`.
+///
+/// Carries the raw bytes and the watchman mode/size fields so callers can log exactly what
+/// watchman reported instead of a lossy converted string.
+#[derive(Debug)]
+pub struct WatchmanPathError {
+    pub raw_name: Vec<u8>,
+    pub mode: u64,
+    pub size: u64,
+    pub source: ParseError,
+}
+
+impl std::fmt::Display for WatchmanPathError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid path \"{}\" in watchman response (mode={}, size={}): {}",
+            self.raw_name.escape_ascii(),
+            self.mode,
+            self.size,
+            self.source,
+        )
+    }
+}
+
+impl std::error::Error for WatchmanPathError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Treestate metadata key holding the rolling average watchman query time, in milliseconds.
+const QUERY_TIME_METADATA_KEY: &str = "watchman-query-time-avg-ms";
+
+/// Treestate metadata keys recording the last watchman query, for offline diagnostics.
+const LAST_QUERY_MS_METADATA_KEY: &str = "watchman-last-query-ms";
+const LAST_FILE_COUNT_METADATA_KEY: &str = "watchman-last-file-count";
+const LAST_CLOCK_METADATA_KEY: &str = "watchman-last-clock";
+
+/// Stats of the last watchman query, as recorded in the treestate metadata and returned by
+/// `WatchmanFileSystem::last_query_stats`.
+#[derive(Debug, Default)]
+pub struct LastQueryStats {
+    pub clock: Option<String>,
+    pub query_duration: Option<Duration>,
+    pub file_count: Option<u64>,
+}
+
+/// Compute the watchman sync timeout from the configured base and the rolling average of
+/// prior query durations. The timeout grows once queries take more than half the base
+/// timeout, so large repos don't trip spurious fresh instances, and is capped at `max`.
+fn adaptive_sync_timeout(
+    base: Duration,
+    max: Duration,
+    avg_query_time: Option<Duration>,
+) -> Duration {
+    match avg_query_time {
+        Some(avg) if avg > base / 2 => (avg * 4).clamp(base, max.max(base)),
+        _ => base,
+    }
+}
+
 fn warn_about_fresh_instance(
     lgr: &TermLogger,
     old_pid: Option<u32>,
@@ -519,6 +914,15 @@ pub(crate) fn detect_changes(
     ts: &mut TreeState,
     wm_need_check: Vec<metadata::File>,
     wm_fresh_instance: bool,
+    skip_fresh_delete_scan: bool,
+    // Set when watchman was unavailable and the fresh instance was synthesized with an
+    // empty file list. Tracked files are then submitted to the change detector (which
+    // checks them against disk) instead of being reported as deleted wholesale.
+    fallback_walk: bool,
+    // On fresh instance, paths for which this returns false are never considered deleted,
+    // even when watchman didn't report them. Lets callers exclude subtrees outside
+    // watchman's watch root (e.g. union mounts). `None` considers every path.
+    should_consider_deleted: Option<&dyn Fn(&RepoPathBuf) -> bool>,
     fs_case_sensitive: bool,
 ) -> Result<WatchmanPendingChanges> {
     let _span = tracing::info_span!("prepare stuff").entered();
@@ -587,7 +991,59 @@ pub(crate) fn detect_changes(
 
     let mut deletes = Vec::new();
 
-    if wm_fresh_instance {
+    if wm_fresh_instance && fallback_walk {
+        let _span =
+            tracing::info_span!("fallback walk work", wm_len = wm_need_check.len()).entered();
+
+        // Watchman gave us no file list at all, so the delete scan below would report
+        // every tracked file as deleted. Instead, submit the tracked files to the
+        // change detector so deletions and modifications are confirmed against disk.
+        // Also mark them NEED_CHECK so the next (watchman-backed) status rechecks them.
+        let mut fallback_check = Vec::new();
+        walk_treestate(
+            ts,
+            Arc::new(AlwaysMatcher::new()),
+            StateFlags::EXIST_NEXT,
+            StateFlags::empty(),
+            StateFlags::NEED_CHECK,
+            |path, _state| {
+                if !wm_need_check.contains_key(&path) {
+                    fallback_check.push(path);
+                }
+                Ok(())
+            },
+        )?;
+
+        for path in fallback_check {
+            needs_mark.push(path.clone());
+            file_change_detector.submit(metadata::File {
+                path: path.clone(),
+                ts_state: ts.normalized_get(&path)?,
+                fs_meta: None,
+            });
+        }
+    } else if wm_fresh_instance && skip_fresh_delete_scan {
+        let _span =
+            tracing::info_span!("fresh_instance mark work", wm_len = wm_need_check.len()).entered();
+
+        // Instead of detecting deletions eagerly (see below), mark the tracked files not
+        // reported by watchman as NEED_CHECK so the next (non-fresh) pass catches
+        // deletions lazily. The tradeoff is that a tracked file deleted while watchman
+        // wasn't running won't show up as deleted until the following status.
+        walk_treestate(
+            ts,
+            Arc::new(AlwaysMatcher::new()),
+            StateFlags::EXIST_NEXT,
+            StateFlags::empty(),
+            StateFlags::NEED_CHECK,
+            |path, _state| {
+                if !wm_need_check.contains_key(&path) {
+                    needs_mark.push(path);
+                }
+                Ok(())
+            },
+        )?;
+    } else if wm_fresh_instance {
         let _span =
             tracing::info_span!("fresh_instance work", wm_len = wm_need_check.len()).entered();
 
@@ -603,7 +1059,9 @@ pub(crate) fn detect_changes(
             StateFlags::empty(),
             StateFlags::NEED_CHECK,
             |path, _state| {
-                if !wm_need_check.contains_key(&path) {
+                if !wm_need_check.contains_key(&path)
+                    && should_consider_deleted.map_or(true, |deleted| deleted(&path))
+                {
                     deletes.push(path);
                 }
                 Ok(())
@@ -628,6 +1086,8 @@ pub(crate) fn detect_changes(
 
     let _span = tracing::info_span!("submit wm_need_check").entered();
 
+    let watchman_reported = wm_need_check.len();
+
     for (_, wm_needs_check) in wm_need_check {
         // is_tracked is used to short circuit invocations of the ignore
         // matcher, which can be expensive.
@@ -660,6 +1120,26 @@ pub(crate) fn detect_changes(
             }
         }
 
+        // A directory replaced by a symlink (or the reverse) isn't a content
+        // change the detector can reason about: the recorded and on-disk modes
+        // describe different kinds of entries. Report a clear replacement
+        // instead of whatever the metadata comparison would conclude.
+        if let (Some(state), Some(Some(fs_meta))) =
+            (&wm_needs_check.ts_state, &wm_needs_check.fs_meta)
+        {
+            let ts_is_dir = state.mode & 0o170000 == 0o040000;
+            let replaced = (fs_meta.is_symlink_mode() && ts_is_dir)
+                || (fs_meta.is_dir() && state.is_symlink());
+            if replaced {
+                tracing::trace!(path = ?wm_needs_check.path, "symlink/directory replacement");
+                if !ts_need_check.contains_key(&wm_needs_check.path) {
+                    needs_mark.push(wm_needs_check.path.clone());
+                }
+                pending_changes.push(Ok(PendingChange::Changed(wm_needs_check.path)));
+                continue;
+            }
+        }
+
         file_change_detector.submit(wm_needs_check);
     }
 
@@ -667,9 +1147,12 @@ pub(crate) fn detect_changes(
 
     let _span = tracing::info_span!("handle results").entered();
 
+    let mut watchman_confirmed_changed = 0usize;
+
     for result in file_change_detector {
         match result {
             Ok(ResolvedFileChangeResult::Yes(change)) => {
+                watchman_confirmed_changed += 1;
                 let path = change.get_path();
                 if let PendingChange::Deleted(path) = change {
                     deletes.push(path);
@@ -692,6 +1175,14 @@ pub(crate) fn detect_changes(
         }
     }
 
+    // Quantify how much watchman over-reports: entries it told us to check vs
+    // entries that actually turned out to have changed.
+    tracing::debug!(
+        target: "watchman_info",
+        watchman_reported,
+        watchman_confirmed_changed,
+    );
+
     drop(_span);
 
     for d in deletes {
@@ -714,6 +1205,9 @@ fn normalize_watchman_files(
     fs_case_sensitive: bool,
 ) -> Result<HashMap<RepoPathBuf, metadata::File>> {
     let mut wm_need_check = HashMap::with_capacity(wm_files.len());
+    // Maps case-folded path to the spelling we kept, for dedup on case
+    // insensitive filesystems.
+    let mut folded_paths: HashMap<String, RepoPathBuf> = HashMap::new();
 
     for mut file in wm_files {
         let (normalized_path, state) = ts.normalize_path_and_get(file.path.as_ref())?;
@@ -758,6 +1252,34 @@ fn normalize_watchman_files(
 
         file.ts_state = state;
 
+        if !fs_case_sensitive {
+            // Watchman can report multiple spellings of the same underlying file on a
+            // case insensitive filesystem. Collapse them, preferring the spelling the
+            // treestate knows about; between unknown spellings the first report wins.
+            let folded = file.path.as_str().to_lowercase();
+            match folded_paths.get(&folded) {
+                Some(winner) if winner != &file.path => {
+                    let winner_known = wm_need_check
+                        .get(winner)
+                        .map_or(false, |f| f.ts_state.is_some());
+                    if winner_known || file.ts_state.is_none() {
+                        tracing::trace!(
+                            path = ?file.path,
+                            ?winner,
+                            "dropping case-folded duplicate"
+                        );
+                        continue;
+                    }
+                    let winner = winner.clone();
+                    wm_need_check.remove(&winner);
+                    folded_paths.insert(folded, file.path.clone());
+                }
+                _ => {
+                    folded_paths.insert(folded, file.path.clone());
+                }
+            }
+        }
+
         wm_need_check.insert(file.path.clone(), file);
     }
 
@@ -770,7 +1292,30 @@ pub struct WatchmanPendingChanges {
     needs_mark: Vec<RepoPathBuf>,
 }
 
+/// Counts of the work `WatchmanPendingChanges::update_treestate` is about to do, as
+/// reported by `WatchmanPendingChanges::summary`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PendingChangesSummary {
+    /// Files that will be marked NEED_CHECK.
+    pub to_mark: usize,
+    /// Files whose NEED_CHECK flag will be cleared.
+    pub to_clear: usize,
+    /// Pending changes (including errors) that will be reported to the caller.
+    pub pending: usize,
+}
+
 impl WatchmanPendingChanges {
+    /// Summarize the work `update_treestate` will do. Must be called before
+    /// `update_treestate`, which drains the underlying vectors. `to_mark + to_clear`
+    /// matches the total shown by the "recording files" progress bar.
+    pub fn summary(&self) -> PendingChangesSummary {
+        PendingChangesSummary {
+            to_mark: self.needs_mark.len(),
+            to_clear: self.needs_clear.len(),
+            pending: self.pending_changes.len(),
+        }
+    }
+
     #[tracing::instrument(skip_all)]
     pub fn update_treestate(&mut self, ts: &mut TreeState) -> Result<bool> {
         let bar = ProgressBar::new_adhoc(
@@ -780,9 +1325,12 @@ impl WatchmanPendingChanges {
         );
 
         let mut wrote = false;
+        let mut redundant_clears = 0usize;
+        let mut redundant_marks = 0usize;
         for (path, fs_meta) in self.needs_clear.drain(..) {
             match clear_needs_check(ts, &path, fs_meta) {
-                Ok(v) => wrote |= v,
+                Ok(true) => wrote = true,
+                Ok(false) => redundant_clears += 1,
                 Err(e) =>
                 // We can still build a valid result if we fail to clear the
                 // needs check flag. Propagate the error to the caller but allow
@@ -796,10 +1344,18 @@ impl WatchmanPendingChanges {
         }
 
         for path in self.needs_mark.iter() {
-            wrote |= mark_needs_check(ts, path)?;
+            if mark_needs_check(ts, path)? {
+                wrote = true;
+            } else {
+                redundant_marks += 1;
+            }
             bar.increase_position(1);
         }
 
+        if redundant_clears > 0 || redundant_marks > 0 {
+            tracing::debug!(redundant_clears, redundant_marks, "redundant treestate updates");
+        }
+
         Ok(wrote)
     }
 }
@@ -822,3 +1378,92 @@ fn parse_watchman_pid(clock: Option<&Clock>) -> Option<u32> {
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn synthetic_wm_file(name: &str) -> StatusQuery {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "mode": 0o100644u64,
+            "size": 10u64,
+            "mtime": 100u64,
+            "exists": true,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_wm_files_to_needs_check_parallel() {
+        let count = 100_000;
+        let files: Vec<StatusQuery> = (0..count)
+            .map(|i| synthetic_wm_file(&format!("dir{}/file{}", i % 100, i)))
+            .collect();
+
+        let mut errors = Vec::new();
+        let needs_check = wm_files_to_needs_check(files, true, 8, &mut errors);
+
+        assert!(errors.is_empty());
+        assert_eq!(needs_check.len(), count);
+
+        // The conversion is deterministic: input order is preserved across chunks.
+        assert_eq!(needs_check[0].path.as_str(), "dir0/file0");
+        assert_eq!(
+            needs_check[count - 1].path.as_str(),
+            format!("dir{}/file{}", (count - 1) % 100, count - 1)
+        );
+        assert!(needs_check.windows(2).all(|w| {
+            let i = |f: &metadata::File| -> usize {
+                f.path.as_str().rsplit("file").next().unwrap().parse().unwrap()
+            };
+            i(&w[0]) < i(&w[1])
+        }));
+    }
+
+    #[test]
+    fn test_watchman_path_error() {
+        let raw_name = b"foo/ba\xffr".to_vec();
+        let err = RepoPathBuf::from_utf8(raw_name.clone()).unwrap_err();
+        let err = WatchmanPathError {
+            raw_name,
+            mode: 0o100644,
+            size: 123,
+            source: err,
+        };
+
+        // The offending byte sequence is logged escaped, not lossily converted.
+        let msg = format!("{}", err);
+        assert!(msg.contains("foo/ba\\xffr"), "{}", msg);
+        assert!(msg.contains("size=123"), "{}", msg);
+
+        // It flows into pending changes as a downcastable anyhow error.
+        let err = anyhow!(err);
+        assert!(err.is::<WatchmanPathError>());
+    }
+
+    #[test]
+    fn test_adaptive_sync_timeout() {
+        let base = Duration::from_secs(10);
+        let max = Duration::from_secs(60);
+
+        // No history, or fast queries: stick to the configured timeout.
+        assert_eq!(adaptive_sync_timeout(base, max, None), base);
+        assert_eq!(
+            adaptive_sync_timeout(base, max, Some(Duration::from_secs(1))),
+            base
+        );
+
+        // Queries approaching the limit grow the timeout.
+        assert_eq!(
+            adaptive_sync_timeout(base, max, Some(Duration::from_secs(8))),
+            Duration::from_secs(32)
+        );
+
+        // But never past the configured maximum.
+        assert_eq!(
+            adaptive_sync_timeout(base, max, Some(Duration::from_secs(30))),
+            max
+        );
+    }
+}
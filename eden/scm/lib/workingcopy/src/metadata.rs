@@ -59,6 +59,12 @@ impl Metadata {
         vfs.supports_symlinks() && self.flags.intersects(MetadataFlags::IS_SYMLINK)
     }
 
+    /// Like `is_symlink`, but based purely on the recorded file type, ignoring
+    /// whether the VFS supports symlinks.
+    pub(crate) fn is_symlink_mode(&self) -> bool {
+        self.flags.intersects(MetadataFlags::IS_SYMLINK)
+    }
+
     pub fn is_executable(&self, vfs: &VFS) -> bool {
         vfs.supports_executables() && self.flags.intersects(MetadataFlags::IS_EXEC)
     }
@@ -7,6 +7,7 @@
 
 use std::ffi::OsString;
 use std::path::Path;
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use anyhow::Result;
@@ -15,35 +16,58 @@ use once_cell::sync::OnceCell;
 
 pub struct DeferredWatchmanClient {
     config: Arc<dyn Config>,
+    sockpath: Option<PathBuf>,
     watchman_client: OnceCell<Arc<watchman_client::Client>>,
 }
 
 // Defer connection attempt to watchman until necessary.
 impl DeferredWatchmanClient {
     pub fn new(config: Arc<dyn Config>) -> Self {
+        Self::with_sockpath(config, None)
+    }
+
+    /// Connect to the watchman instance listening on `sockpath` instead of the one
+    /// inferred from the environment and config. This lets tests and sandboxed
+    /// environments point at a specific watchman instance deterministically.
+    pub fn with_sockpath(config: Arc<dyn Config>, sockpath: Option<PathBuf>) -> Self {
         Self {
             config,
+            sockpath,
             watchman_client: Default::default(),
         }
     }
 
+    /// The socket path override this client connects to, if any.
+    pub(crate) fn sockpath(&self) -> Option<&Path> {
+        self.sockpath.as_deref()
+    }
+
     pub fn get(&self) -> Result<Arc<watchman_client::Client>> {
         self.watchman_client
-            .get_or_try_init(|| connect_watchman(&self.config))
+            .get_or_try_init(|| connect_watchman(&self.config, self.sockpath.as_deref()))
             .cloned()
     }
 }
 
-fn connect_watchman(config: &dyn Config) -> Result<Arc<watchman_client::Client>> {
-    async_runtime::block_on(connect_watchman_async(config)).map(Arc::new)
+fn connect_watchman(
+    config: &dyn Config,
+    sockpath_override: Option<&Path>,
+) -> Result<Arc<watchman_client::Client>> {
+    async_runtime::block_on(connect_watchman_async(config, sockpath_override)).map(Arc::new)
 }
 
-pub(crate) async fn connect_watchman_async(config: &dyn Config) -> Result<watchman_client::Client> {
-    let sockpath: Option<OsString> = std::env::var_os("WATCHMAN_SOCK").or_else(|| {
-        config
-            .get_nonempty("fsmonitor", "sockpath")
-            .map(|p| p.replace("%i", &whoami::username()).into())
-    });
+pub(crate) async fn connect_watchman_async(
+    config: &dyn Config,
+    sockpath_override: Option<&Path>,
+) -> Result<watchman_client::Client> {
+    let sockpath: Option<OsString> = sockpath_override
+        .map(|p| p.as_os_str().to_os_string())
+        .or_else(|| std::env::var_os("WATCHMAN_SOCK"))
+        .or_else(|| {
+            config
+                .get_nonempty("fsmonitor", "sockpath")
+                .map(|p| p.replace("%i", &whoami::username()).into())
+        });
 
     let mut connector = watchman_client::Connector::new();
 